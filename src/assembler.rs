@@ -1,6 +1,15 @@
 use lazy_static::lazy_static;
-use regex::Regex;
 use std::collections::HashMap;
+use std::fmt;
+
+use crate::{
+  include::load,
+  lexer::Span,
+  macros::{expand, with_calls, Call, Expanded},
+  parser::{Operand, OperandKind, Statement, StatementKind},
+  source::{Source, SourceMap},
+  vm::{word_mask, DEFAULT_WIDTH, WIDTHS},
+};
 
 /// Set of instruction before labels are calculated.
 #[derive(Debug)]
@@ -15,6 +24,8 @@ enum PreInstruction {
   GO(String),
   BIN(String),
   BIZ(String),
+  BIC(String),
+  BIV(String),
   ADD(isize, isize),
   AND(isize, isize),
   MV(isize, isize),
@@ -22,9 +33,25 @@ enum PreInstruction {
   RS(isize, isize),
   LS(isize, isize),
   SW(isize, isize),
+  LI(isize, isize),
+  CALL(String),
+  RET,
+  PUSH(isize),
+  POP(isize),
+  SUB(isize, isize),
+  OR(isize, isize),
+  XOR(isize, isize),
+  ASR(isize, isize),
+  ROL(isize, isize),
+  ROR(isize, isize),
+  HALT(isize),
+  EI,
+  DI,
+  SIV(String),
+  RTI,
 }
 /// Set of instruction after labels are calculated.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
   SA(isize),
   RB(isize),
@@ -36,6 +63,10 @@ pub enum Instruction {
   GO(isize),
   BIN(isize),
   BIZ(isize),
+  /// Branches if the C flag is set.
+  BIC(isize),
+  /// Branches if the V flag is set.
+  BIV(isize),
   ADD(isize, isize),
   AND(isize, isize),
   MV(isize, isize),
@@ -43,6 +74,34 @@ pub enum Instruction {
   RS(isize, isize),
   LS(isize, isize),
   SW(isize, isize),
+  /// Loads a value into a register.
+  LI(isize, isize),
+  /// Pushes the index of the next instruction and jumps like `GO`.
+  CALL(isize),
+  /// Pops an instruction index and jumps to it.
+  RET,
+  PUSH(isize),
+  POP(isize),
+  SUB(isize, isize),
+  OR(isize, isize),
+  XOR(isize, isize),
+  /// Shifts right by one, keeping the sign bit.
+  ASR(isize, isize),
+  /// Rotates left by one, moving the top bit to the bottom.
+  ROL(isize, isize),
+  /// Rotates right by one, moving the bottom bit to the top.
+  ROR(isize, isize),
+  /// Stops the program with the value of a register as its exit status.
+  HALT(isize),
+  /// Enables interrupts.
+  EI,
+  /// Disables interrupts.
+  DI,
+  /// Sets the interrupt vector, the instruction interrupts jump to, holding
+  /// the index before it like a branch.
+  SIV(isize),
+  /// Returns from an interrupt, restoring the flags and PC it saved.
+  RTI,
 }
 
 lazy_static! {
@@ -66,419 +125,1299 @@ lazy_static! {
     map.insert("LS", 14);
     map.insert("SW", 15);
     map.insert("PRINT", 16);
+    map.insert("LI", 17);
+    map.insert("CALL", 18);
+    map.insert("RET", 19);
+    map.insert("PUSH", 20);
+    map.insert("POP", 21);
+    map.insert("SUB", 22);
+    map.insert("OR", 23);
+    map.insert("XOR", 24);
+    map.insert("ASR", 25);
+    map.insert("ROL", 26);
+    map.insert("ROR", 27);
+    map.insert("BIC", 28);
+    map.insert("BIV", 29);
+    map.insert("HALT", 30);
+    map.insert("EI", 31);
+    map.insert("DI", 32);
+    map.insert("SIV", 33);
+    map.insert("RTI", 34);
     map
   };
-  pub static ref LABEL_OPS: [isize; 4] = [-1, 6, 7, 8];
-  pub static ref ZERO_ARG_OPS: [isize; 3] = [2, 3, 16];
-  pub static ref ONE_REG_OPS: [isize; 4] = [0, 1, 4, 5];
-  pub static ref TWO_REG_OPS: [isize; 7] = [9, 10, 11, 12, 13, 14, 15];
-  pub static ref IS_CNAME: Regex = Regex::new("[_a-zA-Z][_a-zA-Z0-9]*").unwrap();
+  pub static ref LABEL_OPS: [isize; 8] = [-1, 6, 7, 8, 18, 28, 29, 33];
+  pub static ref ZERO_ARG_OPS: [isize; 7] = [2, 3, 16, 19, 31, 32, 34];
+  pub static ref ONE_REG_OPS: [isize; 6] = [0, 1, 4, 5, 20, 21];
+  pub static ref TWO_REG_OPS: [isize; 13] = [9, 10, 11, 12, 13, 14, 15, 22, 23, 24, 25, 26, 27];
+  /// Operations added to classic VMAL, which strict mode rejects.
+  pub static ref EXTENSION_OPS: [isize; 18] =
+    [17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34];
+  /// Operations taking a register that may be left out, for the 0 register.
+  pub static ref OPTIONAL_REG_OPS: [isize; 1] = [30];
+  /// Operations taking a register and a number or data label.
+  pub static ref IMMEDIATE_OPS: [isize; 1] = [17];
+  /// Registers given a fixed value when the program starts: PC and the 0, 1
+  /// and -1 constants.
+  pub static ref RESET_REGS: [isize; 4] = [0, 5, 6, 7];
 }
 
-fn print_error(i: usize, line: &str, msg: String) -> ! {
-  println!("Error on line #{}: {}", i + 1, msg);
-  println!("\t>{}", line);
-  std::process::exit(1);
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+  Error,
+  Warning,
+  /// Extra context attached to another diagnostic.
+  Note,
 }
 
-fn parse_number(s: &str) -> Result<isize, &'static str> {
-  let a = s.strip_prefix("0x");
-  if let Some(b) = a {
-    match isize::from_str_radix(b, 16) {
-      Ok(r) => return Ok(r),
-      Err(_) => return Err("hexadecimal"),
+/// An error or warning in the assembled source, pointing at the offending
+/// part of a line.
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+  pub severity: Severity,
+  /// Name of the included file the diagnostic is in, or None for the main
+  /// file.
+  pub file: Option<Box<str>>,
+  /// Zero-based line number.
+  pub line: usize,
+  /// Text of the line.
+  pub text: String,
+  /// Byte range of the line the diagnostic points at.
+  pub columns: (usize, usize),
+  pub message: String,
+  /// Other places involved, such as the macro calls that led to an error.
+  pub notes: Vec<Diagnostic>,
+}
+
+impl Diagnostic {
+  fn new<S: Source + ?Sized>(severity: Severity, source: &S, span: Span, message: String) -> Self {
+    let (file, line, text, start) = source.line_at(span.start);
+    let end = start + text.len();
+    Diagnostic {
+      severity,
+      file: file.map(Box::from),
+      line,
+      text: text.to_owned(),
+      columns: (span.start - start, span.end.clamp(span.start, end) - start),
+      message,
+      notes: vec![],
     }
   }
-  let a = s.strip_prefix("0b");
-  if let Some(b) = a {
-    match isize::from_str_radix(b, 2) {
-      Ok(r) => return Ok(r),
-      Err(_) => return Err("binary"),
-    }
+  pub fn error<S: Source + ?Sized>(source: &S, span: Span, message: String) -> Self {
+    Diagnostic::new(Severity::Error, source, span, message)
+  }
+  pub fn warning<S: Source + ?Sized>(source: &S, span: Span, message: String) -> Self {
+    Diagnostic::new(Severity::Warning, source, span, message)
   }
-  match isize::from_str_radix(s, 10) {
-    Ok(r) => return Ok(r),
-    Err(_) => return Err("character"),
+  pub fn note<S: Source + ?Sized>(source: &S, span: Span, message: String) -> Self {
+    Diagnostic::new(Severity::Note, source, span, message)
   }
 }
 
-#[derive(Debug)]
+impl fmt::Display for Diagnostic {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let kind = match self.severity {
+      Severity::Error => "Error",
+      Severity::Warning => "Warning",
+      Severity::Note => "Note",
+    };
+    match &self.file {
+      Some(file) => writeln!(
+        f,
+        "{} on line #{} of {}: {}",
+        kind,
+        self.line + 1,
+        file,
+        self.message
+      )?,
+      None => writeln!(f, "{} on line #{}: {}", kind, self.line + 1, self.message)?,
+    }
+    writeln!(f, "\t>{}", self.text.trim_end_matches('\r'))?;
+    // Keep tabs in the padding so the carets line up with the text above.
+    let (start, end) = self.columns;
+    let padding = self
+      .text
+      .get(..start)
+      .unwrap_or("")
+      .chars()
+      .map(|c| if c == '\t' { '\t' } else { ' ' })
+      .collect::<String>();
+    let width = self.text.get(start..end).map_or(0, |s| s.chars().count());
+    write!(f, "\t {}{}", padding, "^".repeat(width.max(1)))?;
+    for note in &self.notes {
+      write!(f, "\n{}", note)?;
+    }
+    Ok(())
+  }
+}
+
+#[derive(Debug, Default)]
 pub struct Assembly {
+  /// Number of bits in a word, set with `.width` or on the command line.
+  pub width: u32,
   pub reg_inits: Vec<(isize, isize)>,
   pub mem_inits: Vec<(isize, isize)>,
   pub instructions: Vec<Instruction>,
+  /// Zero-based source line of every instruction.
+  pub lines: Vec<usize>,
+  /// Index in `sources` of the file of every instruction.
+  pub files: Vec<usize>,
+  /// Span of the statement of every instruction.
+  pub spans: Vec<Span>,
+  /// Span of the name in every label definition.
+  pub labels: HashMap<String, Span>,
+  /// Index of the instruction every label names.
+  pub targets: HashMap<String, usize>,
+  /// Span of the label in every data directive that has one.
+  pub data_labels: HashMap<String, Span>,
+  /// Address every data label names.
+  pub addresses: HashMap<String, isize>,
+  /// Index in `mem_inits` of every word laid out by a data directive.
+  pub data: Vec<usize>,
+  /// Number of words from address 0 data directives lay out, up to the end of
+  /// the furthest one.
+  pub data_size: isize,
+  /// Every initializer and `LI` set to the address of a data label, with the
+  /// label and its span. Until the program is linked, the address of a label
+  /// declared `.extern` is 0.
+  pub fixups: Vec<(Fixup, String, Span)>,
+  /// Every label used by `GO`, `CALL` and the conditional branches, or for its address, with its
+  /// span.
+  pub label_refs: Vec<(String, Span)>,
+  /// Labels other objects may branch to, declared with `.global`.
+  pub globals: HashMap<String, Span>,
+  /// Labels defined by other objects, declared with `.extern`.
+  pub externs: HashMap<String, Span>,
+  /// Index of every branch to an external label, with the label. Until the
+  /// program is linked, these branch past its last instruction.
+  pub imports: Vec<(usize, String)>,
+  pub warnings: Vec<Diagnostic>,
+  /// The program's source, including every file it includes.
+  pub sources: SourceMap,
 }
 
-impl Assembly {
-  pub fn assemble<S: Into<String>>(file: S) -> Self {
-    let file = file.into();
-    let mut assembly = Assembly {
-      reg_inits: vec![],
-      mem_inits: vec![],
-      instructions: vec![],
+/// Whether a label was defined in a macro, which gives each expansion its own
+/// copy of the label.
+fn is_local(label: &str) -> bool {
+  label.contains('@')
+}
+
+/// Directives that lay out words in memory.
+const DATA_DIRECTIVES: [&str; 6] = [".org", ".word", ".fill", ".ascii", ".asciz", ".space"];
+
+/// A register or memory initializer, or an `LI` instruction, by index, whose
+/// value is the address of a data label, which may be defined after it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fixup {
+  Register(usize),
+  Memory(usize),
+  Instruction(usize),
+}
+
+/// Options for assembling a program.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Settings {
+  /// Whether to reject operations classic VMAL does not have.
+  pub strict: bool,
+  /// Word width of programs without a `.width` directive, if not the default.
+  pub width: Option<u32>,
+}
+
+/// State of the assembler while it goes through the parsed statements.
+struct Assembler<'a> {
+  source: &'a SourceMap,
+  strict: bool,
+  /// Word width given in the settings, which a `.width` directive must match.
+  width: Option<u32>,
+  assembly: Assembly,
+  instructions: Vec<PreInstruction>,
+  /// Span of the label each branching instruction refers to, and the macro
+  /// calls it came from.
+  label_spans: HashMap<usize, (Span, Vec<Call>)>,
+  label_map: HashMap<String, isize>,
+  /// Address the next data directive lays its words out at.
+  data_address: isize,
+  /// Initializers to set to the address of a data label, with the label, its
+  /// span and the macro calls it came from.
+  fixups: Vec<(Fixup, String, Span, Vec<Call>)>,
+  /// Macro calls the current statement came from, outermost first.
+  calls: Vec<Call>,
+  /// Span of the `.width` directive that set the word width, if any.
+  width_span: Option<Span>,
+}
+
+impl<'a> Assembler<'a> {
+  fn error(&self, span: Span, message: String) -> Diagnostic {
+    Diagnostic::error(self.source, span, message)
+  }
+  fn warn(&mut self, span: Span, message: String) {
+    let warning = Diagnostic::warning(self.source, span, message);
+    self.assembly.warnings.push(warning);
+  }
+  fn push(&mut self, instruction: PreInstruction, statement: &Statement) {
+    self.instructions.push(instruction);
+    // Instructions from macros belong to the outermost call.
+    let span = self.calls.first().map_or(statement.span, |(_, span)| *span);
+    let (file, line, _) = self.source.position(span.start);
+    self.assembly.files.push(file);
+    self.assembly.lines.push(line);
+    self.assembly.spans.push(span);
+  }
+  /// Sets an initializer to the address of a data label once it is known.
+  fn fixup(&mut self, fixup: Fixup, label: &Operand) {
+    self
+      .assembly
+      .label_refs
+      .push((label.text.clone(), label.span));
+    let calls = self.calls.clone();
+    self
+      .fixups
+      .push((fixup, label.text.clone(), label.span, calls));
+  }
+  fn is_defined(&self, label: &str) -> bool {
+    self.label_map.contains_key(label) || self.assembly.addresses.contains_key(label)
+  }
+  /// Checks that a literal fits in a word, as a signed or an unsigned number.
+  fn fits(&self, operand: &Operand, value: isize) -> Result<isize, Diagnostic> {
+    let width = self.assembly.width;
+    if width >= isize::BITS || (-(1 << (width - 1))..=word_mask(width)).contains(&value) {
+      Ok(value)
+    } else {
+      Err(self.error(
+        operand.span,
+        format!("{} does not fit in a {}-bit word", operand.text, width),
+      ))
+    }
+  }
+  /// Sets the word width for the whole program from a `.width` directive.
+  fn set_width(&mut self, statement: &Statement, args: &[Operand]) -> Result<(), Diagnostic> {
+    if args.len() != 1 {
+      return Err(self.error(
+        statement.span,
+        "Expected a word width in bits after .width".to_owned(),
+      ));
+    }
+    let arg = &args[0];
+    let width = match arg.number() {
+      Ok(width) if WIDTHS.contains(&(width as u32)) => width as u32,
+      _ => {
+        return Err(self.error(
+          arg.span,
+          format!("Word width must be 8, 16, 32 or 64 bits, not {}", arg.text),
+        ))
+      }
     };
-    let mut instructions = vec![];
-    let mut lbl_lines: HashMap<usize, (usize, &str)> = HashMap::new();
-    let mut label_map: HashMap<String, isize> = HashMap::new();
-    for (i, line) in file.split("\n").enumerate() {
-      let mut code = match line.split_once("#") {
-        Some(a) => a.0,
-        None => line,
-      };
-      code = code.trim();
-      if code.is_empty() {
-        continue;
+    if self.width_span.is_some() && width != self.assembly.width {
+      return Err(self.error(
+        arg.span,
+        format!("Word width already set to {} bits", self.assembly.width),
+      ));
+    }
+    if let Some(given) = self.width {
+      if given != width {
+        return Err(self.error(
+          arg.span,
+          format!(".width {} does not match --width {}", width, given),
+        ));
       }
-      code = match code.split_once(";") {
-        Some(a) => {
-          if !a.1.trim().is_empty() {
-            print_error(
-              i,
-              line,
+    }
+    self.assembly.width = width;
+    self.width_span = Some(statement.span);
+    Ok(())
+  }
+
+  /// Assembles one statement.
+  fn statement(&mut self, statement: &Statement) -> Result<(), Diagnostic> {
+    match &statement.kind {
+      StatementKind::RegisterInit { register, value } => {
+        let reg = register.register().ok_or_else(|| {
+          self.error(
+            register.span,
+            format!(
+              "Invalid register in register initializer - '{}'",
+              register.text
+            ),
+          )
+        })?;
+        let val = if value.kind == OperandKind::Name {
+          self.fixup(Fixup::Register(self.assembly.reg_inits.len()), value);
+          0
+        } else {
+          let val = value.number().map_err(|err| {
+            self.error(
+              value.span,
               format!(
-                "Extra non-comment character sequence after semicolon - '{}'",
-                a.1
+                "Invalid {} literal in register initializer - \"{}\"",
+                err, value.text
               ),
-            );
-          }
-          a.0
-        }
-        None => {
-          print_error(i, line, "Missing semicolon".to_owned());
+            )
+          })?;
+          self.fits(value, val)?
+        };
+        if RESET_REGS.contains(&reg) {
+          self.warn(
+            statement.span,
+            format!(
+              "Register {:X} is reset when the program starts, so this initializer has no effect",
+              reg
+            ),
+          );
+        } else if self.assembly.reg_inits.iter().any(|(r, _)| *r == reg) {
+          self.warn(
+            statement.span,
+            format!("Register {:X} is initialized more than once", reg),
+          );
         }
-      };
-      match code.split_once(":") {
-        Some((bpart, epart)) => {
-          let loc = bpart.trim();
-          let val = epart.trim();
-          let mut is_reg_init = false;
-          let reg = if loc.len() == 1 {
-            let r = match isize::from_str_radix(loc, 16) {
-              Ok(r) => r,
-              Err(_) => print_error(
-                i,
-                line,
-                format!("Invalid register in register initializer - '{}'", loc),
-              ),
-            };
-            is_reg_init = true;
-            r
-          } else if loc.starts_with("[") && loc.ends_with("]") {
-            let mem_str = &loc[1..(loc.len() - 1)];
-            match parse_number(mem_str) {
-              Ok(r) => r,
-              Err(err) => print_error(
-                i,
-                line,
-                format!(
-                  "Invalid {} literal in {} initializer - \"{}\"",
-                  err, "memory", mem_str
-                ),
+        self.assembly.reg_inits.push((reg, val));
+        Ok(())
+      }
+      StatementKind::MemoryInit { address, value } => {
+        let literal = |operand: &Operand| {
+          operand.number().map_err(|err| {
+            self.error(
+              operand.span,
+              format!(
+                "Invalid {} literal in memory initializer - \"{}\"",
+                err, operand.text
               ),
-            }
+            )
+          })
+        };
+        let loc = self.fits(address, literal(address)?)?;
+        let val = if value.kind == OperandKind::Name {
+          self.fixup(Fixup::Memory(self.assembly.mem_inits.len()), value);
+          0
+        } else {
+          self.fits(value, literal(value)?)?
+        };
+        self.init_memory(statement, loc, &[val]);
+        Ok(())
+      }
+      StatementKind::Instruction { op, args } => self.instruction(statement, op, args),
+      StatementKind::Directive { label, name, args } => {
+        let directive = name.text.to_lowercase();
+        if DATA_DIRECTIVES.contains(&directive.as_str()) {
+          return self.data(statement, label.as_ref(), &directive, args);
+        }
+        if ![".global", ".extern", ".width"].contains(&directive.as_str()) {
+          return Err(self.error(name.span, format!("Unknown directive '{}'", name.text)));
+        }
+        if let Some(label) = label {
+          return Err(self.error(
+            label.span,
+            format!("Only data directives can have a label, not {}", directive),
+          ));
+        }
+        if directive == ".width" {
+          // Already set by `check`, before any value had to fit in a word.
+          return Ok(());
+        }
+        if args.is_empty() {
+          return Err(self.error(
+            statement.span,
+            format!("Expected label names after {}", directive),
+          ));
+        }
+        for arg in args {
+          if arg.kind != OperandKind::Name {
+            return Err(self.error(
+              arg.span,
+              format!("Label name is not a valid cname - '{}'", arg.text),
+            ));
+          }
+          let (declared, other, other_name) = if directive == ".global" {
+            (
+              &mut self.assembly.globals,
+              &self.assembly.externs,
+              ".extern",
+            )
           } else {
-            print_error(
-              i,
-              line,
-              "Invalid syntax for register/memory initializer".to_owned(),
-            );
-          };
-          let val = match parse_number(val) {
-            Ok(r) => r,
-            Err(err) => {
-              print_error(
-                i,
-                line,
-                format!(
-                  "Invalid {} literal in {} initializer - \"{}\"",
-                  err, reg, val
-                ),
-              );
-            }
+            (
+              &mut self.assembly.externs,
+              &self.assembly.globals,
+              ".global",
+            )
           };
-          if is_reg_init {
-            assembly.reg_inits.push((reg, val));
-          } else {
-            assembly.mem_inits.push((reg, val));
+          if other.contains_key(&arg.text) {
+            return Err(self.error(
+              arg.span,
+              format!("Label '{}' is already declared {}", arg.text, other_name),
+            ));
           }
-          continue;
+          declared.entry(arg.text.clone()).or_insert(arg.span);
         }
-        None => {}
+        Ok(())
       }
-      let code = code.trim();
-      let (op, space, args) = match code.split_once(" ") {
-        Some(a) => (a.0, " ", a.1),
-        None => (code, "", ""),
-      };
-      let op = op.to_uppercase();
-      if op != "RD" && op != "WR" && op != "PRINT" && space != " " {
-        print_error(i, line, format!("Unknown character sequence '{}'", op));
+    }
+  }
+
+  fn instruction(
+    &mut self,
+    statement: &Statement,
+    op_name: &Operand,
+    args: &[Operand],
+  ) -> Result<(), Diagnostic> {
+    let op = op_name.text.to_uppercase();
+    let op_num = match OP_MAP.get(op.as_str()) {
+      Some(op_num) => *op_num,
+      None => {
+        return Err(self.error(op_name.span, format!("Unknown operation '{}'", op)));
       }
-      if !OP_MAP.contains_key(op.as_str()) {
-        print_error(i, line, format!("Unknown operation '{}'", op));
+    };
+    if EXTENSION_OPS.contains(&op_num) && self.strict {
+      return Err(self.error(
+        op_name.span,
+        format!(
+          "{} is not part of classic VMAL, so it is not allowed in strict mode",
+          op
+        ),
+      ));
+    }
+    let (count, expected) = if LABEL_OPS.contains(&op_num) {
+      (1, "1 label")
+    } else if ZERO_ARG_OPS.contains(&op_num) {
+      (0, "no arguments")
+    } else if ONE_REG_OPS.contains(&op_num) {
+      (1, "1 register")
+    } else if OPTIONAL_REG_OPS.contains(&op_num) {
+      (1, "at most 1 register")
+    } else if IMMEDIATE_OPS.contains(&op_num) {
+      (2, "1 register and 1 value")
+    } else {
+      (2, "2 registers")
+    };
+    if args.len() > count {
+      return Err(self.error(
+        args[count].span.to(args[args.len() - 1].span),
+        format!(
+          "Too many arguments for {} operation (expected {}, got {} args)",
+          op,
+          expected,
+          args.len()
+        ),
+      ));
+    }
+    if args.len() < count && !OPTIONAL_REG_OPS.contains(&op_num) {
+      return Err(self.error(
+        statement.span,
+        format!(
+          "Not enough arguments for {} operation (expected {}, got {} args)",
+          op,
+          expected,
+          args.len()
+        ),
+      ));
+    }
+
+    if LABEL_OPS.contains(&op_num) {
+      let label = &args[0];
+      if label.kind != OperandKind::Name {
+        return Err(self.error(
+          label.span,
+          format!("Label name is not a valid cname - '{}'", label.text),
+        ));
       }
-      let op_num = *OP_MAP.get(op.as_str()).unwrap();
-      let args = args
-        .trim()
-        .split(",")
-        .map(|x| x.trim())
-        .filter(|x| !x.is_empty())
-        .collect::<Vec<_>>();
-      if LABEL_OPS.contains(&op_num) {
-        if args.len() > 1 {
-          print_error(
-            i,
-            line,
-            format!(
-              "Too many arguments for {} operation (expected 1 label, got {} args)",
-              op,
-              args.len()
-            ),
-          );
+      let lbl = label.text.clone();
+      if op_num == -1 {
+        if self.is_defined(&lbl) {
+          return Err(self.error(label.span, format!("Label '{}' already defined", lbl)));
+        }
+        if !is_local(&lbl) {
+          self.assembly.labels.insert(lbl.clone(), label.span);
+          self
+            .assembly
+            .targets
+            .insert(lbl.clone(), self.instructions.len());
         }
-        if args.len() < 1 {
-          print_error(
-            i,
-            line,
+        self
+          .label_map
+          .insert(lbl, self.instructions.len() as isize - 1);
+        return Ok(());
+      }
+      if !is_local(&lbl) {
+        self.assembly.label_refs.push((lbl.clone(), label.span));
+      }
+      self
+        .label_spans
+        .insert(self.instructions.len(), (label.span, self.calls.clone()));
+      let instruction = match op.as_str() {
+        "GO" => PreInstruction::GO(lbl),
+        "BIN" => PreInstruction::BIN(lbl),
+        "BIZ" => PreInstruction::BIZ(lbl),
+        "BIC" => PreInstruction::BIC(lbl),
+        "BIV" => PreInstruction::BIV(lbl),
+        "CALL" => PreInstruction::CALL(lbl),
+        "SIV" => PreInstruction::SIV(lbl),
+        _ => unreachable!(),
+      };
+      self.push(instruction, statement);
+      return Ok(());
+    }
+
+    if IMMEDIATE_OPS.contains(&op_num) {
+      let (register, value) = (&args[0], &args[1]);
+      let reg = register.register().ok_or_else(|| {
+        self.error(
+          register.span,
+          format!("Invalid register specifier '{}'", register.text),
+        )
+      })?;
+      let val = if value.kind == OperandKind::Name {
+        self.fixup(Fixup::Instruction(self.instructions.len()), value);
+        0
+      } else {
+        let val = value.number().map_err(|err| {
+          self.error(
+            value.span,
             format!(
-              "Not enough arguments for {} operation (expected 1 label, got {} args)",
-              op,
-              args.len()
+              "Invalid {} literal in {} operation - \"{}\"",
+              err, op, value.text
             ),
-          );
+          )
+        })?;
+        self.fits(value, val)?
+      };
+      self.push(PreInstruction::LI(reg, val), statement);
+      return Ok(());
+    }
+
+    let mut regs = vec![];
+    for arg in args {
+      match arg.register() {
+        Some(reg) => regs.push(reg),
+        None => {
+          return Err(self.error(
+            arg.span,
+            format!("Invalid register specifier '{}'", arg.text),
+          ));
         }
-        let lbl = args[0].to_owned();
-        if op_num == -1 {
-          let m = IS_CNAME.is_match(&lbl);
-          if !m {
-            print_error(
-              i,
-              line,
-              format!("Label name is not a valid cname - '{}'", lbl),
-            );
-          }
-          if label_map.contains_key(&lbl) {
-            print_error(i, line, format!("Label '{}' already defined", lbl));
+      }
+    }
+    let instruction = match op.as_str() {
+      "RD" => PreInstruction::RD,
+      "WR" => PreInstruction::WR,
+      "PRINT" => PreInstruction::PRINT,
+      "RET" => PreInstruction::RET,
+      "EI" => PreInstruction::EI,
+      "DI" => PreInstruction::DI,
+      "RTI" => PreInstruction::RTI,
+      "SA" => PreInstruction::SA(regs[0]),
+      "RB" => PreInstruction::RB(regs[0]),
+      "SB" => PreInstruction::SB(regs[0]),
+      "SF" => PreInstruction::SF(regs[0]),
+      "ADD" => PreInstruction::ADD(regs[0], regs[1]),
+      "AND" => PreInstruction::AND(regs[0], regs[1]),
+      "MV" => PreInstruction::MV(regs[0], regs[1]),
+      "NOT" => PreInstruction::NOT(regs[0], regs[1]),
+      "LS" => PreInstruction::LS(regs[0], regs[1]),
+      "RS" => PreInstruction::RS(regs[0], regs[1]),
+      "SW" => PreInstruction::SW(regs[0], regs[1]),
+      "PUSH" => PreInstruction::PUSH(regs[0]),
+      "POP" => PreInstruction::POP(regs[0]),
+      "SUB" => PreInstruction::SUB(regs[0], regs[1]),
+      "OR" => PreInstruction::OR(regs[0], regs[1]),
+      "XOR" => PreInstruction::XOR(regs[0], regs[1]),
+      "ASR" => PreInstruction::ASR(regs[0], regs[1]),
+      "ROL" => PreInstruction::ROL(regs[0], regs[1]),
+      "ROR" => PreInstruction::ROR(regs[0], regs[1]),
+      "HALT" => PreInstruction::HALT(regs.first().copied().unwrap_or(5)),
+      _ => unreachable!(),
+    };
+    self.push(instruction, statement);
+    Ok(())
+  }
+
+  /// Initializes consecutive memory locations, warning once if any of them
+  /// was already initialized.
+  fn init_memory(&mut self, statement: &Statement, start: isize, words: &[isize]) {
+    let end = start + words.len() as isize;
+    let overlap = self
+      .assembly
+      .mem_inits
+      .iter()
+      .map(|(m, _)| *m)
+      .filter(|m| (start..end).contains(m))
+      .min();
+    if let Some(loc) = overlap {
+      self.warn(
+        statement.span,
+        format!("Memory location {} is initialized more than once", loc),
+      );
+    }
+    for (i, word) in words.iter().enumerate() {
+      self.assembly.mem_inits.push((start + i as isize, *word));
+    }
+  }
+
+  /// Lays out the words of a data directive after those of the previous one,
+  /// or moves where they are laid out for `.org`.
+  fn data(
+    &mut self,
+    statement: &Statement,
+    label: Option<&Operand>,
+    directive: &str,
+    args: &[Operand],
+  ) -> Result<(), Diagnostic> {
+    let expected = match directive {
+      ".word" => args.len().max(1),
+      ".fill" => 2,
+      _ => 1,
+    };
+    if args.len() != expected {
+      return Err(self.error(
+        statement.span,
+        format!(
+          "Wrong number of arguments for {} (expected {}, got {})",
+          directive,
+          expected,
+          args.len()
+        ),
+      ));
+    }
+    let number = |operand: &Operand| {
+      operand.number().map_err(|err| {
+        self.error(
+          operand.span,
+          format!(
+            "Invalid {} literal in {} - \"{}\"",
+            err, directive, operand.text
+          ),
+        )
+      })
+    };
+    let count = |operand: &Operand| match number(operand)? {
+      n if n < 0 => Err(self.error(
+        operand.span,
+        format!(
+          "Expected a count of at least 0 for {}, found {}",
+          directive, n
+        ),
+      )),
+      n => Ok(n),
+    };
+    let mut words = vec![];
+    let mut labels = vec![];
+    match directive {
+      ".word" => {
+        for (i, arg) in args.iter().enumerate() {
+          if arg.kind == OperandKind::Name {
+            labels.push((i, arg));
+            words.push(0);
+          } else {
+            words.push(self.fits(arg, number(arg)?)?);
           }
-          label_map.insert(lbl, instructions.len() as isize - 1);
-          continue;
         }
-        lbl_lines.insert(instructions.len(), (i, line));
-        let instruction = match op.as_str() {
-          "GO" => PreInstruction::GO(lbl),
-          "BIN" => PreInstruction::BIN(lbl),
-          "BIZ" => PreInstruction::BIZ(lbl),
-          _ => unreachable!(),
+      }
+      ".fill" => {
+        let value = self.fits(&args[1], number(&args[1])?)?;
+        words = vec![value; count(&args[0])? as usize]
+      }
+      ".ascii" | ".asciz" => {
+        let text = match &args[0].kind {
+          OperandKind::Str(text) => text,
+          _ => {
+            return Err(self.error(
+              args[0].span,
+              format!("Expected a string in quotes after {}", directive),
+            ))
+          }
         };
-        instructions.push(instruction);
-        continue;
+        words = text.chars().map(|c| c as isize).collect();
+        if directive == ".asciz" {
+          words.push(0);
+        }
       }
-      if ZERO_ARG_OPS.contains(&op_num) {
-        if args.len() > 0 {
-          print_error(
-            i,
-            line,
-            format!(
-              "Too many arguments for {} operation (expected no arguments, got {} args)",
-              op,
-              args.len()
-            ),
-          )
+      _ => {}
+    }
+    let size = match directive {
+      ".org" => {
+        self.data_address = self.fits(&args[0], count(&args[0])?)?;
+        0
+      }
+      ".space" => count(&args[0])?,
+      _ => words.len() as isize,
+    };
+    let start = self.data_address;
+    let width = self.assembly.width;
+    let last = start.checked_add(size - 1);
+    if size > 0 && !last.is_some_and(|last| width >= isize::BITS || last <= word_mask(width)) {
+      return Err(self.error(
+        statement.span,
+        format!(
+          "{} lays out words past the last address of a {}-bit word",
+          directive, width
+        ),
+      ));
+    }
+    self.data_address += size;
+    self.assembly.data_size = self.assembly.data_size.max(self.data_address);
+    if let Some(label) = label {
+      if self.is_defined(&label.text) {
+        return Err(self.error(
+          label.span,
+          format!("Label '{}' already defined", label.text),
+        ));
+      }
+      self
+        .assembly
+        .data_labels
+        .insert(label.text.clone(), label.span);
+      self.assembly.addresses.insert(label.text.clone(), start);
+    }
+    for (i, arg) in labels {
+      let fixup = Fixup::Memory(self.assembly.mem_inits.len() + i);
+      self.fixup(fixup, arg);
+    }
+    let first = self.assembly.mem_inits.len();
+    self.init_memory(statement, start, &words);
+    self.assembly.data.extend(first..first + words.len());
+    Ok(())
+  }
+}
+
+impl Assembly {
+  /// Assembles a program, printing any warnings, or printing the errors and
+  /// exiting if it is invalid.
+  pub fn assemble<S: Into<SourceMap>>(file: S, settings: Settings) -> Self {
+    match Assembly::try_assemble_with(file, settings) {
+      Ok(assembly) => {
+        for warning in &assembly.warnings {
+          println!("{}", warning);
         }
-        let instruction = match op.as_str() {
-          "RD" => PreInstruction::RD,
-          "WR" => PreInstruction::WR,
-          "PRINT" => PreInstruction::PRINT,
-          _ => unreachable!(),
-        };
-        instructions.push(instruction);
-        continue;
+        assembly
       }
-      if ONE_REG_OPS.contains(&op_num) {
-        if args.len() > 1 {
-          print_error(
-            i,
-            line,
-            format!(
-              "Too many arguments for {} operation (expected 1 register, got {} args)",
-              op,
-              args.len()
-            ),
-          );
+      Err(errors) => {
+        for err in errors {
+          println!("{}", err);
         }
-        if args.len() < 1 {
-          print_error(
-            i,
-            line,
-            format!(
-              "Not enough arguments for {} operation (expected 1 register, got {} args)",
-              op,
-              args.len()
-            ),
+        std::process::exit(1);
+      }
+    }
+  }
+  /// Assembles a program, failing with every error found in it.
+  pub fn try_assemble<S: Into<SourceMap>>(file: S) -> Result<Self, Vec<Diagnostic>> {
+    Assembly::try_assemble_with(file, Settings::default())
+  }
+  /// Assembles a program with the given settings, failing with every error
+  /// found in it.
+  pub fn try_assemble_with<S: Into<SourceMap>>(
+    file: S,
+    settings: Settings,
+  ) -> Result<Self, Vec<Diagnostic>> {
+    let (assembly, errors) = Assembly::check_with(file, settings);
+    if errors.is_empty() {
+      Ok(assembly)
+    } else {
+      Err(errors)
+    }
+  }
+  /// Assembles as much of a program as possible, returning it along with every
+  /// error found. Instructions with errors are left out.
+  pub fn check<S: Into<SourceMap>>(file: S) -> (Self, Vec<Diagnostic>) {
+    Assembly::check_with(file, Settings::default())
+  }
+  /// Like `check`, with the given settings instead of the defaults.
+  pub fn check_with<S: Into<SourceMap>>(file: S, settings: Settings) -> (Self, Vec<Diagnostic>) {
+    let mut file = file.into();
+    let (statements, mut errors) = load(&mut file);
+    let (statements, macro_errors) = expand(&file, statements);
+    errors.extend(macro_errors);
+    let mut assembler = Assembler {
+      source: &file,
+      strict: settings.strict,
+      width: settings.width,
+      assembly: Assembly {
+        width: settings.width.unwrap_or(DEFAULT_WIDTH),
+        ..Assembly::default()
+      },
+      instructions: vec![],
+      label_spans: HashMap::new(),
+      label_map: HashMap::new(),
+      data_address: 0,
+      fixups: vec![],
+      calls: vec![],
+      width_span: None,
+    };
+    for Expanded { statement, .. } in &statements {
+      if let StatementKind::Directive { name, args, .. } = &statement.kind {
+        if name.text.eq_ignore_ascii_case(".width") {
+          if let Err(err) = assembler.set_width(statement, args) {
+            errors.push(err);
+          }
+        }
+      }
+    }
+    for Expanded { statement, calls } in statements {
+      assembler.calls = calls;
+      if let Err(mut err) = assembler.statement(&statement) {
+        if let Some((name, _)) = assembler.calls.last() {
+          let body = Diagnostic::note(
+            &file,
+            statement.span,
+            format!("In the body of macro '{}'", name),
           );
+          if (&body.file, body.line) != (&err.file, err.line) {
+            err.notes.push(body);
+          }
+          err = with_calls(err, &file, &assembler.calls);
         }
-        let reg = args[0];
-        if reg.len() > 1 {
-          print_error(i, line, format!("Invalid register specifier '{}'", reg));
+        errors.push(err);
+      }
+    }
+    let Assembler {
+      mut assembly,
+      mut instructions,
+      label_spans,
+      label_map,
+      fixups,
+      ..
+    } = assembler;
+    for (fixup, name, span, calls) in fixups {
+      let address = match assembly.addresses.get(&name) {
+        Some(address) => *address,
+        None if assembly.externs.contains_key(&name) => 0,
+        None => {
+          let error = Diagnostic::error(&file, span, format!("Undefined data label - '{}'", name));
+          errors.push(with_calls(error, &file, &calls));
+          continue;
         }
-        let reg = match isize::from_str_radix(reg, 16) {
-          Ok(r) => r,
-          Err(_) => {
-            print_error(i, line, format!("Invalid register specifier '{}'", reg));
+      };
+      match fixup {
+        Fixup::Register(i) => assembly.reg_inits[i].1 = address,
+        Fixup::Memory(i) => assembly.mem_inits[i].1 = address,
+        Fixup::Instruction(i) => {
+          if let PreInstruction::LI(_, value) = &mut instructions[i] {
+            *value = address;
           }
-        };
-        let instruction = match op.as_str() {
-          "SA" => PreInstruction::SA(reg),
-          "RB" => PreInstruction::RB(reg),
-          "SB" => PreInstruction::SB(reg),
-          "SF" => PreInstruction::SF(reg),
-          _ => unreachable!(),
-        };
-        instructions.push(instruction);
-        continue;
+        }
       }
-
-      if TWO_REG_OPS.contains(&op_num) {
-        if args.len() > 2 {
-          print_error(
-            i,
-            line,
-            format!(
-              "Too many arguments for {} operation (expected 2 registers, got {} args)",
-              op,
-              args.len()
-            ),
-          );
+      assembly.fixups.push((fixup, name, span));
+    }
+    for (name, span) in &assembly.globals {
+      if !label_map.contains_key(name) && !assembly.addresses.contains_key(name) {
+        errors.push(Diagnostic::error(
+          &file,
+          *span,
+          format!("Label '{}' is declared .global but never defined", name),
+        ));
+      }
+    }
+    for (name, span) in &assembly.externs {
+      if label_map.contains_key(name) || assembly.addresses.contains_key(name) {
+        errors.push(Diagnostic::error(
+          &file,
+          *span,
+          format!("Label '{}' is declared .extern but defined here", name),
+        ));
+      }
+    }
+    let end = instructions.len() as isize - 1;
+    for (j, x) in instructions.iter().enumerate() {
+      let resolve = |a: &String| match label_map.get(a) {
+        Some(a) => Ok(*a),
+        None => {
+          let (span, calls) = &label_spans[&j];
+          let error =
+            Diagnostic::error(&file, *span, format!("Undefined label reference - '{}'", a));
+          Err(with_calls(error, &file, calls))
         }
-        if args.len() < 2 {
-          print_error(
-            i,
-            line,
-            format!(
-              "Not enough arguments for {} operation (expected 2 registers, got {} args)",
-              op,
-              args.len()
-            ),
-          );
+      };
+      let instruction = match x {
+        PreInstruction::SA(a) => Ok(Instruction::SA(*a)),
+        PreInstruction::RB(a) => Ok(Instruction::RB(*a)),
+        PreInstruction::RD => Ok(Instruction::RD),
+        PreInstruction::WR => Ok(Instruction::WR),
+        PreInstruction::PRINT => Ok(Instruction::PRINT),
+        PreInstruction::SB(a) => Ok(Instruction::SB(*a)),
+        PreInstruction::SF(a) => Ok(Instruction::SF(*a)),
+        PreInstruction::GO(a)
+        | PreInstruction::BIN(a)
+        | PreInstruction::BIZ(a)
+        | PreInstruction::BIC(a)
+        | PreInstruction::BIV(a)
+        | PreInstruction::CALL(a)
+        | PreInstruction::SIV(a)
+          if !label_map.contains_key(a) && assembly.externs.contains_key(a) =>
+        {
+          assembly.imports.push((j, a.clone()));
+          let instruction = match x {
+            PreInstruction::GO(_) => Instruction::GO(end),
+            PreInstruction::BIN(_) => Instruction::BIN(end),
+            PreInstruction::BIZ(_) => Instruction::BIZ(end),
+            PreInstruction::BIC(_) => Instruction::BIC(end),
+            PreInstruction::BIV(_) => Instruction::BIV(end),
+            PreInstruction::SIV(_) => Instruction::SIV(end),
+            _ => Instruction::CALL(end),
+          };
+          Ok(instruction)
         }
-        let reg1 = args[0];
-        let reg1 = match isize::from_str_radix(reg1, 16) {
-          Ok(r) => r,
-          Err(_) => {
-            print_error(i, line, format!("Invalid register specifier '{}'", reg1));
-          }
-        };
-        let reg2 = args[1];
-        let reg2 = match isize::from_str_radix(reg2, 16) {
-          Ok(r) => r,
-          Err(_) => {
-            print_error(i, line, format!("Invalid register specifier '{}'", reg2));
-          }
-        };
-        let instruction = match op.as_str() {
-          "ADD" => PreInstruction::ADD(reg1, reg2),
-          "AND" => PreInstruction::AND(reg1, reg2),
-          "MV" => PreInstruction::MV(reg1, reg2),
-          "NOT" => PreInstruction::NOT(reg1, reg2),
-          "LS" => PreInstruction::LS(reg1, reg2),
-          "RS" => PreInstruction::RS(reg1, reg2),
-          "SW" => PreInstruction::SW(reg1, reg2),
-          _ => unreachable!(),
-        };
-        instructions.push(instruction);
-        continue;
+        PreInstruction::GO(a) => resolve(a).map(Instruction::GO),
+        PreInstruction::BIN(a) => resolve(a).map(Instruction::BIN),
+        PreInstruction::BIZ(a) => resolve(a).map(Instruction::BIZ),
+        PreInstruction::BIC(a) => resolve(a).map(Instruction::BIC),
+        PreInstruction::BIV(a) => resolve(a).map(Instruction::BIV),
+        PreInstruction::ADD(a, b) => Ok(Instruction::ADD(*a, *b)),
+        PreInstruction::AND(a, b) => Ok(Instruction::AND(*a, *b)),
+        PreInstruction::MV(a, b) => Ok(Instruction::MV(*a, *b)),
+        PreInstruction::NOT(a, b) => Ok(Instruction::NOT(*a, *b)),
+        PreInstruction::LS(a, b) => Ok(Instruction::LS(*a, *b)),
+        PreInstruction::RS(a, b) => Ok(Instruction::RS(*a, *b)),
+        PreInstruction::SW(a, b) => Ok(Instruction::SW(*a, *b)),
+        PreInstruction::LI(a, v) => Ok(Instruction::LI(*a, *v)),
+        PreInstruction::CALL(a) => resolve(a).map(Instruction::CALL),
+        PreInstruction::RET => Ok(Instruction::RET),
+        PreInstruction::PUSH(a) => Ok(Instruction::PUSH(*a)),
+        PreInstruction::POP(a) => Ok(Instruction::POP(*a)),
+        PreInstruction::SUB(a, b) => Ok(Instruction::SUB(*a, *b)),
+        PreInstruction::OR(a, b) => Ok(Instruction::OR(*a, *b)),
+        PreInstruction::XOR(a, b) => Ok(Instruction::XOR(*a, *b)),
+        PreInstruction::ASR(a, b) => Ok(Instruction::ASR(*a, *b)),
+        PreInstruction::ROL(a, b) => Ok(Instruction::ROL(*a, *b)),
+        PreInstruction::ROR(a, b) => Ok(Instruction::ROR(*a, *b)),
+        PreInstruction::HALT(a) => Ok(Instruction::HALT(*a)),
+        PreInstruction::EI => Ok(Instruction::EI),
+        PreInstruction::DI => Ok(Instruction::DI),
+        PreInstruction::SIV(a) => resolve(a).map(Instruction::SIV),
+        PreInstruction::RTI => Ok(Instruction::RTI),
+      };
+      match instruction {
+        Ok(instruction) => assembly.instructions.push(instruction),
+        Err(err) => errors.push(err),
       }
     }
-    let a = instructions
+    // Errors in the main file come first.
+    errors.sort_by(|a, b| (&a.file, a.line, a.columns).cmp(&(&b.file, b.line, b.columns)));
+    assembly.sources = file;
+    (assembly, errors)
+  }
+  /// An error for every branch to a label of another object, and every use of
+  /// the address of one, as the program cannot run until it is linked.
+  pub fn unlinked(&self) -> Vec<Diagnostic> {
+    let branches = self.imports.iter().map(|(i, name)| (self.spans[*i], name));
+    let addresses = self
+      .fixups
       .iter()
-      .enumerate()
-      .map(|(j, x)| match x {
-        PreInstruction::SA(a) => Instruction::SA(*a),
-        PreInstruction::RB(a) => Instruction::RB(*a),
-        PreInstruction::RD => Instruction::RD,
-        PreInstruction::WR => Instruction::WR,
-        PreInstruction::PRINT => Instruction::PRINT,
-        PreInstruction::SB(a) => Instruction::SB(*a),
-        PreInstruction::SF(a) => Instruction::SF(*a),
-        PreInstruction::GO(a) => match label_map.get(a) {
-          Some(a) => Instruction::GO(*a),
-          None => {
-            let (i, line) = lbl_lines.get(&j).unwrap();
-            print_error(*i, line, format!("Undefined label reference - '{}'", a))
-          }
-        },
-        PreInstruction::BIN(a) => match label_map.get(a) {
-          Some(a) => Instruction::BIN(*a),
-          None => {
-            let (i, line) = lbl_lines.get(&j).unwrap();
-            print_error(*i, line, format!("Undefined label reference - '{}'", a))
-          }
-        },
-        PreInstruction::BIZ(a) => match label_map.get(a) {
-          Some(a) => Instruction::BIZ(*a),
-          None => {
-            let (i, line) = lbl_lines.get(&j).unwrap();
-            print_error(*i, line, format!("Undefined label reference - '{}'", a))
-          }
-        },
-        PreInstruction::ADD(a, b) => Instruction::ADD(*a, *b),
-        PreInstruction::AND(a, b) => Instruction::AND(*a, *b),
-        PreInstruction::MV(a, b) => Instruction::MV(*a, *b),
-        PreInstruction::NOT(a, b) => Instruction::NOT(*a, *b),
-        PreInstruction::LS(a, b) => Instruction::LS(*a, *b),
-        PreInstruction::RS(a, b) => Instruction::RS(*a, *b),
-        PreInstruction::SW(a, b) => Instruction::SW(*a, *b),
+      .filter(|(_, name, _)| !self.addresses.contains_key(name))
+      .map(|(_, name, span)| (*span, name));
+    let mut errors = branches
+      .chain(addresses)
+      .map(|(span, name)| {
+        Diagnostic::error(
+          &self.sources,
+          span,
+          format!(
+            "Label '{}' is declared .extern, so the program must be linked with `vmal link` to run",
+            name
+          ),
+        )
       })
       .collect::<Vec<_>>();
-    assembly.instructions = a;
-    assembly
+    errors.sort_by(|a, b| (&a.file, a.line, a.columns).cmp(&(&b.file, b.line, b.columns)));
+    errors
   }
 }
 
 #[test]
 fn test_comments() {
-  let a = Assembly::assemble("#test");
+  let a = Assembly::try_assemble("#test").unwrap();
   assert_eq!(a.instructions.len(), 0);
-  let b = Assembly::assemble("#test\nADD A, B;");
+  let b = Assembly::try_assemble("#test\nADD A, B;").unwrap();
   assert_eq!(b.instructions.len(), 1);
-  let c = Assembly::assemble("ADD A, B; #Test");
+  let c = Assembly::try_assemble("ADD A, B; #Test").unwrap();
   assert_eq!(c.instructions.len(), 1);
   assert!(matches!(c.instructions[0], Instruction::ADD(..)));
 }
 
 #[test]
 fn test_register_init() {
-  let decimal = Assembly::assemble("4: 1024;");
+  let decimal = Assembly::try_assemble("4: 1024;").unwrap();
   assert_eq!(decimal.reg_inits[0], (4, 1024));
-  let hex = Assembly::assemble("4: 0x1D;");
+  let hex = Assembly::try_assemble("4: 0x1D;").unwrap();
   assert_eq!(hex.reg_inits[0], (4, 0x1D));
-  let binary = Assembly::assemble("4: 0b1010;");
+  let binary = Assembly::try_assemble("4: 0b1010;").unwrap();
   assert_eq!(binary.reg_inits[0], (4, 0b1010));
 }
 
 #[test]
 fn test_memory_init() {
-  let a = Assembly::assemble("[1024]: 34;");
+  let a = Assembly::try_assemble("[1024]: 34;").unwrap();
   assert_eq!(a.mem_inits[0], (1024, 34));
-  let b = Assembly::assemble("[0x401]: 0b101;");
+  let b = Assembly::try_assemble("[0x401]: 0b101;").unwrap();
   assert_eq!(b.mem_inits[0], (0x401, 0b101));
-  let c = Assembly::assemble("[0b10000000010]: 0x10;");
+  let c = Assembly::try_assemble("[0b10000000010]: 0x10;").unwrap();
   assert_eq!(c.mem_inits[0], (0b10000000010, 0x10));
-  let d = Assembly::assemble("[1056]: 34;");
+  let d = Assembly::try_assemble("[1056]: 34;").unwrap();
   assert_eq!(d.mem_inits[0], (1056, 34));
 }
 
 #[test]
 fn test_instructions() {
-  let a = Assembly::assemble("ADD E, A;");
+  let a = Assembly::try_assemble("ADD E, A;").unwrap();
   assert_eq!(a.instructions.len(), 1);
   assert_eq!(a.instructions[0], Instruction::ADD(0xe, 0xa));
-  let a = Assembly::assemble("AdD e, A;");
+  let a = Assembly::try_assemble("AdD e, A;").unwrap();
   assert_eq!(a.instructions.len(), 1);
   assert_eq!(a.instructions[0], Instruction::ADD(0xe, 0xa));
-  let a = Assembly::assemble("LBL JumpHere;\nADD E, 7;\nSF E;\nBIZ JumpHere;");
+  let a = Assembly::try_assemble("LBL JumpHere;\nADD E, 7;\nSF E;\nBIZ JumpHere;").unwrap();
   assert_eq!(a.instructions.len(), 3);
   assert_eq!(a.instructions[0], Instruction::ADD(0xe, 0x7));
   assert_eq!(a.instructions[1], Instruction::SF(0xe));
   assert_eq!(a.instructions[2], Instruction::BIZ(-1));
+  assert_eq!(a.lines, vec![1, 2, 3]);
+  let a = Assembly::try_assemble("li a, -0x1D;\nLI 0, table;\n.org 8\ntable: .word 1\n").unwrap();
+  assert_eq!(
+    a.instructions,
+    vec![Instruction::LI(0xa, -0x1d), Instruction::LI(0, 8)]
+  );
+  let errors = Assembly::try_assemble("LI A;\nLI Q, 1;\nLI A, 1x;\nLI A, B, C;").unwrap_err();
+  assert_eq!(
+    errors
+      .iter()
+      .map(|e| e.message.as_str())
+      .collect::<Vec<_>>(),
+    vec![
+      "Not enough arguments for LI operation (expected 1 register and 1 value, got 1 args)",
+      "Invalid register specifier 'Q'",
+      "Invalid character literal in LI operation - \"1x\"",
+      "Too many arguments for LI operation (expected 1 register and 1 value, got 3 args)",
+    ]
+  );
+  let a =
+    Assembly::try_assemble("CALL f;\nGO end;\nLBL f;\npush a;\nPOP B;\nRET;\nLBL end;").unwrap();
+  assert_eq!(
+    a.instructions,
+    vec![
+      Instruction::CALL(1),
+      Instruction::GO(4),
+      Instruction::PUSH(0xa),
+      Instruction::POP(0xb),
+      Instruction::RET
+    ]
+  );
+  let source = "sub a, b;\nOR A, B;\nXOR A, B;\nASR A, B;\nROL A, B;\nROR A, B;";
+  let a = Assembly::try_assemble(source).unwrap();
+  assert_eq!(
+    a.instructions,
+    vec![
+      Instruction::SUB(0xa, 0xb),
+      Instruction::OR(0xa, 0xb),
+      Instruction::XOR(0xa, 0xb),
+      Instruction::ASR(0xa, 0xb),
+      Instruction::ROL(0xa, 0xb),
+      Instruction::ROR(0xa, 0xb)
+    ]
+  );
+  let a = Assembly::try_assemble("halt;\nHALT A;").unwrap();
+  assert_eq!(
+    a.instructions,
+    vec![Instruction::HALT(5), Instruction::HALT(0xa)]
+  );
+  let a = Assembly::try_assemble("SIV tick;\nEI;\nDI;\nLBL tick;\nRTI;").unwrap();
+  assert_eq!(
+    a.instructions,
+    vec![
+      Instruction::SIV(2),
+      Instruction::EI,
+      Instruction::DI,
+      Instruction::RTI
+    ]
+  );
+  let errors = Assembly::try_assemble("HALT A, B;").unwrap_err();
+  assert_eq!(
+    errors[0].message,
+    "Too many arguments for HALT operation (expected at most 1 register, got 2 args)"
+  );
+  let strict = Settings {
+    strict: true,
+    width: None,
+  };
+  let errors = Assembly::try_assemble_with("ADD A, B;\nSUB A, B;", strict).unwrap_err();
+  assert_eq!(
+    errors[0].message,
+    "SUB is not part of classic VMAL, so it is not allowed in strict mode"
+  );
+  assert_eq!(errors.len(), 1);
+  for (op, line) in [
+    ("LI", "LI A, 1;"),
+    ("CALL", "LBL f;\nCALL f;"),
+    ("RET", "RET;"),
+    ("PUSH", "PUSH A;"),
+    ("POP", "POP A;"),
+  ] {
+    let errors = Assembly::try_assemble_with(line, strict).unwrap_err();
+    assert_eq!(
+      errors[0].message,
+      format!(
+        "{} is not part of classic VMAL, so it is not allowed in strict mode",
+        op
+      )
+    );
+    assert!(Assembly::try_assemble(line).is_ok(), "{}", line);
+  }
+  let errors = Assembly::try_assemble_with(
+    ".width 16\n",
+    Settings {
+      strict: false,
+      width: Some(32),
+    },
+  )
+  .unwrap_err();
+  assert_eq!(errors[0].message, ".width 16 does not match --width 32");
+}
+
+#[test]
+fn test_diagnostics() {
+  let (a, errors) = Assembly::check("7: 1;\nA: 1;\nA: 2;\nADD A, Q;\nGO nowhere;\nLBL x;\nGO x;");
+  assert_eq!(
+    errors.iter().map(|e| e.line).collect::<Vec<_>>(),
+    vec![3, 4]
+  );
+  assert_eq!(
+    a.warnings.iter().map(|w| w.line).collect::<Vec<_>>(),
+    vec![0, 2]
+  );
+  assert_eq!(a.labels["x"], Span::new(44, 45));
+  assert_eq!(
+    a.label_refs,
+    vec![
+      ("nowhere".to_owned(), Span::new(31, 38)),
+      ("x".to_owned(), Span::new(50, 51))
+    ]
+  );
+  assert_eq!(
+    errors[0].to_string(),
+    "Error on line #4: Invalid register specifier 'Q'\n\t>ADD A, Q;\n\t        ^"
+  );
+  assert!(Assembly::try_assemble("GO nowhere;").is_err());
+}
+
+#[test]
+fn test_labels_and_spacing() {
+  let a = Assembly::try_assemble("LBL _loop2;\nGO\t_loop2;\nrd;").unwrap();
+  assert_eq!(a.instructions, vec![Instruction::GO(-1), Instruction::RD]);
+  for source in &["LBL 1abc;", "LBL a-b;", "LBL \"x\";"] {
+    assert!(Assembly::try_assemble(*source).is_err(), "{}", source);
+  }
+  let errors = Assembly::try_assemble("\tADD E, 7, 8;").unwrap_err();
+  assert_eq!(errors[0].columns, (11, 12));
+  assert!(errors[0].to_string().ends_with("\n\t \t          ^"));
+}
+
+#[test]
+fn test_data() {
+  let a = Assembly::try_assemble(
+    "\
+.org 0x10
+table: .word 1, -2, 0x3
+msg: .asciz \"Hi\"
+.space 2
+zeros: .fill 3, 0
+ptrs: .word table, msg
+A: table;
+[0]: ptrs;
+",
+  )
+  .unwrap();
+  assert_eq!(
+    a.mem_inits,
+    vec![
+      (16, 1),
+      (17, -2),
+      (18, 3),
+      (19, 'H' as isize),
+      (20, 'i' as isize),
+      (21, 0),
+      (24, 0),
+      (25, 0),
+      (26, 0),
+      (27, 16),
+      (28, 19),
+      (0, 27)
+    ]
+  );
+  assert_eq!(a.reg_inits, vec![(0xA, 16)]);
+  assert_eq!(a.addresses["zeros"], 24);
+  assert!(a.warnings.is_empty());
+
+  let (a, errors) = Assembly::check(
+    ".word;\n.fill 2;\n.space -1\n.ascii 5\nA: nowhere;\nx: .global y\na: .word 1\nLBL a;\n",
+  );
+  assert_eq!(
+    errors
+      .iter()
+      .map(|e| e.message.as_str())
+      .collect::<Vec<_>>(),
+    vec![
+      "Wrong number of arguments for .word (expected 1, got 0)",
+      "Wrong number of arguments for .fill (expected 2, got 1)",
+      "Expected a count of at least 0 for .space, found -1",
+      "Expected a string in quotes after .ascii",
+      "Undefined data label - 'nowhere'",
+      "Only data directives can have a label, not .global",
+      "Label 'a' already defined",
+    ]
+  );
+  assert!(a.warnings.is_empty());
+  let a = Assembly::try_assemble(".word 1, 2\n[1]: 3;\n").unwrap();
+  assert_eq!(
+    a.warnings[0].message,
+    "Memory location 1 is initialized more than once"
+  );
+}
+
+#[test]
+fn test_width() {
+  let a = Assembly::try_assemble(".width 8\nA: -128;\nB: 0xFF;\n.word 0b10000000\n").unwrap();
+  assert_eq!(a.width, 8);
+  assert_eq!(a.reg_inits, vec![(0xA, -128), (0xB, 0xFF)]);
+  let a = Assembly::try_assemble(".width 64\nA: 0xFFFFFFFFFFFFFFFF;\n").unwrap();
+  assert_eq!(a.reg_inits, vec![(0xA, -1)]);
+  assert_eq!(
+    Assembly::try_assemble("A: 1;").unwrap().width,
+    DEFAULT_WIDTH
+  );
+
+  let (_, errors) = Assembly::check(
+    ".width 12\n.width\n.width 16\n.width 8\nA: 0x10000;\nLI B, -32769;\n.fill 2, 65536\nx: .width 16\n[0x20000]: 1;\n",
+  );
+  assert_eq!(
+    errors
+      .iter()
+      .map(|e| e.message.as_str())
+      .collect::<Vec<_>>(),
+    vec![
+      "Word width must be 8, 16, 32 or 64 bits, not 12",
+      "Expected a word width in bits after .width",
+      "Word width already set to 16 bits",
+      "0x10000 does not fit in a 16-bit word",
+      "-32769 does not fit in a 16-bit word",
+      "65536 does not fit in a 16-bit word",
+      "Only data directives can have a label, not .width",
+      "0x20000 does not fit in a 16-bit word",
+    ]
+  );
+  assert_eq!(errors.last().unwrap().columns, (1, 8));
+
+  let (_, errors) = Assembly::check(
+    ".width 8\n.org 300\nx: .word 1\nLI A, x;\n.org 0xFE\n.word 1, 2, 3\n.space 2\n.fill 3, 0\n.ascii \"ab\"\n",
+  );
+  assert_eq!(
+    errors
+      .iter()
+      .map(|e| e.message.as_str())
+      .collect::<Vec<_>>(),
+    vec![
+      "300 does not fit in a 8-bit word",
+      ".word lays out words past the last address of a 8-bit word",
+      ".fill lays out words past the last address of a 8-bit word",
+      ".ascii lays out words past the last address of a 8-bit word",
+    ]
+  );
+  let (a, errors) = Assembly::check(".width 8\n.org 0xFF\n.word 1\n.space 0\n");
+  assert!(errors.is_empty());
+  assert_eq!(a.mem_inits, vec![(0xFF, 1)]);
+  let (_, errors) = Assembly::check(".org 0x100000000\n");
+  assert_eq!(
+    errors[0].message,
+    "0x100000000 does not fit in a 32-bit word"
+  );
 }
@@ -0,0 +1,786 @@
+//! The bus memory accesses go through, which routes every address to the
+//! device mapped over it.
+//!
+//! A device is mapped over a range of addresses and sees accesses as offsets
+//! into the range. The standard machine is RAM alone, as in classic VMAL, and
+//! can have a console and a timer mapped to the top of it. Other machines are read from a description listing their devices:
+//!
+//! ```json
+//! {
+//!   "devices": [
+//!     { "type": "ram", "start": 0, "size": "0x10000" },
+//!     { "type": "rom", "start": "0x10000", "size": 256 },
+//!     { "type": "console", "start": "0xFFFFFFFE" },
+//!     { "type": "timer", "start": "0x20000", "period": 100 },
+//!     { "type": "random", "start": "0x20001", "seed": 42 }
+//!   ]
+//! }
+//! ```
+//!
+//! Addresses are numbers or strings holding decimal or `0x` hexadecimal
+//! numbers, and may be negative to count down from the top of the address
+//! space. RAM without a range covers every address no other device is mapped
+//! to.
+//!
+//! Memory spans every address a word can hold unless the description gives
+//! its `size` in words, or the number of `address_bits` that reach it. Going
+//! outside it is a fault, or wraps around to its start if `wrap` is true.
+
+use std::any::Any;
+use std::fmt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+
+use crate::{
+  console::Console,
+  memory::{Backend, Memory},
+};
+
+/// Something memory accesses can be routed to. Offsets count words from the
+/// start of the range the device is mapped over.
+pub trait Device: Any + Send {
+  /// Reads a word, which may have side effects such as taking input.
+  fn read(&mut self, offset: isize) -> isize;
+  fn write(&mut self, offset: isize, val: isize);
+  /// Stores a word the program starts with, which ROM accepts even though the
+  /// program cannot write it.
+  fn load(&mut self, offset: isize, val: isize) {
+    self.write(offset, val);
+  }
+  /// The word at an offset for debuggers to show, without side effects, or
+  /// None if there is nothing to show.
+  fn peek(&self, _offset: isize) -> Option<isize> {
+    None
+  }
+  /// Every word the device holds, as offsets and values.
+  fn cells(&self) -> Vec<(isize, isize)> {
+    vec![]
+  }
+  /// Whether the word at an offset has been written or loaded. Only memory
+  /// has words that were not.
+  fn written(&self, _offset: isize) -> bool {
+    true
+  }
+  /// Called after every instruction. Returns true to interrupt the program.
+  fn tick(&mut self) -> bool {
+    false
+  }
+  /// Called instead of `tick` for `n` instructions at once, when nothing
+  /// could have read the device in between. Returns true if any of the ticks
+  /// would have interrupted the program.
+  fn tick_many(&mut self, n: isize) -> bool {
+    let mut interrupt = false;
+    for _ in 0..n {
+      interrupt |= self.tick();
+    }
+    interrupt
+  }
+}
+
+/// Memory that reads 0 from words never written to it.
+#[derive(Debug)]
+pub struct Ram {
+  words: Box<dyn Memory>,
+}
+
+impl Ram {
+  /// RAM of `size` words, or reaching any address if None, stored in
+  /// `backend`.
+  pub fn new(backend: Backend, size: Option<isize>) -> Result<Self, String> {
+    Ok(Ram {
+      words: backend.memory(size)?,
+    })
+  }
+}
+
+impl Default for Ram {
+  fn default() -> Self {
+    Ram::new(Backend::default(), None).unwrap()
+  }
+}
+
+impl Device for Ram {
+  fn read(&mut self, offset: isize) -> isize {
+    self.words.get(offset).unwrap_or(0)
+  }
+  fn write(&mut self, offset: isize, val: isize) {
+    self.words.set(offset, val);
+  }
+  fn peek(&self, offset: isize) -> Option<isize> {
+    self.words.get(offset)
+  }
+  fn cells(&self) -> Vec<(isize, isize)> {
+    self.words.cells()
+  }
+  fn written(&self, offset: isize) -> bool {
+    self.words.get(offset).is_some()
+  }
+}
+
+/// Memory the program can only read, holding the words it was loaded with.
+#[derive(Debug, Default)]
+pub struct Rom {
+  words: Ram,
+}
+
+impl Device for Rom {
+  fn read(&mut self, offset: isize) -> isize {
+    self.words.read(offset)
+  }
+  fn write(&mut self, _offset: isize, _val: isize) {}
+  fn load(&mut self, offset: isize, val: isize) {
+    self.words.write(offset, val);
+  }
+  fn peek(&self, offset: isize) -> Option<isize> {
+    self.words.peek(offset)
+  }
+  fn cells(&self) -> Vec<(isize, isize)> {
+    self.words.cells()
+  }
+  fn written(&self, offset: isize) -> bool {
+    self.words.written(offset)
+  }
+}
+
+/// Counts the instructions run, and interrupts the program every `period` of
+/// them. The count is its first word and the period its second, and both can
+/// be written. A period of 0 never interrupts.
+#[derive(Debug, Default)]
+pub struct Timer {
+  pub count: isize,
+  pub period: isize,
+}
+
+impl Device for Timer {
+  fn read(&mut self, offset: isize) -> isize {
+    self.peek(offset).unwrap()
+  }
+  fn write(&mut self, offset: isize, val: isize) {
+    if offset == 0 {
+      self.count = val;
+    } else {
+      self.period = val;
+    }
+  }
+  fn peek(&self, offset: isize) -> Option<isize> {
+    Some(if offset == 0 { self.count } else { self.period })
+  }
+  fn tick(&mut self) -> bool {
+    self.count = self.count.wrapping_add(1);
+    self.period > 0 && self.count % self.period == 0
+  }
+  fn tick_many(&mut self, n: isize) -> bool {
+    let count = self.count.wrapping_add(n);
+    if self.period <= 0 {
+      self.count = count;
+      return false;
+    }
+    if self.count < 0 || count < self.count {
+      // The count goes through 0 or wraps around, so count every tick.
+      let mut interrupt = false;
+      for _ in 0..n {
+        interrupt |= self.tick();
+      }
+      return interrupt;
+    }
+    // Whether a multiple of the period was passed.
+    let interrupt = count / self.period > self.count / self.period;
+    self.count = count;
+    interrupt
+  }
+}
+
+/// Gives a new pseudo-random word every time it is read. Writing a word seeds
+/// it.
+#[derive(Debug)]
+pub struct Random {
+  state: u64,
+}
+
+impl Random {
+  pub fn new(seed: u64) -> Self {
+    // Xorshift gets stuck at 0.
+    Random { state: seed.max(1) }
+  }
+  /// A generator seeded from the clock.
+  pub fn from_clock() -> Self {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    Random::new(now.as_nanos() as u64)
+  }
+}
+
+impl Device for Random {
+  fn read(&mut self, _offset: isize) -> isize {
+    self.state ^= self.state << 13;
+    self.state ^= self.state >> 7;
+    self.state ^= self.state << 17;
+    self.state as isize
+  }
+  fn write(&mut self, _offset: isize, val: isize) {
+    *self = Random::new(val as u64);
+  }
+}
+
+struct Mapping {
+  /// Kind of device, shown in errors.
+  name: String,
+  start: isize,
+  size: isize,
+  device: Box<dyn Device>,
+}
+
+impl Mapping {
+  fn offset(&self, addr: isize) -> Option<isize> {
+    let offset = addr.wrapping_sub(self.start);
+    (0..self.size).contains(&offset).then_some(offset)
+  }
+}
+
+pub struct Bus {
+  mappings: Vec<Mapping>,
+  /// RAM behind every address no device is mapped to, if the machine has it.
+  pub ram: Option<Ram>,
+  /// Number of words of memory, or None for every address a word can hold.
+  size: Option<isize>,
+  /// Whether addresses past the end of memory wrap around to its start,
+  /// instead of being a fault.
+  pub wrap: bool,
+}
+
+impl Bus {
+  /// A machine with nothing but RAM.
+  pub fn new() -> Self {
+    Bus {
+      mappings: vec![],
+      ram: Some(Ram::default()),
+      size: None,
+      wrap: false,
+    }
+  }
+  /// A machine with `size` words of RAM.
+  pub fn with_size(width: u32, size: isize) -> Result<Self, String> {
+    let mut bus = Bus::new();
+    bus.set_size(size, width)?;
+    Ok(bus)
+  }
+  /// Maps the standard console to the last two addresses of memory and a
+  /// timer that does not interrupt below them, on a machine with nothing else
+  /// mapped.
+  pub fn map_devices(&mut self, width: u32) {
+    let top = self.last_address(width);
+    let console = Console::new(width);
+    self
+      .map("console", console_port(top), 2, Box::new(console))
+      .unwrap();
+    self
+      .map("timer", timer_port(top), 2, Box::new(Timer::default()))
+      .unwrap();
+  }
+
+  /// Limits memory to `size` words, failing if that is too few, more than a
+  /// word of `width` bits can address, or leaves out a mapped device.
+  pub fn set_size(&mut self, size: isize, width: u32) -> Result<(), String> {
+    if size < MIN_SIZE {
+      return Err(format!(
+        "Memory size must be at least {} words, not {}",
+        MIN_SIZE, size
+      ));
+    }
+    if width < isize::BITS && size > crate::vm::word_mask(width) + 1 {
+      return Err(format!(
+        "Memory size {:#X} is more than a {}-bit word can address",
+        size, width
+      ));
+    }
+    if let Some(m) = self
+      .mappings
+      .iter()
+      .find(|m| m.start as u64 >= size as u64 || m.start as u64 + m.size as u64 > size as u64)
+    {
+      return Err(format!(
+        "{} at {:#X} is outside memory of {:#X} words",
+        m.name, m.start, size
+      ));
+    }
+    self.size = Some(size);
+    Ok(())
+  }
+  /// Stores the words of RAM and ROM in `backend`, keeping those already
+  /// there.
+  pub fn set_backend(&mut self, backend: Backend, width: u32) -> Result<(), String> {
+    fn replace(ram: &mut Ram, backend: Backend, size: Option<isize>) -> Result<(), String> {
+      let mut words = backend.memory(size)?;
+      for (offset, val) in ram.cells() {
+        words.set(offset, val);
+      }
+      ram.words = words;
+      Ok(())
+    }
+    let size = self
+      .size
+      .or_else(|| (width < isize::BITS).then(|| crate::vm::word_mask(width) + 1));
+    if let Some(ram) = &mut self.ram {
+      replace(ram, backend, size)?;
+    }
+    for m in &mut self.mappings {
+      let device: &mut dyn Any = m.device.as_mut();
+      if let Some(ram) = device.downcast_mut::<Ram>() {
+        replace(ram, backend, Some(m.size))?;
+      } else if let Some(rom) = device.downcast_mut::<Rom>() {
+        replace(&mut rom.words, backend, Some(m.size))?;
+      }
+    }
+    Ok(())
+  }
+  /// The highest address in memory.
+  pub fn last_address(&self, width: u32) -> isize {
+    self
+      .size
+      .map_or(crate::vm::word_mask(width), |size| size - 1)
+  }
+  /// The address in memory an address refers to, or None if it is outside
+  /// memory and does not wrap around.
+  pub fn resolve(&self, addr: isize) -> Option<isize> {
+    let size = match self.size {
+      Some(size) => size as u64,
+      None => return Some(addr),
+    };
+    if (addr as u64) < size {
+      Some(addr)
+    } else if self.wrap {
+      Some((addr as u64 % size) as isize)
+    } else {
+      None
+    }
+  }
+
+  /// Maps a device over `size` words from `start`, failing if another device
+  /// is mapped to any of them.
+  pub fn map(
+    &mut self,
+    name: &str,
+    start: isize,
+    size: isize,
+    device: Box<dyn Device>,
+  ) -> Result<(), String> {
+    let end = start.wrapping_add(size - 1);
+    if let Some(other) = self.mappings.iter().find(|m| {
+      m.offset(start).is_some() || m.offset(end).is_some() || (start..=end).contains(&m.start)
+    }) {
+      return Err(format!(
+        "{} at {:#X} overlaps {} at {:#X}",
+        name, start, other.name, other.start
+      ));
+    }
+    self.mappings.push(Mapping {
+      name: name.to_owned(),
+      start,
+      size,
+      device,
+    });
+    Ok(())
+  }
+
+  /// The device an address is routed to, and the offset into it.
+  fn route(&mut self, addr: isize) -> Option<(&mut dyn Device, isize)> {
+    for mapping in &mut self.mappings {
+      if let Some(offset) = mapping.offset(addr) {
+        return Some((mapping.device.as_mut(), offset));
+      }
+    }
+    let ram = self.ram.as_mut()?;
+    Some((ram, addr))
+  }
+
+  /// RAM, if nothing else is mapped, so every address in memory reaches it.
+  pub fn only_ram(&mut self) -> Option<&mut Ram> {
+    if self.mappings.is_empty() {
+      self.ram.as_mut()
+    } else {
+      None
+    }
+  }
+
+  /// Whether a device other than RAM is mapped to an address.
+  pub fn mapped(&self, addr: isize) -> bool {
+    self.mappings.iter().any(|m| m.offset(addr).is_some())
+  }
+
+  /// Reads a word, which is 0 where no device is mapped.
+  pub fn read(&mut self, addr: isize) -> isize {
+    self
+      .route(addr)
+      .map_or(0, |(device, offset)| device.read(offset))
+  }
+  /// Writes a word, which is lost where no device is mapped.
+  pub fn write(&mut self, addr: isize, val: isize) {
+    if let Some((device, offset)) = self.route(addr) {
+      device.write(offset, val);
+    }
+  }
+  /// Stores a word the program starts with.
+  pub fn load(&mut self, addr: isize, val: isize) {
+    if let Some((device, offset)) = self.route(addr) {
+      device.load(offset, val);
+    }
+  }
+  /// Whether the word at an address has been written or loaded, which words
+  /// of devices other than RAM and ROM always have.
+  pub fn written(&self, addr: isize) -> bool {
+    for mapping in &self.mappings {
+      if let Some(offset) = mapping.offset(addr) {
+        return mapping.device.written(offset);
+      }
+    }
+    self.ram.as_ref().is_some_and(|ram| ram.written(addr))
+  }
+  /// The word at an address for debuggers to show, if there is one.
+  pub fn peek(&self, addr: isize) -> Option<isize> {
+    for mapping in &self.mappings {
+      if let Some(offset) = mapping.offset(addr) {
+        return mapping.device.peek(offset);
+      }
+    }
+    self.ram.as_ref()?.peek(addr)
+  }
+  /// Every word held by RAM, ROM and the other devices, by address.
+  pub fn cells(&self) -> Vec<(isize, isize)> {
+    let mut cells = self
+      .mappings
+      .iter()
+      .flat_map(|m| {
+        let start = m.start;
+        m.device
+          .cells()
+          .into_iter()
+          .map(move |(offset, val)| (start.wrapping_add(offset), val))
+      })
+      .chain(self.ram.iter().flat_map(|ram| ram.cells()))
+      .collect::<Vec<_>>();
+    cells.sort_unstable();
+    cells
+  }
+  /// Lets every device know an instruction has run, returning true if any of
+  /// them interrupts the program.
+  pub fn tick(&mut self) -> bool {
+    let mut interrupt = false;
+    for mapping in &mut self.mappings {
+      interrupt |= mapping.device.tick();
+    }
+    interrupt
+  }
+  /// Lets every device know `n` instructions have run, returning true if any
+  /// of them interrupts the program.
+  pub fn tick_many(&mut self, n: isize) -> bool {
+    let mut interrupt = false;
+    for mapping in &mut self.mappings {
+      interrupt |= mapping.device.tick_many(n);
+    }
+    interrupt
+  }
+  /// The first device of type `T`.
+  pub fn device_mut<T: Device>(&mut self) -> Option<&mut T> {
+    self.mappings.iter_mut().find_map(|m| {
+      let device: &mut dyn Any = m.device.as_mut();
+      device.downcast_mut::<T>()
+    })
+  }
+
+  /// Reads a machine description file.
+  pub fn from_file(path: &Path, width: u32) -> Result<Self, String> {
+    let text = std::fs::read_to_string(path)
+      .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    Bus::from_description(&text, width).map_err(|e| format!("{}: {}", path.display(), e))
+  }
+
+  /// Builds the machine a description lists the devices of, for words of
+  /// `width` bits.
+  pub fn from_description(text: &str, width: u32) -> Result<Self, String> {
+    let value: Value =
+      serde_json::from_str(text).map_err(|e| format!("Invalid machine description: {}", e))?;
+    let devices = value["devices"]
+      .as_array()
+      .ok_or("Missing 'devices' in machine description")?;
+    let mask = crate::vm::word_mask(width);
+    let mut bus = Bus {
+      mappings: vec![],
+      ram: None,
+      size: None,
+      wrap: false,
+    };
+    for (i, device) in devices.iter().enumerate() {
+      let kind = device["type"].as_str().unwrap_or_default();
+      let field = |name: &str| -> Result<Option<isize>, String> {
+        let number = match &device[name] {
+          Value::Null => return Ok(None),
+          Value::Number(n) => n.as_i64().map(|n| n as isize),
+          Value::String(s) => parse_number(s),
+          _ => None,
+        };
+        number.map(Some).ok_or_else(|| {
+          format!(
+            "Invalid '{}' for device {} ({}): {}",
+            name, i, kind, device[name]
+          )
+        })
+      };
+      let (start, size) = (field("start")?, field("size")?);
+      let (device, fixed_size): (Box<dyn Device>, Option<isize>) = match kind {
+        "ram" if start.is_none() && size.is_none() => {
+          if bus.ram.is_some() {
+            return Err("Only one RAM can be without a range".to_owned());
+          }
+          bus.ram = Some(Ram::default());
+          continue;
+        }
+        "ram" => (Box::new(Ram::default()), None),
+        "rom" => (Box::new(Rom::default()), None),
+        "console" => (Box::new(Console::new(width)), Some(2)),
+        "timer" => {
+          let timer = Timer {
+            count: 0,
+            period: field("period")?.unwrap_or(0),
+          };
+          (Box::new(timer), Some(2))
+        }
+        "random" => match field("seed")? {
+          Some(seed) => (Box::new(Random::new(seed as u64)), Some(1)),
+          None => (Box::new(Random::from_clock()), Some(1)),
+        },
+        _ => return Err(format!("Unknown device type '{}'", kind)),
+      };
+      let start = start.ok_or_else(|| format!("Device {} ({}) needs a start", i, kind))?;
+      let size = match (fixed_size, size) {
+        (Some(fixed), None) => fixed,
+        (Some(fixed), Some(size)) if size == fixed => fixed,
+        (Some(fixed), Some(_)) => {
+          return Err(format!(
+            "Device {} ({}) always has a size of {}",
+            i, kind, fixed
+          ))
+        }
+        (None, Some(size)) if size > 0 => size,
+        (None, _) => {
+          return Err(format!(
+            "Device {} ({}) needs a size of at least 1",
+            i, kind
+          ))
+        }
+      };
+      bus.map(kind, start & mask, size, device)?;
+    }
+    let size = match (&value["size"], &value["address_bits"]) {
+      (Value::Null, Value::Null) => None,
+      (size, Value::Null) => {
+        let size = match size {
+          Value::Number(n) => n.as_i64().map(|n| n as isize),
+          Value::String(s) => parse_number(s),
+          _ => None,
+        };
+        Some(
+          size
+            .ok_or_else(|| format!("Invalid 'size' in machine description: {}", value["size"]))?,
+        )
+      }
+      (Value::Null, bits) => match bits.as_u64() {
+        Some(bits) if bits < width as u64 => Some(1 << bits),
+        Some(bits) if bits == width as u64 => None,
+        _ => {
+          return Err(format!(
+            "Invalid 'address_bits' in machine description: {}",
+            bits
+          ))
+        }
+      },
+      _ => {
+        return Err("A machine description cannot give both 'size' and 'address_bits'".to_owned())
+      }
+    };
+    if let Some(size) = size {
+      bus.set_size(size, width)?;
+    }
+    bus.wrap = match &value["wrap"] {
+      Value::Null => false,
+      Value::Bool(wrap) => *wrap,
+      wrap => return Err(format!("Invalid 'wrap' in machine description: {}", wrap)),
+    };
+    Ok(bus)
+  }
+}
+
+impl Default for Bus {
+  fn default() -> Self {
+    Bus::new()
+  }
+}
+
+impl fmt::Debug for Bus {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let mappings = self
+      .mappings
+      .iter()
+      .map(|m| (&m.name, m.start, m.size))
+      .collect::<Vec<_>>();
+    f.debug_struct("Bus")
+      .field("mappings", &mappings)
+      .field("ram", &self.ram)
+      .field("size", &self.size)
+      .field("wrap", &self.wrap)
+      .finish()
+  }
+}
+
+/// Fewest words memory can have, which leaves room for the standard devices
+/// and a stack below them.
+pub const MIN_SIZE: isize = 16;
+
+/// Address of the standard console's character port, the second to last
+/// address of memory ending at `top`.
+pub fn console_port(top: isize) -> isize {
+  top - 1
+}
+
+/// Address of the standard timer's count, with its period after it, just
+/// below the console.
+pub fn timer_port(top: isize) -> isize {
+  console_port(top) - 2
+}
+
+/// Parses a decimal or `0x` hexadecimal number, which may be negative.
+pub fn parse_number(s: &str) -> Option<isize> {
+  let (negative, digits) = match s.strip_prefix('-') {
+    Some(digits) => (true, digits),
+    None => (false, s),
+  };
+  let value = match digits.strip_prefix("0x") {
+    Some(hex) => u64::from_str_radix(hex, 16).ok()? as isize,
+    None => digits.parse().ok()?,
+  };
+  Some(if negative {
+    value.wrapping_neg()
+  } else {
+    value
+  })
+}
+
+#[test]
+fn test_bus() {
+  let mut bus = Bus::new();
+  assert!(bus.device_mut::<Console>().is_none());
+  bus.map_devices(16);
+  bus.write(3, 7);
+  bus.load(0xFFFE, 'x' as isize);
+  assert_eq!((bus.read(3), bus.read(4)), (7, 0));
+  assert_eq!(
+    (bus.peek(3), bus.peek(4), bus.peek(0xFFFE)),
+    (Some(7), None, None)
+  );
+  assert_eq!(bus.cells(), vec![(3, 7)]);
+  assert!(bus.device_mut::<Console>().is_some());
+  assert!(bus.device_mut::<Timer>().is_some());
+  assert!(bus.device_mut::<Random>().is_none());
+  assert_eq!(
+    bus.map("rom", 0xFFF0, 0x10, Box::new(Rom::default())),
+    Err("rom at 0xFFF0 overlaps console at 0xFFFE".to_owned())
+  );
+  assert_eq!(
+    bus.map("rom", 0xFFF0, 0xD, Box::new(Rom::default())),
+    Err("rom at 0xFFF0 overlaps timer at 0xFFFC".to_owned())
+  );
+
+  let description = r#"{"devices": [
+    {"type": "ram", "start": 0, "size": 16},
+    {"type": "rom", "start": "0x10", "size": 4},
+    {"type": "timer", "start": -3, "period": 3},
+    {"type": "random", "start": "0xFF", "seed": 1}
+  ]}"#;
+  let mut bus = Bus::from_description(description, 8).unwrap();
+  bus.load(0x10, 5);
+  bus.write(0x10, 6);
+  bus.write(0x20, 1);
+  bus.write(15, 2);
+  assert_eq!((bus.read(0x10), bus.read(0x20), bus.read(15)), (5, 0, 2));
+  assert!(!bus.tick() && !bus.tick());
+  assert_eq!(bus.read(0xFD), 2);
+  assert!(bus.tick());
+  assert_eq!(bus.read(0xFE), 3);
+  assert_ne!(bus.read(0xFF), bus.read(0xFF));
+  assert_eq!(bus.cells(), vec![(15, 2), (0x10, 5)]);
+
+  for (description, error) in [
+    ("[]", "Missing 'devices' in machine description"),
+    (
+      r#"{"devices": [{"type": "disk", "start": 0}]}"#,
+      "Unknown device type 'disk'",
+    ),
+    (
+      r#"{"devices": [{"type": "rom", "start": 0}]}"#,
+      "Device 0 (rom) needs a size of at least 1",
+    ),
+    (
+      r#"{"devices": [{"type": "timer", "start": "x"}]}"#,
+      "Invalid 'start' for device 0 (timer): \"x\"",
+    ),
+    (
+      r#"{"devices": [{"type": "ram", "start": 0, "size": 8}, {"type": "timer", "start": 7}]}"#,
+      "timer at 0x7 overlaps ram at 0x0",
+    ),
+  ] {
+    assert_eq!(Bus::from_description(description, 32).unwrap_err(), error);
+  }
+
+  let mut bus = Bus::with_size(16, 0x100).unwrap();
+  assert_eq!(bus.last_address(16), 0xFF);
+  assert_eq!((bus.resolve(0xFF), bus.resolve(0x100)), (Some(0xFF), None));
+  assert!(!bus.written(0xFE));
+  bus.map_devices(16);
+  assert!(bus.written(0xFE) && !bus.written(0));
+  let description = r#"{"address_bits": 5, "wrap": true, "devices": [
+    {"type": "console", "start": 30},
+    {"type": "ram", "start": 0, "size": 16}
+  ]}"#;
+  let bus = Bus::from_description(description, 16).unwrap();
+  assert_eq!((bus.resolve(0x21), bus.last_address(16)), (Some(1), 31));
+  assert!(!bus.written(16));
+  for (description, width, error) in [
+    (
+      r#"{"size": 8, "devices": []}"#,
+      16,
+      "Memory size must be at least 16 words, not 8",
+    ),
+    (
+      r#"{"size": "0x101", "devices": []}"#,
+      8,
+      "Memory size 0x101 is more than a 8-bit word can address",
+    ),
+    (
+      r#"{"address_bits": 9, "devices": []}"#,
+      8,
+      "Invalid 'address_bits' in machine description: 9",
+    ),
+    (
+      r#"{"size": 32, "devices": [{"type": "console", "start": 31}]}"#,
+      16,
+      "console at 0x1F is outside memory of 0x20 words",
+    ),
+  ] {
+    assert_eq!(
+      Bus::from_description(description, width).unwrap_err(),
+      error
+    );
+  }
+
+  let description = r#"{"devices": [{"type": "rom", "start": 0, "size": 4}, {"type": "ram"}]}"#;
+  let mut bus = Bus::from_description(description, 16).unwrap();
+  bus.load(1, 5);
+  bus.write(0x100, 6);
+  bus.set_backend(Backend::Dense, 16).unwrap();
+  assert_eq!(bus.cells(), vec![(1, 5), (0x100, 6)]);
+  assert!(bus.written(1) && !bus.written(2));
+  assert_eq!(
+    Bus::new().set_backend(Backend::Dense, 32),
+    Err("Dense memory holds at most 0x1000000 words, not 0x100000000".to_owned())
+  );
+}
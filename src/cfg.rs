@@ -0,0 +1,243 @@
+//! Splits programs into basic blocks and renders their control-flow graph in
+//! Graphviz DOT.
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::assembler::{Assembly, Instruction};
+
+/// Index of the instruction a branch jumps to. Branches hold the index before
+/// their target, as PC is incremented after every instruction.
+fn target(t: isize) -> usize {
+  (t + 1) as usize
+}
+
+/// Indices of the instructions that can run after the one at `i`. A `CALL`
+/// continues at its target and `RET` at the instruction after any `CALL`,
+/// except that calls to other objects are assumed to return. Nothing runs
+/// after `HALT`, and nothing is known to run after `RTI`, which goes back to
+/// wherever the program was interrupted.
+pub fn successors(code: &[Instruction], i: usize) -> Vec<usize> {
+  let next = match code[i] {
+    Instruction::GO(t) => vec![target(t)],
+    Instruction::BIN(t) | Instruction::BIZ(t) | Instruction::BIC(t) | Instruction::BIV(t) => {
+      vec![i + 1, target(t)]
+    }
+    Instruction::CALL(t) if target(t) >= code.len() => vec![i + 1],
+    Instruction::CALL(t) => vec![target(t)],
+    Instruction::RET => code
+      .iter()
+      .enumerate()
+      .filter(|(_, op)| matches!(op, Instruction::CALL(_)))
+      .map(|(j, _)| j + 1)
+      .collect(),
+    Instruction::HALT(_) | Instruction::RTI => vec![],
+    _ => vec![i + 1],
+  };
+  next.into_iter().filter(|j| *j < code.len()).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+  Taken,
+  Fallthrough,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Edge {
+  pub kind: EdgeKind,
+  /// Index of the block the edge leads to, or None where the program ends.
+  pub to: Option<usize>,
+}
+
+/// A run of instructions that is only entered at its first instruction and
+/// only left after its last.
+#[derive(Debug, PartialEq)]
+pub struct Block {
+  /// Index of the first instruction.
+  pub start: usize,
+  /// Index after the last instruction.
+  pub end: usize,
+  /// Labels naming the first instruction.
+  pub labels: Vec<String>,
+  pub edges: Vec<Edge>,
+}
+
+/// Index of the instruction every label names, in source order.
+fn label_positions(assembly: &Assembly) -> Vec<(String, usize)> {
+  let mut labels = assembly
+    .targets
+    .iter()
+    .map(|(name, i)| (name.clone(), *i))
+    .collect::<Vec<_>>();
+  labels.sort_by_key(|(name, _)| {
+    let span = assembly.labels[name];
+    (assembly.sources.file_at(span.start), span.start)
+  });
+  labels
+}
+
+/// Splits a program into basic blocks, starting a block at every label and
+/// branch target and after every branch.
+pub fn blocks(assembly: &Assembly) -> Vec<Block> {
+  let code = &assembly.instructions;
+  let labels = label_positions(assembly);
+  let mut leaders = BTreeSet::new();
+  if !code.is_empty() {
+    leaders.insert(0);
+  }
+  leaders.extend(labels.iter().map(|(_, i)| *i));
+  for (i, instruction) in code.iter().enumerate() {
+    match instruction {
+      Instruction::GO(t)
+      | Instruction::BIN(t)
+      | Instruction::BIZ(t)
+      | Instruction::BIC(t)
+      | Instruction::BIV(t)
+      | Instruction::CALL(t) => {
+        leaders.insert(target(*t));
+        leaders.insert(i + 1);
+      }
+      // Interrupts can enter the program at the vector.
+      Instruction::SIV(t) => {
+        leaders.insert(target(*t));
+      }
+      Instruction::RET | Instruction::HALT(_) | Instruction::RTI => {
+        leaders.insert(i + 1);
+      }
+      _ => {}
+    }
+  }
+  let leaders = leaders
+    .into_iter()
+    .filter(|i| *i < code.len())
+    .collect::<Vec<_>>();
+  let block_of = |i: usize| {
+    if i < code.len() {
+      Some(leaders.iter().rposition(|l| *l <= i).unwrap())
+    } else {
+      None
+    }
+  };
+
+  let mut blocks = vec![];
+  for (b, start) in leaders.iter().enumerate() {
+    let end = leaders.get(b + 1).copied().unwrap_or(code.len());
+    let last = end - 1;
+    let fallthrough = Edge {
+      kind: EdgeKind::Fallthrough,
+      to: block_of(end),
+    };
+    let edges = match code[last] {
+      Instruction::GO(t) => vec![Edge {
+        kind: EdgeKind::Taken,
+        to: block_of(target(t)),
+      }],
+      // A call returns to the instruction after it.
+      Instruction::BIN(t)
+      | Instruction::BIZ(t)
+      | Instruction::BIC(t)
+      | Instruction::BIV(t)
+      | Instruction::CALL(t) => vec![
+        Edge {
+          kind: EdgeKind::Taken,
+          to: block_of(target(t)),
+        },
+        fallthrough,
+      ],
+      Instruction::RET | Instruction::HALT(_) | Instruction::RTI => vec![],
+      _ => vec![fallthrough],
+    };
+    blocks.push(Block {
+      start: *start,
+      end,
+      labels: labels
+        .iter()
+        .filter(|(_, i)| i == start)
+        .map(|(name, _)| name.clone())
+        .collect(),
+      edges,
+    });
+  }
+  blocks
+}
+
+fn escape(s: &str) -> String {
+  s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Renders the control-flow graph of a program in DOT, labelling every block
+/// with its instructions as written in the source.
+pub fn to_dot(name: &str, assembly: &Assembly) -> String {
+  let blocks = blocks(assembly);
+  let node = |to: Option<usize>| match to {
+    Some(b) => format!("b{}", b),
+    None => "end".to_owned(),
+  };
+  let mut out = String::new();
+  writeln!(out, "digraph \"{}\" {{", escape(name)).unwrap();
+  writeln!(out, "  node [shape=box, fontname=\"monospace\"];").unwrap();
+  writeln!(out, "  start [shape=oval];").unwrap();
+  writeln!(out, "  end [shape=oval];").unwrap();
+  writeln!(out, "  start -> {};", node(blocks.first().map(|_| 0))).unwrap();
+  for (b, block) in blocks.iter().enumerate() {
+    // `\l` ends a left-justified line.
+    let mut label = String::new();
+    for name in &block.labels {
+      label += &format!("{}:\\l", escape(name));
+    }
+    for span in &assembly.spans[block.start..block.end] {
+      label += &format!("  {}\\l", escape(assembly.sources.text(*span)));
+    }
+    writeln!(out, "  b{} [label=\"{}\"];", b, label).unwrap();
+    for edge in &block.edges {
+      let kind = match edge.kind {
+        EdgeKind::Taken => "taken",
+        EdgeKind::Fallthrough => "fallthrough",
+      };
+      writeln!(out, "  b{} -> {} [label=\"{}\"];", b, node(edge.to), kind).unwrap();
+    }
+  }
+  out.push_str("}\n");
+  out
+}
+
+#[test]
+fn test_blocks() {
+  let source = "A: 3;\nLBL loop;\nADD A, 7;\nSF A;\nBIZ done;\nGO loop;\nLBL done;\nPRINT;\n";
+  let assembly = Assembly::try_assemble(source).unwrap();
+  let blocks = blocks(&assembly);
+  let summary = blocks
+    .iter()
+    .map(|b| {
+      (
+        b.start,
+        b.end,
+        b.labels.clone(),
+        b.edges.iter().map(|e| (e.kind, e.to)).collect::<Vec<_>>(),
+      )
+    })
+    .collect::<Vec<_>>();
+  assert_eq!(
+    summary,
+    vec![
+      (
+        0,
+        3,
+        vec!["loop".to_owned()],
+        vec![(EdgeKind::Taken, Some(2)), (EdgeKind::Fallthrough, Some(1))]
+      ),
+      (3, 4, vec![], vec![(EdgeKind::Taken, Some(0))]),
+      (
+        4,
+        5,
+        vec!["done".to_owned()],
+        vec![(EdgeKind::Fallthrough, None)]
+      ),
+    ]
+  );
+  let dot = to_dot("loop.vmal", &assembly);
+  assert!(dot.contains("  b0 [label=\"loop:\\l  ADD A, 7;\\l  SF A;\\l  BIZ done;\\l\"];\n"));
+  assert!(dot.contains("  b1 -> b0 [label=\"taken\"];\n"));
+  assert!(dot.contains("  b2 -> end [label=\"fallthrough\"];\n"));
+}
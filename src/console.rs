@@ -0,0 +1,90 @@
+//! The console a program talks to through memory-mapped ports.
+//!
+//! Writing a word to the character port, the first word of the console,
+//! prints its low byte, and writing one to the number port after it prints it
+//! in decimal. Reading the character port takes the next byte of input, or -1
+//! once the input has run out.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::{
+  bus::Device,
+  vm::{get_int, word_mask},
+};
+
+pub struct Console {
+  /// Number of bits in a word, to print numbers with their sign.
+  width: u32,
+  input: Box<dyn Read + Send>,
+  /// Output not taken yet, or None to write output straight to stdout.
+  captured: Option<Vec<u8>>,
+}
+
+impl Console {
+  /// A console for words of `width` bits, reading stdin and writing to
+  /// stdout.
+  pub fn new(width: u32) -> Self {
+    Console {
+      width,
+      input: Box::new(io::stdin()),
+      captured: None,
+    }
+  }
+  pub fn set_input(&mut self, input: Box<dyn Read + Send>) {
+    self.input = input;
+  }
+  /// Keeps output to be taken with `take_output`, for debuggers that cannot
+  /// let the program write to the terminal.
+  pub fn capture(&mut self) {
+    self.captured.get_or_insert_with(Vec::new);
+  }
+  /// Output captured since it was last taken.
+  pub fn take_output(&mut self) -> String {
+    let captured = self.captured.as_mut().map(std::mem::take);
+    String::from_utf8_lossy(&captured.unwrap_or_default()).into_owned()
+  }
+  fn print(&mut self, bytes: &[u8]) {
+    match &mut self.captured {
+      Some(captured) => captured.extend_from_slice(bytes),
+      None => {
+        // Flushed straight away, so prompts show before the program reads.
+        let mut out = io::stdout();
+        out.write_all(bytes).unwrap();
+        out.flush().unwrap();
+      }
+    }
+  }
+}
+
+impl Device for Console {
+  fn read(&mut self, offset: isize) -> isize {
+    if offset != 0 {
+      return 0;
+    }
+    let mut byte = [0];
+    match self.input.read_exact(&mut byte) {
+      Ok(()) => byte[0] as isize,
+      Err(_) => word_mask(self.width),
+    }
+  }
+  fn write(&mut self, offset: isize, val: isize) {
+    if offset == 0 {
+      self.print(&[val as u8]);
+    } else {
+      let number = get_int(val, self.width).to_string();
+      self.print(number.as_bytes());
+    }
+  }
+  /// The program's initial memory cannot print anything.
+  fn load(&mut self, _offset: isize, _val: isize) {}
+}
+
+impl fmt::Debug for Console {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Console")
+      .field("width", &self.width)
+      .field("captured", &self.captured)
+      .finish_non_exhaustive()
+  }
+}
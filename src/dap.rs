@@ -0,0 +1,770 @@
+//! A Debug Adapter Protocol server, so `.vmal` files can be debugged from an
+//! editor. Messages are read from stdin and written to stdout.
+//!
+//! The program has a single thread, with a stack frame for the current
+//! instruction and for every `CALL` that has not returned. Registers, flags
+//! and memory are exposed as variable scopes.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, stdin, stdout, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+
+use serde_json::{json, Value};
+
+use crate::{
+  assembler::{Assembly, Instruction, Settings},
+  bus::Bus,
+  console::Console,
+  source::SourceMap,
+  util::{read_message, write_message, SHOULD_SHOW_BINARY, SHOULD_USE_UNSIGNED_INT},
+  vm::{format_binary, get_int, Fault, VM},
+};
+
+const THREAD_ID: i64 = 1;
+const REGISTERS_REF: i64 = 1;
+const FLAGS_REF: i64 = 2;
+const MEMORY_REF: i64 = 3;
+
+/// Number of instructions run between checks for a pause request while
+/// continuing.
+const POLL_INTERVAL: usize = 10000;
+
+fn format_value(val: isize, width: u32) -> String {
+  if *SHOULD_SHOW_BINARY.read().unwrap() {
+    format_binary(val, width)
+  } else {
+    format!("{}", get_int(val, width))
+  }
+}
+
+fn variable(name: &str, value: String) -> Value {
+  json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+/// A path that compares equal however the file was named.
+fn canonical(path: &Path) -> PathBuf {
+  path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
+
+/// Why the program stopped.
+enum Stop {
+  Entry,
+  Step,
+  Breakpoint,
+  Pause,
+  Fault(Fault),
+  Exited,
+}
+
+struct Program {
+  path: String,
+  assembly: Assembly,
+  vm: VM,
+}
+
+struct Session<W: Write> {
+  output: W,
+  seq: i64,
+  program: Option<Program>,
+  /// Requested breakpoint lines in every source, kept until the program is
+  /// launched. Breakpoints without a source path are in the main file.
+  breakpoint_lines: HashMap<String, Vec<i64>>,
+  breakpoints: HashSet<isize>,
+  stop_on_entry: bool,
+  /// Requests received while the program was running.
+  pending: VecDeque<Value>,
+}
+
+/// Runs the adapter on stdin and stdout until the client disconnects.
+pub fn serve() -> io::Result<()> {
+  let (sender, receiver) = channel();
+  std::thread::spawn(move || {
+    let stdin = stdin();
+    let mut input = stdin.lock();
+    while let Ok(Some(message)) = read_message(&mut input) {
+      if sender.send(message).is_err() {
+        break;
+      }
+    }
+  });
+  let stdout = stdout();
+  let mut session = Session::new(stdout.lock());
+  session.run(&receiver)
+}
+
+impl<W: Write> Session<W> {
+  fn new(output: W) -> Self {
+    Session {
+      output,
+      seq: 0,
+      program: None,
+      breakpoint_lines: HashMap::new(),
+      breakpoints: HashSet::new(),
+      stop_on_entry: false,
+      pending: VecDeque::new(),
+    }
+  }
+
+  fn run(&mut self, receiver: &Receiver<Value>) -> io::Result<()> {
+    loop {
+      let message = match self.pending.pop_front() {
+        Some(message) => message,
+        None => match receiver.recv() {
+          Ok(message) => message,
+          Err(_) => return Ok(()),
+        },
+      };
+      if message["type"] != "request" {
+        continue;
+      }
+      if !self.handle(&message, receiver)? {
+        return Ok(());
+      }
+    }
+  }
+
+  fn send(&mut self, mut message: Value) -> io::Result<()> {
+    self.seq += 1;
+    message["seq"] = json!(self.seq);
+    write_message(&mut self.output, &message)
+  }
+
+  fn respond(&mut self, request: &Value, body: Value) -> io::Result<()> {
+    self.send(json!({
+      "type": "response",
+      "request_seq": request["seq"],
+      "success": true,
+      "command": request["command"],
+      "body": body,
+    }))
+  }
+
+  fn respond_error(&mut self, request: &Value, message: String) -> io::Result<()> {
+    self.send(json!({
+      "type": "response",
+      "request_seq": request["seq"],
+      "success": false,
+      "command": request["command"],
+      "message": message,
+    }))
+  }
+
+  fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+    self.send(json!({ "type": "event", "event": event, "body": body }))
+  }
+
+  /// Handles a request, returning false once the session should end.
+  fn handle(&mut self, request: &Value, receiver: &Receiver<Value>) -> io::Result<bool> {
+    let args = &request["arguments"];
+    match request["command"].as_str().unwrap_or("") {
+      "initialize" => {
+        self.respond(
+          request,
+          json!({
+            "supportsConfigurationDoneRequest": true,
+            "supportsSingleThreadExecutionRequests": false,
+          }),
+        )?;
+        self.event("initialized", json!({}))?;
+      }
+      "launch" => self.launch(request)?,
+      "setBreakpoints" => {
+        let path = args["source"]["path"].as_str().unwrap_or("").to_owned();
+        let lines = args["breakpoints"]
+          .as_array()
+          .map(|b| b.iter().filter_map(|b| b["line"].as_i64()).collect())
+          .unwrap_or_default();
+        self.breakpoint_lines.insert(path.clone(), lines);
+        let breakpoints = self.resolve_breakpoints(&path);
+        self.respond(request, json!({ "breakpoints": breakpoints }))?;
+      }
+      "setExceptionBreakpoints" => self.respond(request, json!({ "breakpoints": [] }))?,
+      "configurationDone" => {
+        self.respond(request, json!({}))?;
+        if self.program.is_some() {
+          let pc = self.program.as_ref().unwrap().vm.registers[0];
+          if self.stop_on_entry {
+            self.stopped(Stop::Entry)?;
+          } else if self.breakpoints.contains(&pc) {
+            self.stopped(Stop::Breakpoint)?;
+          } else {
+            let stop = self.resume(receiver);
+            self.stopped(stop)?;
+          }
+        }
+      }
+      "threads" => self.respond(
+        request,
+        json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }),
+      )?,
+      "stackTrace" => {
+        let frames = self.stack_frames();
+        let total = frames.len();
+        self.respond(
+          request,
+          json!({ "stackFrames": frames, "totalFrames": total }),
+        )?;
+      }
+      "scopes" => self.respond(
+        request,
+        json!({ "scopes": [
+          { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+          { "name": "Flags", "variablesReference": FLAGS_REF, "expensive": false },
+          { "name": "Memory", "variablesReference": MEMORY_REF, "expensive": false },
+        ] }),
+      )?,
+      "variables" => {
+        let variables = self.variables(args["variablesReference"].as_i64().unwrap_or(0));
+        self.respond(request, json!({ "variables": variables }))?;
+      }
+      "stepIn" => {
+        self.respond(request, json!({}))?;
+        let stop = self.step();
+        self.stopped(stop)?;
+      }
+      // Stepping over a `CALL` runs until the frame it makes returns, and
+      // stepping out until the current frame does.
+      "next" | "stepOut" => {
+        self.respond(request, json!({}))?;
+        let depth = self.program.as_ref().map_or(0, |p| p.vm.depth());
+        let stop = if request["command"] == "next" {
+          self.run_until(receiver, |vm| vm.depth() <= depth)
+        } else {
+          self.run_until(receiver, |vm| vm.depth() < depth)
+        };
+        self.stopped(stop)?;
+      }
+      "continue" => {
+        self.respond(request, json!({ "allThreadsContinued": true }))?;
+        let stop = self.resume(receiver);
+        self.stopped(stop)?;
+      }
+      "pause" => self.respond(request, json!({}))?,
+      "disconnect" | "terminate" => {
+        self.respond(request, json!({}))?;
+        return Ok(false);
+      }
+      command => self.respond_error(request, format!("Unsupported request '{}'", command))?,
+    }
+    Ok(true)
+  }
+
+  fn launch(&mut self, request: &Value) -> io::Result<()> {
+    let args = &request["arguments"];
+    let path = match args["program"].as_str() {
+      Some(path) => path.to_owned(),
+      None => return self.respond_error(request, "No program given".to_owned()),
+    };
+    let file = match std::fs::read_to_string(&path) {
+      Ok(file) => file,
+      Err(e) => return self.respond_error(request, format!("Cannot read {}: {}", path, e)),
+    };
+    let mut sources = SourceMap::from_path(&path, file);
+    sources.include_dirs = args["includeDirs"]
+      .as_array()
+      .map(|d| {
+        d.iter()
+          .filter_map(|d| d.as_str())
+          .map(PathBuf::from)
+          .collect()
+      })
+      .unwrap_or_default();
+    let settings = Settings {
+      strict: args["strict"].as_bool().unwrap_or(false),
+      width: args["width"].as_u64().map(|w| w as u32),
+    };
+    let assembly = match Assembly::try_assemble_with(sources, settings) {
+      Ok(assembly) if !assembly.unlinked().is_empty() => {
+        let message = assembly
+          .unlinked()
+          .iter()
+          .map(|e| e.to_string())
+          .collect::<Vec<_>>()
+          .join("\n");
+        return self.respond_error(request, message);
+      }
+      Ok(assembly) => assembly,
+      Err(errors) => {
+        let message = errors
+          .iter()
+          .map(|e| e.to_string())
+          .collect::<Vec<_>>()
+          .join("\n");
+        return self.respond_error(request, message);
+      }
+    };
+    if let Some(unsigned) = args["unsigned"].as_bool() {
+      *SHOULD_USE_UNSIGNED_INT.write().unwrap() = unsigned;
+    }
+    if let Some(binary) = args["binary"].as_bool() {
+      *SHOULD_SHOW_BINARY.write().unwrap() = binary;
+    }
+    self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+    if args["noDebug"].as_bool().unwrap_or(false) {
+      self.stop_on_entry = false;
+      self.breakpoint_lines.clear();
+    }
+    let bus = match args["machine"].as_str() {
+      Some(machine) => match Bus::from_file(Path::new(machine), assembly.width) {
+        Ok(bus) => bus,
+        Err(err) => return self.respond_error(request, err),
+      },
+      None => {
+        let mut bus = Bus::new();
+        if args["devices"].as_bool().unwrap_or(false) {
+          bus.map_devices(assembly.width);
+        }
+        bus
+      }
+    };
+    let vm = VM::with_bus(
+      assembly.width,
+      bus,
+      assembly.reg_inits.clone(),
+      assembly.mem_inits.clone(),
+    );
+    let mut vm = match vm {
+      Ok(vm) => vm,
+      Err(err) => return self.respond_error(request, err),
+    };
+    vm.start_stack(&assembly.instructions);
+    if let Some(unwritten) = args["unwritten"].as_str() {
+      match unwritten.parse() {
+        Ok(unwritten) => vm.unwritten_reads = unwritten,
+        Err(err) => return self.respond_error(request, err),
+      }
+    }
+    // Stdin and stdout carry the protocol, so the program only gets input
+    // from a file, and its output is sent as output events.
+    let input: Box<dyn Read + Send> = match args["input"].as_str() {
+      Some(input) => match File::open(input) {
+        Ok(file) => Box::new(BufReader::new(file)),
+        Err(err) => {
+          return self.respond_error(request, format!("Cannot read {}: {}", input, err));
+        }
+      },
+      None => Box::new(io::empty()),
+    };
+    if let Some(console) = vm.memory.device_mut::<Console>() {
+      console.set_input(input);
+      console.capture();
+    }
+    vm.printed = Some(String::new());
+    self.program = Some(Program { path, assembly, vm });
+    self.resolve_breakpoints("");
+    self.respond(request, json!({}))
+  }
+
+  /// Maps the requested breakpoint lines onto the first instruction at or after
+  /// each line in the same file, returning the breakpoints of the source at
+  /// `path`.
+  fn resolve_breakpoints(&mut self, path: &str) -> Vec<Value> {
+    self.breakpoints.clear();
+    let program = match &self.program {
+      Some(program) => program,
+      None => {
+        return self
+          .breakpoint_lines
+          .get(path)
+          .into_iter()
+          .flatten()
+          .map(|line| json!({ "verified": false, "line": line }))
+          .collect();
+      }
+    };
+    let assembly = &program.assembly;
+    let mut result = vec![];
+    for (source, lines) in &self.breakpoint_lines {
+      let file = if source.is_empty() {
+        Some(0)
+      } else {
+        let source = canonical(Path::new(source));
+        assembly
+          .sources
+          .files
+          .iter()
+          .position(|f| f.path.as_deref().map(canonical) == Some(source.clone()))
+      };
+      for line in lines {
+        let target = file.and_then(|file| {
+          (0..assembly.lines.len())
+            .find(|i| assembly.files[*i] == file && assembly.lines[*i] as i64 + 1 >= *line)
+        });
+        let breakpoint = match target {
+          Some(i) => {
+            self.breakpoints.insert(i as isize);
+            json!({
+              "id": i,
+              "verified": true,
+              "line": assembly.lines[i] + 1,
+            })
+          }
+          None => json!({
+            "verified": false,
+            "line": line,
+            "message": "No instruction at or after this line",
+          }),
+        };
+        if source == path {
+          result.push(breakpoint);
+        }
+      }
+    }
+    result
+  }
+
+  fn stack_frames(&self) -> Vec<Value> {
+    let program = match &self.program {
+      Some(program) => program,
+      None => return vec![],
+    };
+    let assembly = &program.assembly;
+    let backtrace = program.vm.backtrace();
+    let mut frames = vec![];
+    for (id, pc) in backtrace.iter().enumerate() {
+      let line = match assembly.lines.get(*pc as usize) {
+        Some(line) => line + 1,
+        None => break,
+      };
+      let file = &assembly.sources.files[assembly.files[*pc as usize]];
+      let path = match &file.path {
+        Some(path) => path.display().to_string(),
+        None => program.path.clone(),
+      };
+      let file_name = Path::new(&path)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+      // A frame is named after the label its caller called, and the outermost
+      // one after the file.
+      let called = backtrace.get(id + 1).and_then(|call| {
+        let target = match assembly.instructions[*call as usize] {
+          Instruction::CALL(t) => (t + 1) as usize,
+          _ => return None,
+        };
+        assembly
+          .targets
+          .iter()
+          .filter(|(_, i)| **i == target)
+          .map(|(name, _)| name.clone())
+          .min()
+      });
+      let name = called.unwrap_or_else(|| file_name.clone());
+      frames.push(json!({
+        "id": id,
+        "name": format!("{} ({})", name, pc),
+        "source": { "name": file_name, "path": path },
+        "line": line,
+        "column": 1,
+      }));
+    }
+    frames
+  }
+
+  fn variables(&self, reference: i64) -> Vec<Value> {
+    let vm = match &self.program {
+      Some(program) => &program.vm,
+      None => return vec![],
+    };
+    match reference {
+      REGISTERS_REF => {
+        let mut variables = (0..16)
+          .map(|i| variable(&format!("{:X}", i), format_value(vm.registers[i], vm.width)))
+          .collect::<Vec<_>>();
+        variables.push(variable("MAR", format!("{}", vm.MAR)));
+        variables.push(variable("MBR", format_value(vm.MBR, vm.width)));
+        variables
+      }
+      FLAGS_REF => vec![
+        variable("N", format!("{}", vm.N)),
+        variable("Z", format!("{}", vm.Z)),
+        variable("C", format!("{}", vm.C)),
+        variable("V", format!("{}", vm.V)),
+        variable("I", format!("{}", vm.I)),
+      ],
+      MEMORY_REF => vm
+        .memory
+        .cells()
+        .into_iter()
+        .map(|(loc, val)| variable(&format!("[{}]", loc), format_value(val, vm.width)))
+        .collect(),
+      _ => vec![],
+    }
+  }
+
+  fn finished(&self) -> bool {
+    match &self.program {
+      Some(program) => program.vm.stopped(&program.assembly.instructions),
+      None => true,
+    }
+  }
+
+  /// Why the program stopped once it cannot run any further.
+  fn end(&self) -> Stop {
+    match self.program.as_ref().and_then(|p| p.vm.fault) {
+      Some(fault) => Stop::Fault(fault),
+      None => Stop::Exited,
+    }
+  }
+
+  /// Runs one instruction. Output of `PRINT` and the console is sent as an
+  /// output event instead of being written over the protocol stream.
+  fn run_one(&mut self) -> io::Result<()> {
+    let program = self.program.as_mut().unwrap();
+    let vm = &mut program.vm;
+    let warned = vm.warnings.len();
+    vm.step(&program.assembly.instructions);
+    let warnings = vm.warnings[warned..]
+      .iter()
+      .map(|(pc, warning)| format!("Warning: {} at instruction {}\n", warning, pc))
+      .collect::<String>();
+    let printed = vm.printed.replace(String::new()).unwrap_or_default();
+    let output = vm
+      .memory
+      .device_mut::<Console>()
+      .map(|console| console.take_output())
+      .unwrap_or_default();
+    if !warnings.is_empty() {
+      self.event(
+        "output",
+        json!({ "category": "console", "output": warnings }),
+      )?;
+    }
+    for output in [printed, output] {
+      if !output.is_empty() {
+        self.event("output", json!({ "category": "stdout", "output": output }))?;
+      }
+    }
+    Ok(())
+  }
+
+  fn step(&mut self) -> Stop {
+    if !self.finished() {
+      self.run_one().unwrap();
+    }
+    if self.finished() {
+      self.end()
+    } else {
+      Stop::Step
+    }
+  }
+
+  /// Runs until a breakpoint, the end of the program or a pause request. The
+  /// instruction at PC always runs, even if it has a breakpoint.
+  fn resume(&mut self, receiver: &Receiver<Value>) -> Stop {
+    self.run_until(receiver, |_| false)
+  }
+
+  /// Runs like `resume`, but also stops as a step once `done` is true of the
+  /// machine.
+  fn run_until(&mut self, receiver: &Receiver<Value>, done: impl Fn(&VM) -> bool) -> Stop {
+    if !self.finished() {
+      self.run_one().unwrap();
+    }
+    let mut count = 0;
+    while !self.finished() {
+      let vm = &self.program.as_ref().unwrap().vm;
+      if done(vm) {
+        return Stop::Step;
+      }
+      if self.breakpoints.contains(&vm.registers[0]) {
+        return Stop::Breakpoint;
+      }
+      self.run_one().unwrap();
+      count += 1;
+      if count % POLL_INTERVAL == 0 {
+        while let Ok(message) = receiver.try_recv() {
+          if message["command"] == "pause" {
+            self.respond(&message, json!({})).unwrap();
+            return Stop::Pause;
+          }
+          self.pending.push_back(message);
+        }
+      }
+    }
+    self.end()
+  }
+
+  fn stopped(&mut self, stop: Stop) -> io::Result<()> {
+    let reason = match stop {
+      Stop::Entry => "entry",
+      Stop::Step => "step",
+      Stop::Breakpoint => "breakpoint",
+      Stop::Pause => "pause",
+      Stop::Fault(fault) => {
+        let description = fault.to_string();
+        return self.event(
+          "stopped",
+          json!({
+            "reason": "exception",
+            "description": description,
+            "text": description,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+          }),
+        );
+      }
+      Stop::Exited => {
+        let status = self.program.as_ref().map_or(0, |p| p.vm.exit_status());
+        self.event("exited", json!({ "exitCode": status }))?;
+        return self.event("terminated", json!({}));
+      }
+    };
+    self.event(
+      "stopped",
+      json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+    )
+  }
+}
+
+#[test]
+fn test_dap_session() {
+  // Runs a session debugging `source`, returning every message sent.
+  fn session(source: &str, stop_on_entry: bool, after: Vec<Value>) -> Vec<Value> {
+    let path = std::env::temp_dir().join(format!("vmal_dap_{}.vmal", std::process::id()));
+    std::fs::write(&path, source).unwrap();
+    let (sender, receiver) = channel();
+    let launch = json!({ "program": path.to_str().unwrap(), "stopOnEntry": stop_on_entry });
+    let requests = vec![
+      json!({ "command": "initialize", "arguments": {} }),
+      json!({ "command": "launch", "arguments": launch }),
+    ];
+    for (seq, mut request) in requests.into_iter().chain(after).enumerate() {
+      request["seq"] = json!(seq + 1);
+      request["type"] = json!("request");
+      sender.send(request).unwrap();
+    }
+    let mut output = vec![];
+    Session::new(&mut output).run(&receiver).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let mut input = io::Cursor::new(output);
+    let mut messages = vec![];
+    while let Some(message) = read_message(&mut input).unwrap() {
+      messages.push(message);
+    }
+    messages
+  }
+  let events = |messages: &[Value]| {
+    messages
+      .iter()
+      .filter(|m| m["type"] == "event")
+      .map(|m| {
+        format!(
+          "{}{}",
+          m["event"].as_str().unwrap(),
+          m["body"]["reason"].as_str().unwrap_or("")
+        )
+      })
+      .collect::<Vec<_>>()
+  };
+
+  let messages = session(
+    "A: 5;\n\nADD E, A;\nPRINT;\n# comment\nADD E, A;\nADD E, A;\n",
+    true,
+    vec![
+      json!({ "command": "setBreakpoints", "arguments": { "breakpoints": [{ "line": 5 }] } }),
+      json!({ "command": "configurationDone" }),
+      json!({ "command": "next" }),
+      json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+      json!({ "command": "continue" }),
+      json!({ "command": "variables", "arguments": { "variablesReference": REGISTERS_REF } }),
+      json!({ "command": "continue" }),
+      json!({ "command": "disconnect" }),
+    ],
+  );
+  let find = |command: &str| {
+    messages
+      .iter()
+      .find(|m| m["command"] == command)
+      .unwrap()
+      .clone()
+  };
+  assert_eq!(
+    events(&messages),
+    vec![
+      "initialized",
+      "stoppedentry",
+      "stoppedstep",
+      "output",
+      "stoppedbreakpoint",
+      "exited",
+      "terminated"
+    ]
+  );
+  assert!(find("launch")["success"].as_bool().unwrap());
+  assert_eq!(find("setBreakpoints")["body"]["breakpoints"][0]["line"], 6);
+  assert_eq!(find("stackTrace")["body"]["stackFrames"][0]["line"], 4);
+  assert_eq!(
+    find("variables")["body"]["variables"][0xE]["value"],
+    json!("5")
+  );
+
+  // Stepping over and out of calls, and PRINT run like any instruction.
+  let stack_trace = json!({ "command": "stackTrace", "arguments": { "threadId": 1 } });
+  let messages = session(
+    "A: 1;\nCALL f;\nCALL f;\nPRINT;\nGO end;\nLBL f;\nADD B, A;\nADD B, A;\nRET;\nLBL end;\n",
+    true,
+    vec![
+      json!({ "command": "configurationDone" }),
+      json!({ "command": "next" }),
+      stack_trace.clone(),
+      json!({ "command": "stepIn" }),
+      stack_trace.clone(),
+      json!({ "command": "stepIn" }),
+      json!({ "command": "stepOut" }),
+      stack_trace,
+      json!({ "command": "variables", "arguments": { "variablesReference": REGISTERS_REF } }),
+      json!({ "command": "next" }),
+      json!({ "command": "continue" }),
+      json!({ "command": "disconnect" }),
+    ],
+  );
+  assert_eq!(
+    events(&messages),
+    vec![
+      "initialized",
+      "stoppedentry",
+      "stoppedstep",
+      "stoppedstep",
+      "stoppedstep",
+      "stoppedstep",
+      "output",
+      "stoppedstep",
+      "exited",
+      "terminated"
+    ]
+  );
+  let lines = messages
+    .iter()
+    .filter(|m| m["command"] == "stackTrace")
+    .map(|m| m["body"]["stackFrames"][0]["line"].clone())
+    .collect::<Vec<_>>();
+  assert_eq!(lines, vec![json!(3), json!(7), json!(4)]);
+  let variables = messages
+    .iter()
+    .find(|m| m["command"] == "variables")
+    .unwrap();
+  assert_eq!(variables["body"]["variables"][0xB]["value"], json!("4"));
+  let output = messages.iter().find(|m| m["event"] == "output").unwrap();
+  assert!(output["body"]["output"]
+    .as_str()
+    .unwrap()
+    .contains("Registers"));
+
+  // Without stopOnEntry, the program runs as soon as it is configured.
+  let messages = session(
+    "A: 1;\nADD B, A;\n",
+    false,
+    vec![
+      json!({ "command": "configurationDone" }),
+      json!({ "command": "disconnect" }),
+    ],
+  );
+  assert_eq!(
+    events(&messages),
+    vec!["initialized", "exited", "terminated"]
+  );
+}
@@ -0,0 +1,218 @@
+//! Rewrites VMAL source in a canonical layout, keeping every comment.
+//!
+//! Mnemonics and registers are uppercased, operands are separated by `, `,
+//! instructions are indented while labels and initializers are not, trailing
+//! comments are aligned across consecutive statements, and runs of blank lines
+//! are collapsed into one.
+
+use crate::{
+  assembler::{Assembly, Diagnostic, LABEL_OPS, OP_MAP},
+  lexer::LineIndex,
+  parser::{parse, Operand, OperandKind, Statement, StatementKind},
+  source::SourceMap,
+};
+
+/// Indentation of instructions.
+const INDENT: usize = 2;
+
+enum Line<'a> {
+  Blank,
+  /// A line holding only a comment.
+  Comment {
+    indent: usize,
+    text: &'a str,
+  },
+  Statement {
+    code: String,
+    dedent: bool,
+    comment: Option<&'a str>,
+  },
+}
+
+/// Normalizes a statement, returning it along with whether it belongs at the
+/// start of the line. Names in `params`, the parameters of the macro being
+/// defined, are kept as written.
+fn format_statement(statement: &Statement, params: &[String]) -> (String, bool) {
+  let join = |args: &[Operand], verbatim: bool| {
+    args
+      .iter()
+      .map(|arg| match arg.kind {
+        OperandKind::Name if !verbatim && !params.contains(&arg.text) => arg.text.to_uppercase(),
+        _ => arg.text.clone(),
+      })
+      .collect::<Vec<_>>()
+      .join(", ")
+  };
+  match &statement.kind {
+    StatementKind::RegisterInit { register, value } => (
+      format!("{}: {};", register.text.to_uppercase(), value.text),
+      true,
+    ),
+    StatementKind::MemoryInit { address, value } => {
+      (format!("[{}]: {};", address.text, value.text), true)
+    }
+    StatementKind::Instruction { op, args } => {
+      let op = op.text.to_uppercase();
+      // Arguments to macros may be labels, so they are kept as written too.
+      let verbatim = OP_MAP
+        .get(op.as_str())
+        .is_none_or(|op| LABEL_OPS.contains(op));
+      let code = if args.is_empty() {
+        format!("{};", op)
+      } else if op == "LI" && args.len() == 2 {
+        // The value may be a data label.
+        format!("{} {}, {};", op, join(&args[..1], false), args[1].text)
+      } else {
+        format!("{} {};", op, join(args, verbatim))
+      };
+      (code, op == "LBL")
+    }
+    StatementKind::Directive { label, name, args } => {
+      let name = match label {
+        Some(label) => format!("{}: {}", label.text, name.text.to_lowercase()),
+        None => name.text.to_lowercase(),
+      };
+      if args.is_empty() {
+        (name, true)
+      } else if name == ".macro" {
+        let macro_name = args[0].text.to_uppercase();
+        match join(&args[1..], true) {
+          p if p.is_empty() => (format!("{} {}", name, macro_name), true),
+          p => (format!("{} {} {}", name, macro_name, p), true),
+        }
+      } else {
+        (format!("{} {}", name, join(args, true)), true)
+      }
+    }
+  }
+}
+
+/// Formats the main file of a program, failing with its errors if it does not
+/// assemble. Included files are left as they are.
+pub fn format<S: Into<SourceMap>>(source: S) -> Result<String, Vec<Diagnostic>> {
+  let assembly = Assembly::try_assemble(source)?;
+  let source = assembly.sources.main();
+  let program = parse(source);
+  let index = LineIndex::new(source);
+  let mut lines = source.split('\n').map(|_| Line::Blank).collect::<Vec<_>>();
+  let mut params = vec![];
+  for statement in &program.statements {
+    if let StatementKind::Directive { name, args, .. } = &statement.kind {
+      match name.text.to_lowercase().as_str() {
+        ".macro" => params = args.iter().skip(1).map(|a| a.text.clone()).collect(),
+        ".endm" => params.clear(),
+        _ => {}
+      }
+    }
+    let (line, _) = index.position(statement.span.start);
+    let (code, dedent) = format_statement(statement, &params);
+    lines[line] = Line::Statement {
+      code,
+      dedent,
+      comment: None,
+    };
+  }
+  for span in &program.comments {
+    let (line, indent) = index.position(span.start);
+    let text = source[span.start..span.end].trim_end();
+    match &mut lines[line] {
+      Line::Statement { comment, .. } => *comment = Some(text),
+      line => *line = Line::Comment { indent, text },
+    }
+  }
+  let width = |line: &Line| match line {
+    Line::Statement { code, dedent, .. } => code.len() + if *dedent { 0 } else { INDENT },
+    _ => 0,
+  };
+
+  // Trailing comments line up across statements that are not separated by a
+  // blank line or a comment at the start of a line.
+  let mut columns = vec![0; lines.len()];
+  let mut run: Vec<usize> = vec![];
+  for i in 0..=lines.len() {
+    let ends_run = match lines.get(i) {
+      Some(Line::Statement { .. }) => {
+        run.push(i);
+        false
+      }
+      Some(Line::Comment { indent, .. }) => *indent == 0,
+      _ => true,
+    };
+    if ends_run && !run.is_empty() {
+      let column = run.iter().map(|i| width(&lines[*i])).max().unwrap() + 1;
+      for i in run.drain(..) {
+        columns[i] = column;
+      }
+    }
+  }
+
+  let mut out = String::new();
+  let mut blank = false;
+  // Column of the last trailing comment, which indented comment lines after it
+  // continue.
+  let mut continued: Option<usize> = None;
+  for (i, line) in lines.iter().enumerate() {
+    if let Line::Blank = line {
+      blank = true;
+      continue;
+    }
+    if blank && !out.is_empty() {
+      out.push('\n');
+    }
+    blank = false;
+    match line {
+      Line::Blank => unreachable!(),
+      Line::Comment { indent, text } => {
+        let indent = match continued {
+          Some(column) if *indent > 0 => column,
+          _ => {
+            continued = None;
+            *indent
+          }
+        };
+        out += &format!("{:indent$}{}\n", "", text, indent = indent);
+      }
+      Line::Statement {
+        code,
+        dedent,
+        comment,
+      } => {
+        let indent = if *dedent { 0 } else { INDENT };
+        let statement = format!("{:indent$}{}", "", code, indent = indent);
+        match comment {
+          Some(comment) => {
+            out += &format!("{:column$}{}\n", statement, comment, column = columns[i]);
+            continued = Some(columns[i]);
+          }
+          None => {
+            out += &statement;
+            out.push('\n');
+            continued = None;
+          }
+        }
+      }
+    }
+  }
+  Ok(out)
+}
+
+#[test]
+fn test_format() {
+  let source = "\n\n# Header\nA:0x1D;   # init\n\n\n\nLBL  loop;\nadd e ,a;# add\n   rd;    # read\n            # more\nSF e;\nbiz   loop ;\n\n";
+  assert_eq!(
+    format(source).unwrap(),
+    "# Header\nA: 0x1D; # init\n\nLBL loop;\n  ADD E, A; # add\n  RD;       # read\n            # more\n  SF E;\n  BIZ loop;\n"
+  );
+  assert!(format("ADD E;").is_err());
+}
+
+#[test]
+fn test_format_idempotent() {
+  let once = format(include_str!("../example.vmal")).unwrap();
+  assert_eq!(format(once.as_str()).unwrap(), once);
+  let a = Assembly::try_assemble(include_str!("../example.vmal")).unwrap();
+  let b = Assembly::try_assemble(once).unwrap();
+  assert_eq!(a.instructions, b.instructions);
+  assert_eq!(a.reg_inits, b.reg_inits);
+  assert_eq!(a.mem_inits, b.mem_inits);
+}
@@ -0,0 +1,533 @@
+//! A GDB remote serial protocol stub, letting GDB (or any other RSP client)
+//! control a program running on the VM.
+//!
+//! The target has 19 registers as wide as a word: `pc` (register 0), `r1` to
+//! `rf`, `mar`, `mbr` and `flags` (Z in bit 0, N in bit 1, C in bit 2, V in
+//! bit 3, I in bit 4). Code addresses are instruction indexes, so breakpoints
+//! are set on the value PC has before the instruction runs. Data memory is
+//! exposed as little-endian words, byte address `n` times the bytes in a word
+//! being memory location `n`.
+
+use std::collections::HashSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+use crate::{
+  assembler::Instruction,
+  vm::{word_mask, Fault, VM},
+};
+
+const REGISTER_COUNT: usize = 19;
+const MAR: usize = 16;
+const MBR: usize = 17;
+const FLAGS: usize = 18;
+
+/// Most bytes of a packet the client may send, or that a reply holds.
+const PACKET_SIZE: isize = 0x4000;
+
+/// Number of instructions run between checks for an interrupt from the client
+/// while continuing.
+const POLL_INTERVAL: usize = 10000;
+
+fn target_xml(width: u32) -> String {
+  let mut xml = format!(
+    "<?xml version=\"1.0\"?>\n\
+     <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+     <target version=\"1.0\">\n\
+     <feature name=\"org.vmal.core\">\n\
+     <flags id=\"vmal_flags\" size=\"{}\">\n\
+     <field name=\"Z\" start=\"0\" end=\"0\"/>\n\
+     <field name=\"N\" start=\"1\" end=\"1\"/>\n\
+     <field name=\"C\" start=\"2\" end=\"2\"/>\n\
+     <field name=\"V\" start=\"3\" end=\"3\"/>\n\
+     <field name=\"I\" start=\"4\" end=\"4\"/>\n\
+     </flags>\n\
+     <reg name=\"pc\" bitsize=\"{}\" type=\"code_ptr\" regnum=\"0\"/>\n",
+    width / 8,
+    width
+  );
+  for i in 1..16 {
+    xml += &format!(
+      "<reg name=\"r{:x}\" bitsize=\"{w}\" type=\"uint{w}\"/>\n",
+      i,
+      w = width
+    );
+  }
+  xml += &format!(
+    "<reg name=\"mar\" bitsize=\"{w}\" type=\"uint{w}\"/>\n\
+     <reg name=\"mbr\" bitsize=\"{w}\" type=\"uint{w}\"/>\n\
+     <reg name=\"flags\" bitsize=\"{w}\" type=\"vmal_flags\"/>\n\
+     </feature>\n\
+     </target>\n",
+    w = width
+  );
+  xml
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+  if !s.len().is_multiple_of(2) {
+    return None;
+  }
+  (0..s.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+    .collect()
+}
+
+fn parse_hex(s: &str) -> Option<isize> {
+  isize::from_str_radix(s, 16).ok()
+}
+
+/// Parses the `addr,length` argument of memory and breakpoint packets.
+fn parse_range(s: &str) -> Option<(isize, isize)> {
+  let (addr, len) = s.split_once(',')?;
+  Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+fn word_to_hex(val: isize, bytes: usize) -> String {
+  to_hex(&(val as u64).to_le_bytes()[..bytes])
+}
+
+fn word_from_hex(s: &str, bytes: usize) -> Option<isize> {
+  let data = from_hex(s)?;
+  if data.len() != bytes {
+    return None;
+  }
+  let mut word = [0; 8];
+  word[..bytes].copy_from_slice(&data);
+  Some(u64::from_le_bytes(word) as isize)
+}
+
+/// Why the target stopped running.
+enum Stop {
+  /// Stopped after a step or an interrupt.
+  Trap,
+  /// Stopped on a breakpoint.
+  Breakpoint,
+  /// The program has finished.
+  Exited,
+  /// The program faulted, and cannot go on.
+  Fault,
+}
+
+struct Server<'a> {
+  stream: TcpStream,
+  vm: &'a mut VM,
+  code: &'a [Instruction],
+  breakpoints: HashSet<isize>,
+  ack: bool,
+}
+
+/// Serves the remote protocol on `stream` until the client detaches, kills the
+/// program or disconnects.
+pub fn serve(stream: TcpStream, vm: &mut VM, code: &[Instruction]) -> io::Result<()> {
+  stream.set_nodelay(true)?;
+  let mut server = Server {
+    stream,
+    vm,
+    code,
+    breakpoints: HashSet::new(),
+    ack: true,
+  };
+  while let Some(packet) = server.read_packet()? {
+    let reply = match server.handle(&packet) {
+      Some(reply) => reply,
+      None => {
+        server.write_packet("OK")?;
+        break;
+      }
+    };
+    server.write_packet(&reply)?;
+  }
+  Ok(())
+}
+
+impl<'a> Server<'a> {
+  /// Reads the next packet, returning `None` once the client disconnects.
+  /// Acknowledgements and interrupts outside of a continue are ignored.
+  fn read_packet(&mut self) -> io::Result<Option<String>> {
+    let mut byte = [0];
+    loop {
+      if self.stream.read(&mut byte)? == 0 {
+        return Ok(None);
+      }
+      if byte[0] == b'$' {
+        break;
+      }
+    }
+    let mut data = vec![];
+    loop {
+      if self.stream.read(&mut byte)? == 0 {
+        return Ok(None);
+      }
+      if byte[0] == b'#' {
+        break;
+      }
+      data.push(byte[0]);
+    }
+    let mut checksum = [0; 2];
+    self.stream.read_exact(&mut checksum)?;
+    let expected = std::str::from_utf8(&checksum)
+      .ok()
+      .and_then(|c| u8::from_str_radix(c, 16).ok());
+    let actual = data.iter().fold(0u8, |a, b| a.wrapping_add(*b));
+    if self.ack {
+      if expected == Some(actual) {
+        self.stream.write_all(b"+")?;
+      } else {
+        self.stream.write_all(b"-")?;
+        return self.read_packet();
+      }
+    }
+    Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+  }
+
+  fn write_packet(&mut self, data: &str) -> io::Result<()> {
+    let checksum = data.bytes().fold(0u8, |a, b| a.wrapping_add(b));
+    write!(self.stream, "${}#{:02x}", data, checksum)?;
+    self.stream.flush()?;
+    if self.ack {
+      let mut byte = [0];
+      loop {
+        if self.stream.read(&mut byte)? == 0 || byte[0] == b'+' {
+          break;
+        }
+        if byte[0] == b'-' {
+          write!(self.stream, "${}#{:02x}", data, checksum)?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Returns the reply to a packet, or `None` if the session should end.
+  fn handle(&mut self, packet: &str) -> Option<String> {
+    let bytes = self.word_bytes() as usize;
+    let (kind, args) = packet.split_at(packet.len().min(1));
+    let reply = match kind {
+      "?" => "S05".to_owned(),
+      "g" => (0..REGISTER_COUNT)
+        .map(|i| word_to_hex(self.read_register(i), bytes))
+        .collect(),
+      "G" => {
+        let digits = bytes * 2;
+        for i in 0..REGISTER_COUNT {
+          let val = args
+            .get(i * digits..(i + 1) * digits)
+            .and_then(|s| word_from_hex(s, bytes));
+          match val {
+            Some(val) => self.write_register(i, val),
+            None => return Some("E01".to_owned()),
+          }
+        }
+        "OK".to_owned()
+      }
+      "p" => match parse_hex(args) {
+        Some(i) if (i as usize) < REGISTER_COUNT => {
+          word_to_hex(self.read_register(i as usize), bytes)
+        }
+        _ => "E01".to_owned(),
+      },
+      "P" => {
+        let reg = args.split_once('=').and_then(|(reg, val)| {
+          let reg = parse_hex(reg)? as usize;
+          Some((reg, word_from_hex(val, bytes)?))
+        });
+        match reg {
+          Some((reg, val)) if reg < REGISTER_COUNT => {
+            self.write_register(reg, val);
+            "OK".to_owned()
+          }
+          _ => "E01".to_owned(),
+        }
+      }
+      "m" => match parse_range(args) {
+        // Each byte read takes two in the reply.
+        Some((addr, len))
+          if addr >= 0
+            && (0..=PACKET_SIZE / 2).contains(&len)
+            && addr.checked_add(len).is_some() =>
+        {
+          to_hex(&self.read_memory(addr, len))
+        }
+        _ => "E01".to_owned(),
+      },
+      "M" => {
+        let write = args.split_once(':').and_then(|(range, data)| {
+          let (addr, len) = parse_range(range)?;
+          let data = from_hex(data)?;
+          if addr < 0
+            || data.len() as isize != len
+            || len > PACKET_SIZE / 2
+            || addr.checked_add(len).is_none()
+          {
+            return None;
+          }
+          Some((addr, data))
+        });
+        match write.map(|(addr, data)| self.write_memory(addr, &data)) {
+          Some(Ok(())) => "OK".to_owned(),
+          _ => "E01".to_owned(),
+        }
+      }
+      "Z" | "z" => {
+        let bp = args
+          .split_once(',')
+          .filter(|(kind, _)| *kind == "0" || *kind == "1")
+          .and_then(|(_, range)| parse_range(range));
+        match bp {
+          Some((addr, _)) => {
+            if kind == "Z" {
+              self.breakpoints.insert(addr);
+            } else {
+              self.breakpoints.remove(&addr);
+            }
+            "OK".to_owned()
+          }
+          None => String::new(),
+        }
+      }
+      "s" => {
+        let stop = self.step();
+        self.stop_reply(stop)
+      }
+      "c" => {
+        let stop = self.resume();
+        self.stop_reply(stop)
+      }
+      "H" => "OK".to_owned(),
+      "T" => "OK".to_owned(),
+      "D" | "k" => return None,
+      "q" | "Q" => self.handle_query(packet),
+      _ => String::new(),
+    };
+    Some(reply)
+  }
+
+  fn handle_query(&mut self, packet: &str) -> String {
+    if packet.starts_with("qSupported") {
+      format!(
+        "PacketSize={:x};qXfer:features:read+;swbreak+;QStartNoAckMode+",
+        PACKET_SIZE
+      )
+    } else if packet == "QStartNoAckMode" {
+      self.ack = false;
+      "OK".to_owned()
+    } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+      let xml = target_xml(self.vm.width);
+      match parse_range(range) {
+        Some((offset, len)) => {
+          let offset = (offset as usize).min(xml.len());
+          let end = (offset + len as usize).min(xml.len());
+          let more = if end < xml.len() { 'm' } else { 'l' };
+          format!("{}{}", more, &xml[offset..end])
+        }
+        None => "E01".to_owned(),
+      }
+    } else if packet == "qAttached" {
+      "1".to_owned()
+    } else if packet == "qC" {
+      "QC1".to_owned()
+    } else if packet == "qfThreadInfo" {
+      "m1".to_owned()
+    } else if packet == "qsThreadInfo" {
+      "l".to_owned()
+    } else {
+      String::new()
+    }
+  }
+
+  fn stop_reply(&self, stop: Stop) -> String {
+    match stop {
+      Stop::Trap => "S05".to_owned(),
+      Stop::Breakpoint => "T05swbreak:;".to_owned(),
+      // Only the low byte of the status is reported, as for a native program.
+      Stop::Exited => format!("W{:02x}", self.vm.exit_status() as u8),
+      // Reported as a segmentation fault, as for a native program.
+      Stop::Fault => "S0b".to_owned(),
+    }
+  }
+
+  fn finished(&self) -> bool {
+    self.vm.stopped(self.code)
+  }
+
+  /// Why the program stopped once it cannot run any further.
+  fn end(&self) -> Stop {
+    if self.vm.fault.is_some() {
+      Stop::Fault
+    } else {
+      Stop::Exited
+    }
+  }
+
+  fn step(&mut self) -> Stop {
+    self.vm.step(self.code);
+    if self.finished() {
+      self.end()
+    } else {
+      Stop::Trap
+    }
+  }
+
+  /// Runs until a breakpoint, the end of the program, or an interrupt from the
+  /// client. The instruction at PC always runs, even if it has a breakpoint.
+  fn resume(&mut self) -> Stop {
+    self.vm.step(self.code);
+    let mut count = 0;
+    while !self.finished() {
+      if self.breakpoints.contains(&self.vm.registers[0]) {
+        return Stop::Breakpoint;
+      }
+      self.vm.step(self.code);
+      count += 1;
+      if count % POLL_INTERVAL == 0 && self.interrupted() {
+        return Stop::Trap;
+      }
+    }
+    self.end()
+  }
+
+  /// Checks, without blocking, whether the client sent an interrupt.
+  fn interrupted(&mut self) -> bool {
+    let mut byte = [0];
+    self.stream.set_nonblocking(true).unwrap();
+    let read = self.stream.read(&mut byte);
+    self.stream.set_nonblocking(false).unwrap();
+    match read {
+      Ok(1) => byte[0] == 0x03,
+      Err(e) if e.kind() == ErrorKind::WouldBlock => false,
+      _ => false,
+    }
+  }
+
+  fn read_register(&self, i: usize) -> isize {
+    match i {
+      MAR => self.vm.MAR,
+      MBR => self.vm.MBR,
+      FLAGS => self.vm.flags(),
+      _ => self.vm.registers[i],
+    }
+  }
+
+  fn write_register(&mut self, i: usize, val: isize) {
+    let val = val & word_mask(self.vm.width);
+    match i {
+      MAR => self.vm.MAR = val,
+      MBR => self.vm.MBR = val,
+      FLAGS => self.vm.set_flags(val),
+      _ => self.vm.registers[i] = val,
+    }
+  }
+
+  /// Number of bytes in a word.
+  fn word_bytes(&self) -> isize {
+    self.vm.width as isize / 8
+  }
+
+  fn read_memory(&self, addr: isize, len: isize) -> Vec<u8> {
+    let bytes = self.word_bytes();
+    (addr..addr + len)
+      .map(|byte| {
+        let word = self.vm.memory.peek(byte / bytes).unwrap_or(0);
+        (word as u64).to_le_bytes()[(byte % bytes) as usize]
+      })
+      .collect()
+  }
+
+  /// Writes bytes from `addr`, to the addresses stores to the words holding
+  /// them would, failing without writing any if one is outside memory.
+  fn write_memory(&mut self, addr: isize, data: &[u8]) -> Result<(), Fault> {
+    let bytes = self.word_bytes();
+    let first = addr / bytes;
+    let end = (addr + data.len() as isize + bytes - 1) / bytes;
+    let words = (first..end)
+      .map(|loc| self.vm.address(loc))
+      .collect::<Result<Vec<_>, _>>()?;
+    for (i, val) in data.iter().enumerate() {
+      let byte = addr + i as isize;
+      let loc = words[(byte / bytes - first) as usize];
+      let mut word = (self.vm.memory.peek(loc).unwrap_or(0) as u64).to_le_bytes();
+      word[(byte % bytes) as usize] = *val;
+      // Loaded like the program's initial memory, so ROM can be patched.
+      self.vm.memory.load(loc, u64::from_le_bytes(word) as isize);
+    }
+    Ok(())
+  }
+}
+
+#[test]
+fn test_gdb_session() {
+  use crate::assembler::Assembly;
+  use std::net::TcpListener;
+
+  let a =
+    Assembly::try_assemble("[2]: 0x11223344;\nADD E, 6;\nADD E, 6;\nADD E, 6;\nSA 4;").unwrap();
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let address = listener.local_addr().unwrap();
+  let server = std::thread::spawn(move || {
+    let (stream, _) = listener.accept().unwrap();
+    let bus = crate::bus::Bus::with_size(a.width, 0x100).unwrap();
+    let mut vm = VM::with_bus(a.width, bus, a.reg_inits, a.mem_inits).unwrap();
+    serve(stream, &mut vm, &a.instructions).unwrap();
+    vm
+  });
+
+  let mut client = TcpStream::connect(address).unwrap();
+  client.set_nodelay(true).unwrap();
+  let mut request = |packet: &str| -> String {
+    let checksum = packet.bytes().fold(0u8, |a, b| a.wrapping_add(b));
+    write!(client, "${}#{:02x}", packet, checksum).unwrap();
+    let mut byte = [0];
+    client.read_exact(&mut byte).unwrap();
+    assert_eq!(byte[0], b'+');
+    let mut reply = vec![];
+    loop {
+      client.read_exact(&mut byte).unwrap();
+      if byte[0] == b'#' {
+        break;
+      }
+      reply.push(byte[0]);
+    }
+    let mut checksum = [0; 2];
+    client.read_exact(&mut checksum).unwrap();
+    client.write_all(b"+").unwrap();
+    String::from_utf8(reply[1..].to_vec()).unwrap()
+  };
+
+  assert!(request("qSupported:swbreak+").contains("qXfer:features:read+"));
+  assert!(request("qXfer:features:read:target.xml:0,fff").starts_with("l<?xml"));
+  assert_eq!(request("?"), "S05");
+  assert_eq!(request("s"), "S05");
+  assert_eq!(request("p0"), "01000000");
+  assert_eq!(request("pe"), "01000000");
+  assert_eq!(request("Z0,3,4"), "OK");
+  assert_eq!(request("c"), "T05swbreak:;");
+  assert_eq!(request("p0"), "03000000");
+  assert_eq!(request("m8,4"), "44332211");
+  assert_eq!(request("m0,ffffffffffff"), "E01");
+  assert_eq!(request("m7fffffffffffffff,2"), "E01");
+  assert_eq!(request("M8,2:aabb"), "OK");
+  assert_eq!(request("m8,4"), "aabb2211");
+  assert_eq!(request("M3fe,4:ccddeeff"), "E01");
+  assert_eq!(request("M400,1:cc"), "E01");
+  assert_eq!(request("M7fffffffffffffff,1:cc"), "E01");
+  let long = "cc".repeat(PACKET_SIZE as usize / 2 + 1);
+  assert_eq!(request(&format!("M0,{:x}:{}", long.len() / 2, long)), "E01");
+  assert_eq!(request("m0,c"), "0000000000000000aabb2211");
+  assert_eq!(request("P4=10000000"), "OK");
+  assert_eq!(request("c"), "W00");
+  let registers = request("g");
+  assert_eq!(registers.len(), REGISTER_COUNT * 8);
+  assert_eq!(&registers[16 * 8..17 * 8], "10000000");
+  assert_eq!(request("D"), "OK");
+
+  let vm = server.join().unwrap();
+  assert_eq!(vm.registers[0xE], 3);
+  assert_eq!(vm.MAR, 0x10);
+  assert_eq!(vm.memory.peek(2), Some(0x1122bbaa));
+}
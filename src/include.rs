@@ -0,0 +1,213 @@
+//! Loads the files a program includes with `.include "file"`.
+//!
+//! An included file is searched for next to the file including it, then in
+//! each include directory. Its statements take the place of the `.include`.
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+  assembler::Diagnostic,
+  parser::{parse, OperandKind, Statement, StatementKind},
+  source::SourceMap,
+};
+
+struct Loader {
+  statements: Vec<Statement>,
+  errors: Vec<Diagnostic>,
+  /// Files being included, innermost last, to catch include cycles.
+  stack: Vec<PathBuf>,
+}
+
+/// Finds an included file, or the places searched for it.
+fn find(name: &str, dir: &Path, include_dirs: &[PathBuf]) -> Result<PathBuf, Vec<PathBuf>> {
+  let candidates = std::iter::once(dir)
+    .chain(include_dirs.iter().map(|d| d.as_path()))
+    .map(|d| d.join(name))
+    .collect::<Vec<_>>();
+  match candidates.iter().find(|c| c.is_file()) {
+    Some(path) => Ok(path.clone()),
+    None => Err(candidates),
+  }
+}
+
+impl Loader {
+  fn file(&mut self, sources: &mut SourceMap, index: usize) {
+    let file = &sources.files[index];
+    let program = parse(&file.text);
+    let start = file.start;
+    let name = file.name.clone();
+    let dir = match &file.path {
+      Some(path) => path.parent().unwrap_or_else(|| Path::new("")).to_owned(),
+      None => PathBuf::new(),
+    };
+    for mut error in program.errors {
+      if index > 0 {
+        error.file = Some(name.as_str().into());
+      }
+      self.errors.push(error);
+    }
+    for mut statement in program.statements {
+      statement.shift(start);
+      let args = match &statement.kind {
+        StatementKind::Directive { name, args, .. }
+          if name.text.eq_ignore_ascii_case(".include") =>
+        {
+          args
+        }
+        _ => {
+          self.statements.push(statement);
+          continue;
+        }
+      };
+      let included = match args.as_slice() {
+        [arg] => match &arg.kind {
+          OperandKind::Str(included) => included.clone(),
+          _ => String::new(),
+        },
+        _ => String::new(),
+      };
+      if included.is_empty() {
+        self.error(
+          sources,
+          &statement,
+          "Expected a file name in quotes after .include".to_owned(),
+        );
+        continue;
+      }
+      let path = match find(&included, &dir, &sources.include_dirs) {
+        Ok(path) => path,
+        Err(searched) => {
+          let searched = searched
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+          let message = format!(
+            "Cannot find included file '{}' (searched {})",
+            included, searched
+          );
+          self.error(sources, &statement, message);
+          continue;
+        }
+      };
+      let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+      if let Some(i) = self.stack.iter().position(|p| *p == canonical) {
+        let cycle = self.stack[i..]
+          .iter()
+          .chain(std::iter::once(&canonical))
+          .map(|p| p.display().to_string())
+          .collect::<Vec<_>>()
+          .join(" -> ");
+        self.error(sources, &statement, format!("Include cycle: {}", cycle));
+        continue;
+      }
+      let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) => {
+          let message = format!("Cannot read '{}': {}", path.display(), e);
+          self.error(sources, &statement, message);
+          continue;
+        }
+      };
+      let child = sources.add(path.display().to_string(), Some(path), text);
+      self.stack.push(canonical);
+      self.file(sources, child);
+      self.stack.pop();
+    }
+  }
+
+  fn error(&mut self, sources: &SourceMap, statement: &Statement, message: String) {
+    self
+      .errors
+      .push(Diagnostic::error(sources, statement.span, message));
+  }
+}
+
+/// Parses a program along with every file it includes, adding them to the
+/// source map.
+pub fn load(sources: &mut SourceMap) -> (Vec<Statement>, Vec<Diagnostic>) {
+  let mut loader = Loader {
+    statements: vec![],
+    errors: vec![],
+    stack: vec![],
+  };
+  if let Some(path) = &sources.files[0].path {
+    loader
+      .stack
+      .push(path.canonicalize().unwrap_or_else(|_| path.clone()));
+  }
+  loader.file(sources, 0);
+  (loader.statements, loader.errors)
+}
+
+#[test]
+fn test_include() {
+  use crate::assembler::{Assembly, Instruction::*};
+
+  let dir = std::env::temp_dir().join(format!("vmal_include_{}", std::process::id()));
+  std::fs::create_dir_all(dir.join("lib")).unwrap();
+  std::fs::create_dir_all(dir.join("std")).unwrap();
+  let write = |name: &str, text: &str| std::fs::write(dir.join(name), text).unwrap();
+  write(
+    "main.vmal",
+    ".include \"lib/neg.vmal\"\n  NEG E;\n  GO done;\nLBL done;\n",
+  );
+  write(
+    "lib/neg.vmal",
+    ".include \"double.vmal\"\n.macro NEG r\n  NOT r, r;\n  ADD r, 6;\n.endm\n",
+  );
+  write("std/double.vmal", "# Doubles A\n  ADD A, A;\n");
+  write("bad.vmal", ".include \"lib/bad.vmal\"\n");
+  write("lib/bad.vmal", "\n  ADD Q, A;\n");
+  write("cycle.vmal", ".include \"cycle.vmal\"\n");
+  write(
+    "missing.vmal",
+    ".include \"nowhere.vmal\";\n.include nowhere;\n",
+  );
+  let sources = |name: &str| {
+    let path = dir.join(name);
+    let mut sources = SourceMap::from_path(&path, std::fs::read_to_string(&path).unwrap());
+    sources.include_dirs = vec![dir.join("std")];
+    sources
+  };
+
+  let a = Assembly::try_assemble(sources("main.vmal")).unwrap();
+  assert_eq!(
+    a.instructions,
+    vec![ADD(0xA, 0xA), NOT(0xE, 0xE), ADD(0xE, 6), GO(3)]
+  );
+  assert_eq!(a.files, vec![2, 0, 0, 0]);
+  assert_eq!(a.lines, vec![1, 1, 1, 2]);
+  assert_eq!(a.sources.files[2].path, Some(dir.join("std/double.vmal")));
+
+  let (_, errors) = Assembly::check(sources("bad.vmal"));
+  assert_eq!(
+    errors[0].to_string(),
+    format!(
+      "Error on line #2 of {}: Invalid register specifier 'Q'\n\t>  ADD Q, A;\n\t       ^",
+      dir.join("lib/bad.vmal").display()
+    )
+  );
+
+  let (_, errors) = Assembly::check(sources("cycle.vmal"));
+  let cycle = dir.join("cycle.vmal").canonicalize().unwrap();
+  assert_eq!(
+    errors[0].message,
+    format!("Include cycle: {0} -> {0}", cycle.display())
+  );
+
+  let (_, errors) = Assembly::check(sources("missing.vmal"));
+  let messages = errors.iter().map(|e| e.message.clone()).collect::<Vec<_>>();
+  assert_eq!(
+    messages,
+    vec![
+      format!(
+        "Cannot find included file 'nowhere.vmal' (searched {}, {})",
+        dir.join("nowhere.vmal").display(),
+        dir.join("std/nowhere.vmal").display()
+      ),
+      "Expected a file name in quotes after .include".to_owned(),
+    ]
+  );
+  std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,223 @@
+//! Splits VMAL source into tokens.
+
+/// A byte range in the source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+  pub start: usize,
+  pub end: usize,
+}
+
+impl Span {
+  pub fn new(start: usize, end: usize) -> Self {
+    Span { start, end }
+  }
+  /// The smallest span covering both spans.
+  pub fn to(self, other: Span) -> Span {
+    Span::new(self.start.min(other.start), self.end.max(other.end))
+  }
+  pub fn contains(&self, offset: usize) -> bool {
+    self.start <= offset && offset <= self.end
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+  /// A name: mnemonics, registers and labels.
+  Ident,
+  /// A number literal, possibly with a `0x` or `0b` prefix. Anything starting
+  /// with a digit is lexed as a number, so that `1abc` is reported as an
+  /// invalid literal rather than split up.
+  Number,
+  /// A directive name, including its leading `.`.
+  Directive,
+  /// A string literal, holding its unescaped value.
+  Str(String),
+  Colon,
+  Semicolon,
+  Comma,
+  LBracket,
+  RBracket,
+  Minus,
+  /// A comment, from `#` up to the end of the line.
+  Comment,
+  Newline,
+  /// A string literal missing its closing quote.
+  UnterminatedStr,
+  /// A character that cannot start a token.
+  Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+  pub kind: TokenKind,
+  pub span: Span,
+}
+
+impl Token {
+  pub fn text<'a>(&self, source: &'a str) -> &'a str {
+    &source[self.span.start..self.span.end]
+  }
+}
+
+fn is_name_char(c: char) -> bool {
+  c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Splits source into tokens. Whitespace other than newlines is skipped, and
+/// every line, including the last, ends with a `Newline` token.
+pub fn tokenize(source: &str) -> Vec<Token> {
+  let mut tokens = vec![];
+  let mut chars = source.char_indices().peekable();
+  while let Some((start, c)) = chars.next() {
+    let mut end = start + c.len_utf8();
+    let mut take_while = |end: &mut usize, f: &dyn Fn(char) -> bool| {
+      while let Some((i, c)) = chars.peek() {
+        if !f(*c) {
+          break;
+        }
+        *end = i + c.len_utf8();
+        chars.next();
+      }
+    };
+    let kind = match c {
+      '\n' => TokenKind::Newline,
+      c if c.is_whitespace() => continue,
+      '#' => {
+        take_while(&mut end, &|c| c != '\n');
+        TokenKind::Comment
+      }
+      ':' => TokenKind::Colon,
+      ';' => TokenKind::Semicolon,
+      ',' => TokenKind::Comma,
+      '[' => TokenKind::LBracket,
+      ']' => TokenKind::RBracket,
+      '-' => TokenKind::Minus,
+      '.' => {
+        take_while(&mut end, &is_name_char);
+        TokenKind::Directive
+      }
+      c if c.is_ascii_digit() => {
+        take_while(&mut end, &is_name_char);
+        TokenKind::Number
+      }
+      c if is_name_char(c) => {
+        take_while(&mut end, &is_name_char);
+        TokenKind::Ident
+      }
+      '"' => {
+        let mut value = String::new();
+        let mut terminated = false;
+        while let Some((i, c)) = chars.peek().copied() {
+          if c == '\n' {
+            break;
+          }
+          chars.next();
+          end = i + c.len_utf8();
+          match c {
+            '"' => {
+              terminated = true;
+              break;
+            }
+            '\\' => {
+              if let Some((i, c)) = chars.peek().copied() {
+                if c == '\n' {
+                  break;
+                }
+                chars.next();
+                end = i + c.len_utf8();
+                value.push(match c {
+                  'n' => '\n',
+                  't' => '\t',
+                  'r' => '\r',
+                  '0' => '\0',
+                  c => c,
+                });
+              }
+            }
+            c => value.push(c),
+          }
+        }
+        if terminated {
+          TokenKind::Str(value)
+        } else {
+          TokenKind::UnterminatedStr
+        }
+      }
+      _ => TokenKind::Unknown,
+    };
+    tokens.push(Token {
+      kind,
+      span: Span::new(start, end),
+    });
+  }
+  if tokens.last().map(|t| &t.kind) != Some(&TokenKind::Newline) {
+    tokens.push(Token {
+      kind: TokenKind::Newline,
+      span: Span::new(source.len(), source.len()),
+    });
+  }
+  tokens
+}
+
+/// Converts byte offsets into line and column numbers.
+pub struct LineIndex {
+  starts: Vec<usize>,
+}
+
+impl LineIndex {
+  pub fn new(source: &str) -> Self {
+    let mut starts = vec![0];
+    starts.extend(source.match_indices('\n').map(|(i, _)| i + 1));
+    LineIndex { starts }
+  }
+  /// Zero-based line and byte column of an offset.
+  pub fn position(&self, offset: usize) -> (usize, usize) {
+    let line = match self.starts.binary_search(&offset) {
+      Ok(line) => line,
+      Err(next) => next - 1,
+    };
+    (line, offset - self.starts[line])
+  }
+  /// Byte offset of a zero-based line and column.
+  pub fn offset(&self, line: usize, column: usize) -> Option<usize> {
+    self.starts.get(line).map(|start| start + column)
+  }
+}
+
+#[test]
+fn test_tokenize() {
+  let source = "ADD\tE, 0x1F; # a \"comment\"\n.ascii \"a#b\\n\";\n1abc a-b";
+  let kinds = tokenize(source)
+    .into_iter()
+    .map(|t| t.kind)
+    .collect::<Vec<_>>();
+  assert_eq!(
+    kinds,
+    vec![
+      TokenKind::Ident,
+      TokenKind::Ident,
+      TokenKind::Comma,
+      TokenKind::Number,
+      TokenKind::Semicolon,
+      TokenKind::Comment,
+      TokenKind::Newline,
+      TokenKind::Directive,
+      TokenKind::Str("a#b\n".to_owned()),
+      TokenKind::Semicolon,
+      TokenKind::Newline,
+      TokenKind::Number,
+      TokenKind::Ident,
+      TokenKind::Minus,
+      TokenKind::Ident,
+      TokenKind::Newline,
+    ]
+  );
+  let tokens = tokenize("\"open\nLBL x;");
+  assert_eq!(tokens[0].kind, TokenKind::UnterminatedStr);
+  assert_eq!(tokens[1].kind, TokenKind::Newline);
+
+  let index = LineIndex::new("ab\ncd\n");
+  assert_eq!(index.position(4), (1, 1));
+  assert_eq!(index.position(3), (1, 0));
+  assert_eq!(index.offset(1, 1), Some(4));
+}
@@ -0,0 +1,50 @@
+//! Assembler, virtual machine and debuggers for VMAL.
+//!
+//! Programs run on a [`Bus`] of devices, which may be defined outside this
+//! crate by implementing [`Device`]:
+//!
+//! ```
+//! use vmal::{Bus, Device};
+//!
+//! /// Reads how many times it has been read.
+//! struct Counter(isize);
+//!
+//! impl Device for Counter {
+//!   fn read(&mut self, _offset: isize) -> isize {
+//!     self.0 += 1;
+//!     self.0
+//!   }
+//!   fn write(&mut self, _offset: isize, _val: isize) {}
+//! }
+//!
+//! let mut bus = Bus::new();
+//! bus.map("counter", 0x100, 1, Box::new(Counter(0))).unwrap();
+//! bus.read(0x100);
+//! assert_eq!(bus.read(0x100), 2);
+//! assert_eq!(bus.device_mut::<Counter>().unwrap().0, 2);
+//! ```
+#![allow(non_snake_case)]
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod assembler;
+pub mod bus;
+pub mod cfg;
+pub mod console;
+pub mod dap;
+pub mod decode;
+pub mod formatter;
+pub mod gdb;
+pub mod include;
+pub mod lexer;
+pub mod lint;
+pub mod lsp;
+pub mod macros;
+pub mod memory;
+pub mod object;
+pub mod parser;
+pub mod source;
+pub mod tui;
+pub mod util;
+pub mod vm;
+
+pub use bus::{Bus, Device};
@@ -0,0 +1,329 @@
+//! Static checks for common mistakes in programs that assemble.
+//!
+//! Most checks follow every path through the program from its first
+//! instruction, tracking what may have happened before each instruction runs.
+//! A warning is only given when nothing on any path could have made the
+//! instruction valid, so loops and branches do not cause false alarms.
+
+use crate::{
+  assembler::{Assembly, Diagnostic, Instruction, RESET_REGS},
+  cfg::successors,
+  util::register_role,
+  vm::SP,
+};
+
+/// What may have happened on some path leading to an instruction.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Facts {
+  /// Registers that may have been given a value, one bit each.
+  registers: u16,
+  /// Whether `SF` may have set the flags.
+  flags: bool,
+  /// Whether an `ADD`, `SUB` or shift may have set C.
+  carry: bool,
+  /// Whether an `ADD` or `SUB` may have set V.
+  overflow: bool,
+  /// Whether MBR may have been loaded.
+  mbr: bool,
+  /// Whether MAR may have been set.
+  mar: bool,
+}
+
+impl Facts {
+  fn join(self, other: Facts) -> Facts {
+    Facts {
+      registers: self.registers | other.registers,
+      flags: self.flags || other.flags,
+      carry: self.carry || other.carry,
+      overflow: self.overflow || other.overflow,
+      mbr: self.mbr || other.mbr,
+      mar: self.mar || other.mar,
+    }
+  }
+  /// The facts after running an instruction.
+  fn after(mut self, instruction: &Instruction) -> Facts {
+    let (_, written) = registers(instruction);
+    if let Some(reg) = written {
+      self.registers |= 1 << reg;
+    }
+    match instruction {
+      Instruction::SF(_) => self.flags = true,
+      Instruction::ADD(..) | Instruction::SUB(..) => {
+        self.carry = true;
+        self.overflow = true;
+      }
+      Instruction::LS(..)
+      | Instruction::RS(..)
+      | Instruction::ASR(..)
+      | Instruction::ROL(..)
+      | Instruction::ROR(..) => self.carry = true,
+      Instruction::RD | Instruction::SB(_) => self.mbr = true,
+      Instruction::SA(_) => self.mar = true,
+      Instruction::SW(..) => {
+        self.mar = true;
+        self.mbr = true;
+      }
+      _ => {}
+    }
+    self
+  }
+}
+
+/// The registers an instruction reads and the one it writes.
+fn registers(instruction: &Instruction) -> (Vec<isize>, Option<isize>) {
+  match *instruction {
+    Instruction::SA(a) | Instruction::SB(a) | Instruction::SF(a) => (vec![a], None),
+    Instruction::RB(a) | Instruction::LI(a, _) => (vec![], Some(a)),
+    Instruction::ADD(a, b)
+    | Instruction::AND(a, b)
+    | Instruction::SUB(a, b)
+    | Instruction::OR(a, b)
+    | Instruction::XOR(a, b) => (vec![a, b], Some(a)),
+    Instruction::MV(a, b)
+    | Instruction::NOT(a, b)
+    | Instruction::RS(a, b)
+    | Instruction::LS(a, b)
+    | Instruction::ASR(a, b)
+    | Instruction::ROL(a, b)
+    | Instruction::ROR(a, b) => (vec![b], Some(a)),
+    Instruction::SW(a, b) => (vec![a, b], None),
+    Instruction::PUSH(a) => (vec![a, SP], None),
+    Instruction::POP(a) => (vec![SP], Some(a)),
+    Instruction::CALL(_) | Instruction::RET | Instruction::RTI => (vec![SP], None),
+    Instruction::HALT(a) => (vec![a], None),
+    _ => (vec![], None),
+  }
+}
+
+/// Checks an assembled program, returning a warning for every likely mistake.
+pub fn lint(assembly: &Assembly) -> Vec<Diagnostic> {
+  let source = &assembly.sources;
+  let code = &assembly.instructions;
+  let mut warnings = vec![];
+  let mut warn = |i: usize, message: String| {
+    warnings.push(Diagnostic::warning(source, assembly.spans[i], message));
+  };
+
+  let mut entry = Facts::default();
+  for reg in RESET_REGS
+    .iter()
+    .chain(&[SP])
+    .chain(assembly.reg_inits.iter().map(|(r, _)| r))
+  {
+    entry.registers |= 1 << reg;
+  }
+  // Facts before every instruction, or None for unreachable instructions.
+  let mut facts: Vec<Option<Facts>> = vec![None; code.len()];
+  let mut pending = vec![];
+  if !code.is_empty() {
+    facts[0] = Some(entry);
+    pending.push(0);
+  }
+  // Other objects may branch to global labels with anything already set.
+  let anything = Facts {
+    registers: u16::MAX,
+    flags: true,
+    carry: true,
+    overflow: true,
+    mbr: true,
+    mar: true,
+  };
+  // Interrupt handlers likewise run wherever the program was interrupted.
+  let vectors = code.iter().filter_map(|instruction| match instruction {
+    Instruction::SIV(t) => Some((t + 1) as usize),
+    _ => None,
+  });
+  let globals = assembly
+    .globals
+    .keys()
+    .filter_map(|name| assembly.targets.get(name).copied());
+  for i in globals.chain(vectors).filter(|i| *i < code.len()) {
+    facts[i] = Some(facts[i].map_or(anything, |f| f.join(anything)));
+    pending.push(i);
+  }
+  while let Some(i) = pending.pop() {
+    let after = facts[i].unwrap().after(&code[i]);
+    for j in successors(code, i) {
+      let joined = facts[j].map_or(after, |f| f.join(after));
+      if facts[j] != Some(joined) {
+        facts[j] = Some(joined);
+        pending.push(j);
+      }
+    }
+  }
+
+  for (i, instruction) in code.iter().enumerate() {
+    let before = match facts[i] {
+      Some(before) => before,
+      None => {
+        // Only the first of a run of unreachable instructions is reported.
+        if i == 0 || facts[i - 1].is_some() {
+          let message = match code.get(i.wrapping_sub(1)) {
+            Some(Instruction::GO(_)) => "Unreachable instruction after GO",
+            Some(Instruction::RET) => "Unreachable instruction after RET",
+            Some(Instruction::HALT(_)) => "Unreachable instruction after HALT",
+            Some(Instruction::RTI) => "Unreachable instruction after RTI",
+            _ => "Unreachable instruction",
+          };
+          warn(i, message.to_owned());
+        }
+        continue;
+      }
+    };
+    match instruction {
+      Instruction::BIN(_) | Instruction::BIZ(_) if !before.flags => {
+        let op = if let Instruction::BIN(_) = instruction {
+          "BIN"
+        } else {
+          "BIZ"
+        };
+        warn(
+          i,
+          format!("{} tests the flags, but no SF sets them before it", op),
+        );
+      }
+      Instruction::BIC(_) if !before.carry => {
+        warn(
+          i,
+          "BIC tests the C flag, but no ADD, SUB or shift sets it before it".to_owned(),
+        );
+      }
+      Instruction::BIV(_) if !before.overflow => {
+        warn(
+          i,
+          "BIV tests the V flag, but no ADD or SUB sets it before it".to_owned(),
+        );
+      }
+      Instruction::RB(_) if !before.mbr => {
+        warn(i, "RB reads MBR, but no RD loads it before it".to_owned());
+      }
+      Instruction::WR if !before.mar => {
+        warn(
+          i,
+          "WR writes to MAR, but no SA or SW sets it before it".to_owned(),
+        );
+      }
+      _ => {}
+    }
+    let (mut read, written) = registers(instruction);
+    read.dedup();
+    for reg in read {
+      if before.registers & (1 << reg) == 0 {
+        warn(
+          i,
+          format!("Register {:X} is read before it is given a value", reg),
+        );
+      }
+    }
+    if let Some(reg) = written {
+      if RESET_REGS.contains(&reg) {
+        warn(
+          i,
+          format!(
+            "Write to register {:X}, which is {}",
+            reg,
+            register_role(reg)
+          ),
+        );
+      }
+    }
+  }
+
+  for (name, span) in &assembly.labels {
+    let is_used =
+      assembly.globals.contains_key(name) || assembly.label_refs.iter().any(|(r, _)| r == name);
+    if !is_used {
+      warnings.push(Diagnostic::warning(
+        source,
+        *span,
+        format!("Label '{}' is never used", name),
+      ));
+    }
+  }
+  warnings.sort_by(|a, b| (&a.file, a.line, a.columns).cmp(&(&b.file, b.line, b.columns)));
+  warnings
+}
+
+#[test]
+fn test_lint() {
+  let source = "\
+A: 5;
+LBL unused;
+LBL loop;
+  BIZ loop;
+  RB B;
+  WR;
+  ADD C, A;
+  MV 6, A;
+  SF A;
+  BIN end;
+  GO end;
+  ADD A, A;
+  ADD A, A;
+LBL end;
+  SA A;
+  RD;
+  RB B;
+  WR;
+  ADD 0, B;
+";
+  let assembly = Assembly::try_assemble(source).unwrap();
+  let warnings = lint(&assembly)
+    .into_iter()
+    .map(|w| (w.line, w.message))
+    .collect::<Vec<_>>();
+  assert_eq!(
+    warnings,
+    vec![
+      (1, "Label 'unused' is never used".to_owned()),
+      (
+        3,
+        "BIZ tests the flags, but no SF sets them before it".to_owned()
+      ),
+      (4, "RB reads MBR, but no RD loads it before it".to_owned()),
+      (
+        5,
+        "WR writes to MAR, but no SA or SW sets it before it".to_owned()
+      ),
+      (
+        6,
+        "Register C is read before it is given a value".to_owned()
+      ),
+      (7, "Write to register 6, which is the constant 1".to_owned()),
+      (11, "Unreachable instruction after GO".to_owned()),
+      (
+        18,
+        "Write to register 0, which is PC, the program counter".to_owned()
+      ),
+    ]
+  );
+  let assembly = Assembly::try_assemble("HALT;\nPRINT;").unwrap();
+  assert_eq!(
+    lint(&assembly)[0].message,
+    "Unreachable instruction after HALT"
+  );
+  // A handler can run at any time, so it can test flags it did not set.
+  let assembly =
+    Assembly::try_assemble("SIV tick;\nEI;\nHALT;\nLBL tick;\nBIZ tick;\nRTI;\nPRINT;").unwrap();
+  let warnings = lint(&assembly)
+    .into_iter()
+    .map(|w| (w.line, w.message))
+    .collect::<Vec<_>>();
+  assert_eq!(
+    warnings,
+    vec![(6, "Unreachable instruction after RTI".to_owned())]
+  );
+  let assembly =
+    Assembly::try_assemble("A: 1;\nBIC a;\nLBL a;\nADD A, A;\nBIC b;\nLBL b;\nBIV a;").unwrap();
+  let warnings = lint(&assembly)
+    .into_iter()
+    .map(|w| (w.line, w.message))
+    .collect::<Vec<_>>();
+  assert_eq!(
+    warnings,
+    vec![(
+      1,
+      "BIC tests the C flag, but no ADD, SUB or shift sets it before it".to_owned()
+    )]
+  );
+}
@@ -0,0 +1,580 @@
+//! A language server for `.vmal` files, speaking the Language Server Protocol
+//! on stdin and stdout.
+
+use std::collections::HashMap;
+use std::io::{self, stdin, stdout, Write};
+
+use serde_json::{json, Value};
+
+use crate::{
+  assembler::{
+    Assembly, Diagnostic, Severity, IMMEDIATE_OPS, LABEL_OPS, ONE_REG_OPS, OP_MAP, TWO_REG_OPS,
+  },
+  lexer::{tokenize, LineIndex, Span, TokenKind},
+  parser::{parse, Operand, OperandKind, StatementKind},
+  source::SourceMap,
+  util::{read_message, register_role, write_message},
+};
+
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// Describes what an operation does.
+fn describe_op(op: &str) -> &'static str {
+  match op {
+    "SA" => "`SA r` - Set Address: copies register `r` into MAR.",
+    "RB" => "`RB r` - Read Buffer: copies MBR into register `r`.",
+    "RD" => "`RD` - Read: loads the memory location MAR points to into MBR.",
+    "WR" => "`WR` - Write: stores MBR at the memory location MAR points to.",
+    "SB" => "`SB r` - Set Buffer: copies register `r` into MBR.",
+    "SF" => "`SF r` - Set Flags: sets N if register `r` is negative and Z if it is zero.",
+    "LBL" => "`LBL name` - Label: names the position of the next instruction.",
+    "GO" => "`GO label` - Go: jumps to `label`.",
+    "BIN" => "`BIN label` - Branch If Negative: jumps to `label` if the N flag is set.",
+    "BIZ" => "`BIZ label` - Branch If Zero: jumps to `label` if the Z flag is set.",
+    "ADD" => "`ADD a, b` - Add: `a = a + b`, setting C on an unsigned carry and V on a signed overflow.",
+    "AND" => "`AND a, b` - And: `a = a & b`.",
+    "MV" => "`MV a, b` - Move: `a = b`.",
+    "NOT" => "`NOT a, b` - Not: `a = !b`.",
+    "RS" => "`RS a, b` - Right Shift: `a = b >> 1`, filling with a zero and setting C to the bit shifted out.",
+    "LS" => "`LS a, b` - Left Shift: `a = b << 1`, setting C to the bit shifted out.",
+    "SW" => "`SW a, b` - Store Word: stores register `b` at the memory location in register `a`, leaving MAR and MBR set.",
+    "PRINT" => "`PRINT` - prints the value of every register.",
+    "LI" => "`LI r, v` - Load Immediate: sets register `r` to the number `v`, or to the address of the data label `v`.",
+    "CALL" => "`CALL label` - Call: pushes the position of the next instruction onto the stack and jumps to `label`.",
+    "RET" => "`RET` - Return: pops a position off the stack and jumps back to it.",
+    "PUSH" => "`PUSH r` - Push: decrements SP and stores register `r` at the memory location it points to.",
+    "POP" => "`POP r` - Pop: loads the memory location SP points to into register `r` and increments SP.",
+    "SUB" => "`SUB a, b` - Subtract: `a = a - b`, setting C on an unsigned borrow and V on a signed overflow.",
+    "OR" => "`OR a, b` - Or: `a = a | b`.",
+    "XOR" => "`XOR a, b` - Exclusive Or: `a = a ^ b`.",
+    "ASR" => "`ASR a, b` - Arithmetic Shift Right: `a = b >> 1`, keeping the sign bit and setting C to the bit shifted out.",
+    "ROL" => "`ROL a, b` - Rotate Left: `a = b << 1`, with the top bit moved to the bottom and into C.",
+    "ROR" => "`ROR a, b` - Rotate Right: `a = b >> 1`, with the bottom bit moved to the top and into C.",
+    "BIC" => "`BIC label` - Branch If Carry: jumps to `label` if the C flag is set.",
+    "BIV" => "`BIV label` - Branch If Overflow: jumps to `label` if the V flag is set.",
+    "HALT" => "`HALT` or `HALT a` - Halt: stops the program, with `a` as its exit status (0 if left out).",
+    "EI" => "`EI` - Enable Interrupts: lets devices such as the timer interrupt the program.",
+    "DI" => "`DI` - Disable Interrupts: holds back interrupts until the next `EI`.",
+    "SIV" => "`SIV label` - Set Interrupt Vector: makes interrupts save PC and the flags on the stack, disable interrupts and jump to `label`.",
+    "RTI" => "`RTI` - Return From Interrupt: restores the flags and PC an interrupt saved, enabling interrupts again.",
+    _ => "",
+  }
+}
+
+/// What a word in the source refers to.
+#[derive(Debug, PartialEq)]
+enum Word {
+  Op(String),
+  Label(String),
+  Register(isize),
+}
+
+/// Span of the name in the definition of a code or data label.
+fn definition(assembly: &Assembly, name: &str) -> Option<Span> {
+  assembly
+    .labels
+    .get(name)
+    .or_else(|| assembly.data_labels.get(name))
+    .copied()
+}
+
+/// Finds the word at a byte offset and what it refers to.
+fn word_at(source: &str, offset: usize) -> Option<(Word, Span)> {
+  let program = parse(source);
+  let statement = program
+    .statements
+    .iter()
+    .find(|s| s.span.contains(offset))?;
+  let register = |operand: &Operand| {
+    operand
+      .register()
+      .map(|r| (Word::Register(r), operand.span))
+  };
+  let label = |operand: &Operand| {
+    if operand.kind == OperandKind::Name && operand.span.contains(offset) {
+      Some((Word::Label(operand.text.clone()), operand.span))
+    } else {
+      None
+    }
+  };
+  match &statement.kind {
+    StatementKind::RegisterInit { register: reg, .. } if reg.span.contains(offset) => register(reg),
+    StatementKind::RegisterInit { value, .. } | StatementKind::MemoryInit { value, .. } => {
+      label(value)
+    }
+    // The name and parameters of a macro are not labels.
+    StatementKind::Directive { name, .. } if name.text.eq_ignore_ascii_case(".macro") => None,
+    StatementKind::Directive {
+      label: data_label,
+      args,
+      ..
+    } => data_label.iter().chain(args).find_map(label),
+    StatementKind::Instruction { op, args } => {
+      let name = op.text.to_uppercase();
+      if op.span.contains(offset) {
+        return Some((Word::Op(name), op.span));
+      }
+      let arg = args.iter().find(|a| a.span.contains(offset))?;
+      let op_num = *OP_MAP.get(name.as_str())?;
+      if LABEL_OPS.contains(&op_num) && arg.kind == OperandKind::Name {
+        Some((Word::Label(arg.text.clone()), arg.span))
+      } else if ONE_REG_OPS.contains(&op_num) || TWO_REG_OPS.contains(&op_num) {
+        register(arg)
+      } else if IMMEDIATE_OPS.contains(&op_num) {
+        if arg.span == args[0].span {
+          register(arg)
+        } else {
+          label(arg)
+        }
+      } else {
+        None
+      }
+    }
+  }
+}
+
+fn range(line: usize, start: usize, end: usize) -> Value {
+  json!({
+    "start": { "line": line, "character": start },
+    "end": { "line": line, "character": end },
+  })
+}
+
+fn span_range(index: &LineIndex, span: Span) -> Value {
+  let (start_line, start) = index.position(span.start);
+  let (end_line, end) = index.position(span.end);
+  json!({
+    "start": { "line": start_line, "character": start },
+    "end": { "line": end_line, "character": end },
+  })
+}
+
+fn to_lsp_diagnostic(uri: &str, diagnostic: &Diagnostic) -> Value {
+  let (start, end) = diagnostic.columns;
+  let related = diagnostic
+    .notes
+    .iter()
+    .filter(|note| note.file.is_none())
+    .map(|note| {
+      json!({
+        "location": {
+          "uri": uri,
+          "range": range(note.line, note.columns.0, note.columns.1),
+        },
+        "message": note.message,
+      })
+    })
+    .collect::<Vec<_>>();
+  json!({
+    "range": range(diagnostic.line, start, end),
+    "severity": match diagnostic.severity {
+      Severity::Error => 1,
+      Severity::Warning => 2,
+      Severity::Note => 3,
+    },
+    "source": "vmal",
+    "message": diagnostic.message,
+    "relatedInformation": related,
+  })
+}
+
+/// The source of a document, with included files looked for next to it when it
+/// is a file on disk.
+fn sources(uri: &str, text: &str) -> SourceMap {
+  match uri.strip_prefix("file://") {
+    Some(path) => SourceMap::from_path(path, text.to_owned()),
+    None => SourceMap::from(text),
+  }
+}
+
+struct Server<W: Write> {
+  output: W,
+  documents: HashMap<String, String>,
+}
+
+/// Runs the language server on stdin and stdout until the client exits.
+pub fn serve() -> io::Result<()> {
+  let stdin = stdin();
+  let mut input = stdin.lock();
+  let stdout = stdout();
+  let mut server = Server {
+    output: stdout.lock(),
+    documents: HashMap::new(),
+  };
+  while let Some(message) = read_message(&mut input)? {
+    if !server.handle(&message)? {
+      break;
+    }
+  }
+  Ok(())
+}
+
+impl<W: Write> Server<W> {
+  /// Handles a request or notification, returning false once the client asks
+  /// the server to exit.
+  fn handle(&mut self, message: &Value) -> io::Result<bool> {
+    let params = &message["params"];
+    let method = message["method"].as_str().unwrap_or("");
+    let result = match method {
+      "initialize" => json!({
+        "capabilities": {
+          "textDocumentSync": 1,
+          "definitionProvider": true,
+          "referencesProvider": true,
+          "hoverProvider": true,
+          "completionProvider": { "triggerCharacters": [" ", ","] },
+          "documentSymbolProvider": true,
+        },
+        "serverInfo": { "name": "vmal", "version": env!("CARGO_PKG_VERSION") },
+      }),
+      "shutdown" => Value::Null,
+      "exit" => return Ok(false),
+      "textDocument/didOpen" => {
+        let uri = params["textDocument"]["uri"]
+          .as_str()
+          .unwrap_or("")
+          .to_owned();
+        let text = params["textDocument"]["text"]
+          .as_str()
+          .unwrap_or("")
+          .to_owned();
+        self.update(uri, text)?;
+        return Ok(true);
+      }
+      "textDocument/didChange" => {
+        let uri = params["textDocument"]["uri"]
+          .as_str()
+          .unwrap_or("")
+          .to_owned();
+        if let Some(change) = params["contentChanges"].as_array().and_then(|c| c.last()) {
+          let text = change["text"].as_str().unwrap_or("").to_owned();
+          self.update(uri, text)?;
+        }
+        return Ok(true);
+      }
+      "textDocument/didClose" => {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        self.documents.remove(uri);
+        self.notify(
+          "textDocument/publishDiagnostics",
+          json!({ "uri": uri, "diagnostics": [] }),
+        )?;
+        return Ok(true);
+      }
+      "textDocument/definition" => self.definition(params),
+      "textDocument/references" => self.references(params),
+      "textDocument/hover" => self.hover(params),
+      "textDocument/completion" => self.completion(params),
+      "textDocument/documentSymbol" => self.document_symbols(params),
+      _ => {
+        if !message["id"].is_null() {
+          self.send(json!({
+            "jsonrpc": "2.0",
+            "id": message["id"],
+            "error": {
+              "code": METHOD_NOT_FOUND,
+              "message": format!("Unsupported method '{}'", method),
+            },
+          }))?;
+        }
+        return Ok(true);
+      }
+    };
+    self.send(json!({ "jsonrpc": "2.0", "id": message["id"], "result": result }))?;
+    Ok(true)
+  }
+
+  fn send(&mut self, message: Value) -> io::Result<()> {
+    write_message(&mut self.output, &message)
+  }
+
+  fn notify(&mut self, method: &str, params: Value) -> io::Result<()> {
+    self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+  }
+
+  /// Stores the new text of a document and publishes its diagnostics.
+  fn update(&mut self, uri: String, text: String) -> io::Result<()> {
+    let (assembly, errors) = Assembly::check(sources(&uri, &text));
+    // Diagnostics in included files belong to their own documents.
+    let mut diagnostics = errors
+      .iter()
+      .chain(assembly.warnings.iter())
+      .filter(|d| d.file.is_none())
+      .collect::<Vec<_>>();
+    diagnostics.sort_by_key(|d| (d.line, d.columns));
+    let diagnostics = diagnostics
+      .into_iter()
+      .map(|d| to_lsp_diagnostic(&uri, d))
+      .collect::<Vec<_>>();
+    self.notify(
+      "textDocument/publishDiagnostics",
+      json!({ "uri": uri, "diagnostics": diagnostics }),
+    )?;
+    self.documents.insert(uri, text);
+    Ok(())
+  }
+
+  /// Looks up the document and word a position request is about.
+  fn lookup<'p>(&self, params: &'p Value) -> Option<(&'p str, Assembly, Word)> {
+    let uri = params["textDocument"]["uri"].as_str()?;
+    let text = self.documents.get(uri)?;
+    let line = params["position"]["line"].as_u64()? as usize;
+    let character = params["position"]["character"].as_u64()? as usize;
+    let offset = LineIndex::new(text).offset(line, character)?;
+    let (word, _) = word_at(text, offset)?;
+    Some((uri, Assembly::check(sources(uri, text)).0, word))
+  }
+
+  /// Location of a span, which may be in a file included by the document.
+  fn location(&self, uri: &str, assembly: &Assembly, span: Span) -> Value {
+    let file = &assembly.sources.files[assembly.sources.file_at(span.start)];
+    let uri = match &file.path {
+      Some(path) if file.start > 0 => format!("file://{}", path.display()),
+      _ => uri.to_owned(),
+    };
+    let index = LineIndex::new(&file.text);
+    let span = Span::new(span.start - file.start, span.end - file.start);
+    json!({ "uri": uri, "range": span_range(&index, span) })
+  }
+
+  fn definition(&self, params: &Value) -> Value {
+    match self.lookup(params) {
+      Some((uri, assembly, Word::Label(name))) => match definition(&assembly, &name) {
+        Some(span) => self.location(uri, &assembly, span),
+        None => Value::Null,
+      },
+      _ => Value::Null,
+    }
+  }
+
+  fn references(&self, params: &Value) -> Value {
+    let (uri, assembly, name) = match self.lookup(params) {
+      Some((uri, assembly, Word::Label(name))) => (uri, assembly, name),
+      _ => return Value::Null,
+    };
+    let mut locations = vec![];
+    if params["context"]["includeDeclaration"]
+      .as_bool()
+      .unwrap_or(true)
+    {
+      if let Some(span) = definition(&assembly, &name) {
+        locations.push(self.location(uri, &assembly, span));
+      }
+    }
+    for (label, span) in &assembly.label_refs {
+      if *label == name {
+        locations.push(self.location(uri, &assembly, *span));
+      }
+    }
+    json!(locations)
+  }
+
+  fn hover(&self, params: &Value) -> Value {
+    let contents = match self.lookup(params) {
+      Some((_, _, Word::Op(op))) => describe_op(&op).to_owned(),
+      Some((_, _, Word::Register(reg))) => {
+        format!("Register `{:X}`: {}", reg, register_role(reg))
+      }
+      Some((_, assembly, Word::Label(name))) => match definition(&assembly, &name) {
+        Some(span) => {
+          let label = match assembly.addresses.get(&name) {
+            Some(address) => format!("Data label `{}`, at address {}", name, address),
+            None => format!("Label `{}`", name),
+          };
+          match assembly.sources.position(span.start) {
+            (0, line, _) => format!("{}, defined on line {}", label, line + 1),
+            (file, line, _) => format!(
+              "{}, defined on line {} of {}",
+              label,
+              line + 1,
+              assembly.sources.files[file].name
+            ),
+          }
+        }
+        None => format!("Label `{}` is not defined", name),
+      },
+      None => return Value::Null,
+    };
+    if contents.is_empty() {
+      return Value::Null;
+    }
+    json!({ "contents": { "kind": "markdown", "value": contents } })
+  }
+
+  fn completion(&self, params: &Value) -> Value {
+    let complete = || -> Option<Vec<Value>> {
+      let uri = params["textDocument"]["uri"].as_str()?;
+      let text = self.documents.get(uri)?;
+      let line = text
+        .split('\n')
+        .nth(params["position"]["line"].as_u64()? as usize)?;
+      let character = params["position"]["character"].as_u64()? as usize;
+      let before = line.chars().take(character).collect::<String>();
+      if before.contains('#') || before.contains(':') {
+        return Some(vec![]);
+      }
+      if !before.trim_start().contains(char::is_whitespace) {
+        let mut ops = OP_MAP.keys().collect::<Vec<_>>();
+        ops.sort();
+        return Some(
+          ops
+            .into_iter()
+            .map(|op| json!({ "label": op, "kind": 14, "documentation": describe_op(op) }))
+            .collect(),
+        );
+      }
+      let op = tokenize(line)
+        .into_iter()
+        .next()
+        .filter(|t| t.kind == TokenKind::Ident)?
+        .text(line)
+        .to_uppercase();
+      let op_num = *OP_MAP.get(op.as_str())?;
+      if LABEL_OPS.contains(&op_num) && op != "LBL" {
+        let assembly = Assembly::check(sources(uri, text)).0;
+        let mut labels = assembly.labels.keys().collect::<Vec<_>>();
+        labels.sort();
+        Some(
+          labels
+            .into_iter()
+            .map(|l| json!({ "label": l, "kind": 18 }))
+            .collect(),
+        )
+      } else if IMMEDIATE_OPS.contains(&op_num) && before.contains(',') {
+        let assembly = Assembly::check(sources(uri, text)).0;
+        let mut labels = assembly.data_labels.keys().collect::<Vec<_>>();
+        labels.sort();
+        Some(
+          labels
+            .into_iter()
+            .map(|l| json!({ "label": l, "kind": 21 }))
+            .collect(),
+        )
+      } else if ONE_REG_OPS.contains(&op_num)
+        || TWO_REG_OPS.contains(&op_num)
+        || IMMEDIATE_OPS.contains(&op_num)
+      {
+        Some(
+          (0..16)
+            .map(|r| json!({ "label": format!("{:X}", r), "kind": 6, "detail": register_role(r) }))
+            .collect(),
+        )
+      } else {
+        Some(vec![])
+      }
+    };
+    json!(complete().unwrap_or_default())
+  }
+
+  fn document_symbols(&self, params: &Value) -> Value {
+    let uri = match params["textDocument"]["uri"].as_str() {
+      Some(uri) if self.documents.contains_key(uri) => uri,
+      _ => return Value::Null,
+    };
+    let text = &self.documents[uri];
+    let lines = text.split('\n').collect::<Vec<_>>();
+    let index = LineIndex::new(text);
+    let assembly = Assembly::check(sources(uri, text)).0;
+    let mut labels = assembly
+      .labels
+      .iter()
+      .filter(|(_, span)| assembly.sources.file_at(span.start) == 0)
+      .collect::<Vec<_>>();
+    labels.sort_by_key(|(_, span)| span.start);
+    let symbols = labels
+      .into_iter()
+      .map(|(name, span)| {
+        let (line, _) = index.position(span.start);
+        json!({
+          "name": name,
+          "kind": 12,
+          "range": range(line, 0, lines[line].len()),
+          "selectionRange": span_range(&index, *span),
+        })
+      })
+      .collect::<Vec<_>>();
+    json!(symbols)
+  }
+}
+
+#[test]
+fn test_word_at() {
+  let line = "  ADD E, 7; # comment";
+  assert_eq!(
+    word_at(line, 3),
+    Some((Word::Op("ADD".to_owned()), Span::new(2, 5)))
+  );
+  assert_eq!(
+    word_at(line, 6),
+    Some((Word::Register(0xE), Span::new(6, 7)))
+  );
+  assert_eq!(
+    word_at(line, 9),
+    Some((Word::Register(7), Span::new(9, 10)))
+  );
+  assert_eq!(word_at(line, 15), None);
+  assert_eq!(
+    word_at("RD;\nBIZ JumpHere;", 10),
+    Some((Word::Label("JumpHere".to_owned()), Span::new(8, 16)))
+  );
+  assert_eq!(
+    word_at("4: 1024;", 0),
+    Some((Word::Register(4), Span::new(0, 1)))
+  );
+}
+
+#[test]
+fn test_lsp_session() {
+  let uri = "file:///test.vmal";
+  let text = "5: 1;\nLBL loop;\nADD E, 7;\nSF E;\nBIZ loop;\nGO nowhere;\n";
+  let mut server = Server {
+    output: vec![],
+    documents: HashMap::new(),
+  };
+  let position = |line: usize, character: usize| {
+    json!({
+      "textDocument": { "uri": uri },
+      "position": { "line": line, "character": character },
+      "context": { "includeDeclaration": true },
+    })
+  };
+  let requests = vec![
+    json!({ "id": 1, "method": "initialize", "params": {} }),
+    json!({ "method": "textDocument/didOpen", "params": { "textDocument": { "uri": uri, "text": text } } }),
+    json!({ "id": 2, "method": "textDocument/definition", "params": position(4, 5) }),
+    json!({ "id": 3, "method": "textDocument/references", "params": position(1, 5) }),
+    json!({ "id": 4, "method": "textDocument/hover", "params": position(2, 7) }),
+    json!({ "id": 5, "method": "textDocument/completion", "params": position(4, 4) }),
+    json!({ "id": 6, "method": "textDocument/documentSymbol", "params": { "textDocument": { "uri": uri } } }),
+    json!({ "id": 7, "method": "textDocument/unknown", "params": {} }),
+  ];
+  for request in &requests {
+    assert!(server.handle(request).unwrap());
+  }
+  let mut input = io::Cursor::new(server.output);
+  let mut messages = HashMap::new();
+  let mut diagnostics = Value::Null;
+  while let Some(message) = read_message(&mut input).unwrap() {
+    if message["method"] == "textDocument/publishDiagnostics" {
+      diagnostics = message["params"]["diagnostics"].clone();
+    } else {
+      messages.insert(message["id"].as_i64().unwrap(), message);
+    }
+  }
+  assert_eq!(diagnostics.as_array().unwrap().len(), 2);
+  assert_eq!(diagnostics[0]["severity"], 2);
+  assert_eq!(diagnostics[1]["range"]["start"]["line"], 5);
+  assert_eq!(diagnostics[1]["severity"], 1);
+  assert_eq!(messages[&2]["result"]["range"], range(1, 4, 8));
+  assert_eq!(messages[&3]["result"].as_array().unwrap().len(), 2);
+  assert!(messages[&4]["result"]["contents"]["value"]
+    .as_str()
+    .unwrap()
+    .contains("the constant -1"));
+  assert_eq!(
+    messages[&5]["result"],
+    json!([{ "label": "loop", "kind": 18 }])
+  );
+  assert_eq!(messages[&6]["result"][0]["name"], "loop");
+  assert_eq!(messages[&7]["error"]["code"], METHOD_NOT_FOUND);
+}
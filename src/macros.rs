@@ -0,0 +1,387 @@
+//! Expands macros, defined with `.macro NAME params` and `.endm`:
+//!
+//! ```text
+//! .macro SUB a, b    # a = a - b, using register F
+//!   NOT F, b;
+//!   ADD F, 6;
+//!   ADD a, F;
+//! .endm
+//!   SUB E, A;
+//! ```
+//!
+//! A call replaces every operand naming a parameter with its argument, and
+//! renames the labels defined in the body so that each expansion gets its own.
+//! Bodies may call other macros, which are expanded in turn.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+  assembler::{Diagnostic, EXTENSION_OPS, LABEL_OPS, OP_MAP},
+  lexer::Span,
+  parser::{Operand, OperandKind, Statement, StatementKind},
+  source::SourceMap,
+};
+
+/// How deeply macros may call each other, to catch macros calling themselves.
+const MAX_DEPTH: usize = 64;
+
+struct Macro {
+  params: Vec<String>,
+  body: Vec<Statement>,
+}
+
+/// A macro call, with the span of the calling statement.
+pub type Call = (String, Span);
+
+/// A statement after expansion, along with the macro calls it came from,
+/// outermost first.
+pub struct Expanded {
+  pub statement: Statement,
+  pub calls: Vec<Call>,
+}
+
+/// Adds a note for every macro call leading to a diagnostic, innermost first.
+pub fn with_calls(mut diagnostic: Diagnostic, source: &SourceMap, calls: &[Call]) -> Diagnostic {
+  for (name, span) in calls.iter().rev() {
+    diagnostic.notes.push(Diagnostic::note(
+      source,
+      *span,
+      format!("In expansion of macro '{}'", name),
+    ));
+  }
+  diagnostic
+}
+
+fn is_directive(statement: &Statement, directive: &str) -> bool {
+  match &statement.kind {
+    StatementKind::Directive { name, .. } => name.text.eq_ignore_ascii_case(directive),
+    _ => false,
+  }
+}
+
+/// Name of the macro a statement calls, if any.
+fn call_name(statement: &Statement, macros: &HashMap<String, Macro>) -> Option<String> {
+  match &statement.kind {
+    StatementKind::Instruction { op, .. } => {
+      let name = op.text.to_uppercase();
+      if macros.contains_key(&name) {
+        Some(name)
+      } else {
+        None
+      }
+    }
+    _ => None,
+  }
+}
+
+struct Expander<'a> {
+  source: &'a SourceMap,
+  macros: HashMap<String, Macro>,
+  /// Number of expansions so far, which makes body labels unique.
+  expansions: usize,
+  statements: Vec<Expanded>,
+  errors: Vec<Diagnostic>,
+}
+
+impl<'a> Expander<'a> {
+  fn error(&mut self, span: Span, message: String, calls: &[Call]) {
+    let error = Diagnostic::error(self.source, span, message);
+    self.errors.push(with_calls(error, self.source, calls));
+  }
+
+  /// Reads the definition starting at `statements[i]`, returning the index of
+  /// the statement after its `.endm`.
+  fn define(&mut self, statements: &[Statement], i: usize) -> usize {
+    let header = &statements[i];
+    let end = statements[i + 1..]
+      .iter()
+      .position(|s| is_directive(s, ".endm") || is_directive(s, ".macro"))
+      .map(|e| i + 1 + e);
+    let args = match &header.kind {
+      StatementKind::Directive { args, .. } => args,
+      _ => unreachable!(),
+    };
+    let after = match end {
+      Some(end) if is_directive(&statements[end], ".endm") => end + 1,
+      Some(end) => {
+        self.error(
+          statements[end].span,
+          "Macros cannot be defined inside other macros".to_owned(),
+          &[],
+        );
+        // Skip up to the `.endm` of the outer macro.
+        statements[end..]
+          .iter()
+          .position(|s| is_directive(s, ".endm"))
+          .map_or(statements.len(), |e| end + e + 1)
+      }
+      None => {
+        self.error(header.span, "Missing .endm for macro".to_owned(), &[]);
+        statements.len()
+      }
+    };
+    let name = match args.first() {
+      Some(name) if name.kind == OperandKind::Name => name,
+      _ => {
+        self.error(
+          header.span,
+          "Expected a macro name after .macro".to_owned(),
+          &[],
+        );
+        return after;
+      }
+    };
+    let upper = name.text.to_uppercase();
+    // Macros may replace the operations added to classic VMAL, as programs
+    // written before them may define macros of the same name.
+    let is_op = OP_MAP
+      .get(upper.as_str())
+      .is_some_and(|op| !EXTENSION_OPS.contains(op));
+    if is_op {
+      self.error(
+        name.span,
+        format!("Macro '{}' has the same name as an operation", name.text),
+        &[],
+      );
+      return after;
+    }
+    if self.macros.contains_key(&upper) {
+      self.error(
+        name.span,
+        format!("Macro '{}' already defined", name.text),
+        &[],
+      );
+      return after;
+    }
+    let mut params = vec![];
+    for param in &args[1..] {
+      if param.kind != OperandKind::Name {
+        self.error(
+          param.span,
+          format!("Macro parameter is not a valid cname - '{}'", param.text),
+          &[],
+        );
+        return after;
+      }
+      if params.contains(&param.text) {
+        self.error(
+          param.span,
+          format!("Macro parameter '{}' already defined", param.text),
+          &[],
+        );
+        return after;
+      }
+      params.push(param.text.clone());
+    }
+    let body_end = match end {
+      Some(end) if is_directive(&statements[end], ".endm") => end,
+      _ => after,
+    };
+    let body = statements[i + 1..body_end].to_vec();
+    self.macros.insert(upper, Macro { params, body });
+    after
+  }
+
+  /// Expands a statement, calling the macros it uses.
+  fn statement(&mut self, statement: Statement, calls: &[Call]) {
+    let name = match call_name(&statement, &self.macros) {
+      Some(name) => name,
+      None => {
+        self.statements.push(Expanded {
+          statement,
+          calls: calls.to_vec(),
+        });
+        return;
+      }
+    };
+    let args = match &statement.kind {
+      StatementKind::Instruction { args, .. } => args,
+      _ => unreachable!(),
+    };
+    if calls.len() >= MAX_DEPTH {
+      self.error(
+        statement.span,
+        format!("Macro expansion is too deep, does '{}' call itself?", name),
+        calls,
+      );
+      return;
+    }
+    let Macro { params, body } = &self.macros[&name];
+    let (params, body) = (params.clone(), body.clone());
+    if args.len() != params.len() {
+      let message = format!(
+        "Wrong number of arguments for macro {} (expected {}, got {})",
+        name,
+        params.len(),
+        args.len()
+      );
+      self.error(statement.span, message, calls);
+      return;
+    }
+    self.expansions += 1;
+    let suffix = format!("@{}", self.expansions);
+    let locals = body
+      .iter()
+      .filter_map(|s| match &s.kind {
+        StatementKind::Instruction { op, args } if op.text.eq_ignore_ascii_case("LBL") => {
+          args.first().map(|a| a.text.clone())
+        }
+        _ => None,
+      })
+      .collect::<HashSet<_>>();
+    let substitute = |operand: &Operand, is_label: bool| -> Operand {
+      if operand.kind != OperandKind::Name {
+        return operand.clone();
+      }
+      if let Some(i) = params.iter().position(|p| *p == operand.text) {
+        return args[i].clone();
+      }
+      if is_label && locals.contains(&operand.text) {
+        return Operand {
+          text: format!("{}{}", operand.text, suffix),
+          ..operand.clone()
+        };
+      }
+      operand.clone()
+    };
+    let body = body
+      .iter()
+      .map(|s| {
+        let kind = match &s.kind {
+          StatementKind::RegisterInit { register, value } => StatementKind::RegisterInit {
+            register: substitute(register, false),
+            value: substitute(value, false),
+          },
+          StatementKind::MemoryInit { address, value } => StatementKind::MemoryInit {
+            address: substitute(address, false),
+            value: substitute(value, false),
+          },
+          StatementKind::Instruction { op, args } => {
+            let is_label = OP_MAP
+              .get(op.text.to_uppercase().as_str())
+              .is_some_and(|op| LABEL_OPS.contains(op));
+            StatementKind::Instruction {
+              op: op.clone(),
+              args: args.iter().map(|a| substitute(a, is_label)).collect(),
+            }
+          }
+          StatementKind::Directive { label, name, args } => StatementKind::Directive {
+            label: label.clone(),
+            name: name.clone(),
+            args: args.iter().map(|a| substitute(a, false)).collect(),
+          },
+        };
+        Statement { kind, span: s.span }
+      })
+      .collect::<Vec<_>>();
+    let mut calls = calls.to_vec();
+    calls.push((name, statement.span));
+    for s in body {
+      self.statement(s, &calls);
+    }
+  }
+}
+
+/// Expands every macro call in a program, removing the definitions.
+pub fn expand(source: &SourceMap, statements: Vec<Statement>) -> (Vec<Expanded>, Vec<Diagnostic>) {
+  let mut expander = Expander {
+    source,
+    macros: HashMap::new(),
+    expansions: 0,
+    statements: vec![],
+    errors: vec![],
+  };
+  let mut i = 0;
+  while i < statements.len() {
+    if is_directive(&statements[i], ".macro") {
+      i = expander.define(&statements, i);
+      continue;
+    }
+    if is_directive(&statements[i], ".endm") {
+      expander.error(
+        statements[i].span,
+        ".endm without a matching .macro".to_owned(),
+        &[],
+      );
+    } else {
+      expander.statement(statements[i].clone(), &[]);
+    }
+    i += 1;
+  }
+  (expander.statements, expander.errors)
+}
+
+#[test]
+fn test_macros() {
+  use crate::assembler::{Assembly, Instruction::*};
+
+  let source = "\
+.macro NEG r
+  NOT r, r;
+  ADD r, 6;
+.endm
+.macro SUB a, b
+  MV F, b;
+  NEG F;
+  ADD a, F;
+.endm
+.macro WAIT r
+LBL spin;
+  SF r;
+  BIN spin;
+.endm
+  SUB E, A;
+  WAIT E;
+  wait E;
+";
+  let a = Assembly::try_assemble(source).unwrap();
+  assert_eq!(
+    a.instructions,
+    vec![
+      MV(0xF, 0xA),
+      NOT(0xF, 0xF),
+      ADD(0xF, 6),
+      ADD(0xE, 0xF),
+      SF(0xE),
+      BIN(3),
+      SF(0xE),
+      BIN(5)
+    ]
+  );
+  assert_eq!(a.lines, vec![14, 14, 14, 14, 15, 15, 16, 16]);
+  assert!(a.labels.is_empty());
+
+  let (_, errors) = Assembly::check(".macro NEG r\n  NOT r, r;\n.endm\n  NEG Q;\n  NEG;\n.endm\n");
+  assert_eq!(
+    errors[0].to_string(),
+    "Error on line #4: Invalid register specifier 'Q'\n\t>  NEG Q;\n\t       ^\n\
+     Note on line #2: In the body of macro 'NEG'\n\t>  NOT r, r;\n\t   ^^^^^^^^^\n\
+     Note on line #4: In expansion of macro 'NEG'\n\t>  NEG Q;\n\t   ^^^^^^"
+  );
+  let messages = errors
+    .iter()
+    .map(|e| e.message.as_str())
+    .collect::<Vec<_>>();
+  assert_eq!(
+    messages[1..],
+    [
+      "Wrong number of arguments for macro NEG (expected 1, got 0)",
+      ".endm without a matching .macro"
+    ]
+  );
+
+  let (_, errors) =
+    Assembly::check(".macro LOOP\n  LOOP;\n.endm\nLOOP;\n.macro ADD\n.endm\n.macro X\n");
+  assert_eq!(
+    errors
+      .iter()
+      .map(|e| e.message.as_str())
+      .collect::<Vec<_>>(),
+    vec![
+      "Macro expansion is too deep, does 'LOOP' call itself?",
+      "Macro 'ADD' has the same name as an operation",
+      "Missing .endm for macro"
+    ]
+  );
+  assert_eq!(errors[0].notes.len(), MAX_DEPTH);
+}
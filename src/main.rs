@@ -1,24 +1,207 @@
-#![feature(str_split_once)]
-#![allow(non_snake_case)]
+use std::fs::File;
+use std::io::{stdout, BufReader, IsTerminal};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use structopt::{
+  clap::{AppSettings, Error, ErrorKind},
+  StructOpt,
+};
+use vmal::{
+  assembler, bus, cfg,
+  console::Console,
+  dap, formatter, gdb, lint, lsp,
+  memory::{Backend, BACKENDS, DENSE_LIMIT},
+  object,
+  source::SourceMap,
+  tui,
+  util::{self, print_code},
+  vm,
+};
 
-mod assembler;
-mod util;
-mod vm;
+#[derive(Debug, StructOpt)]
+#[structopt(
+  name = "vmal",
+  settings = &[AppSettings::SubcommandsNegateReqs, AppSettings::ArgsNegateSubcommands]
+)]
+struct Opt {
+  #[structopt(flatten)]
+  run: RunOpt,
 
-use std::path::PathBuf;
-use structopt::StructOpt;
-use util::print_code;
+  #[structopt(subcommand)]
+  cmd: Option<Command>,
+}
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "vmal")]
-struct Opt {
+enum Command {
+  /// Assemble and run a program (the default when no command is given)
+  Run(RunOpt),
+  /// Serve the Debug Adapter Protocol on stdin and stdout
+  Dap,
+  /// Serve the Language Server Protocol on stdin and stdout
+  Lsp,
+  /// Rewrite programs in the canonical layout
+  Fmt(FmtOpt),
+  /// Assemble programs and warn about likely mistakes in them
+  Check(CheckOpt),
+  /// Write the control-flow graph of a program in Graphviz DOT
+  Cfg(CfgOpt),
+  /// Assemble a file into a relocatable object, to be linked with others
+  Asm(AsmOpt),
+  /// Link objects into a program, which starts at the first instruction of
+  /// the first object
+  Link(LinkOpt),
+  /// Time a program with each way of storing memory, run by the interpreter
+  /// the debuggers use and by the faster one programs run with otherwise
+  Bench(BenchOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct FmtOpt {
+  /// Only check that the files are formatted, failing if any is not
+  #[structopt(long)]
+  check: bool,
+
+  /// Files to format
+  #[structopt(parse(from_os_str), required = true)]
+  files: Vec<PathBuf>,
+
+  /// Directory to search for included files, may be given more than once
+  #[structopt(
+    short = "I",
+    long = "include",
+    parse(from_os_str),
+    number_of_values = 1
+  )]
+  include_dirs: Vec<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+struct CfgOpt {
+  /// Input file
+  #[structopt(parse(from_os_str))]
+  input: PathBuf,
+
+  /// Output file, stdout if not present
+  #[structopt(short, long, parse(from_os_str))]
+  output: Option<PathBuf>,
+
+  /// Directory to search for included files, may be given more than once
+  #[structopt(
+    short = "I",
+    long = "include",
+    parse(from_os_str),
+    number_of_values = 1
+  )]
+  include_dirs: Vec<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+struct AsmOpt {
+  /// Input file
+  #[structopt(parse(from_os_str))]
+  input: PathBuf,
+
+  /// Output file, the input file with a .vmo extension if not present
+  #[structopt(short, long, parse(from_os_str))]
+  output: Option<PathBuf>,
+
+  /// Directory to search for included files, may be given more than once
+  #[structopt(
+    short = "I",
+    long = "include",
+    parse(from_os_str),
+    number_of_values = 1
+  )]
+  include_dirs: Vec<PathBuf>,
+
+  /// Reject the operations added to classic VMAL
+  #[structopt(long)]
+  strict: bool,
+
+  /// Number of bits in a word, for programs without a .width directive
+  #[structopt(long, possible_values = &["8", "16", "32", "64"])]
+  width: Option<u32>,
+}
+
+#[derive(Debug, StructOpt)]
+struct LinkOpt {
+  /// Objects to link
+  #[structopt(parse(from_os_str), required = true)]
+  inputs: Vec<PathBuf>,
+
+  /// Output file
+  #[structopt(short, long, parse(from_os_str))]
+  output: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+struct BenchOpt {
+  /// Input file
+  #[structopt(parse(from_os_str))]
+  input: PathBuf,
+
+  /// Number of times to run the program each way
+  #[structopt(long, default_value = "5")]
+  runs: u32,
+
+  /// Directory to search for included files, may be given more than once
+  #[structopt(
+    short = "I",
+    long = "include",
+    parse(from_os_str),
+    number_of_values = 1
+  )]
+  include_dirs: Vec<PathBuf>,
+
+  /// Number of words of memory, in decimal or 0x hexadecimal, for every
+  /// backend [default: as many as dense memory can hold]
+  #[structopt(long, parse(try_from_str = parse_memory_size), value_name = "words")]
+  memory_size: Option<isize>,
+
+  /// Map a console, whose output is thrown away, and a timer to the top of
+  /// memory
+  #[structopt(long)]
+  devices: bool,
+
+  /// Number of bits in a word, for programs without a .width directive
+  #[structopt(long, possible_values = &["8", "16", "32", "64"])]
+  width: Option<u32>,
+}
+
+#[derive(Debug, StructOpt)]
+struct CheckOpt {
+  /// Files to check
+  #[structopt(parse(from_os_str), required = true)]
+  files: Vec<PathBuf>,
+
+  /// Directory to search for included files, may be given more than once
+  #[structopt(
+    short = "I",
+    long = "include",
+    parse(from_os_str),
+    number_of_values = 1
+  )]
+  include_dirs: Vec<PathBuf>,
+
+  /// Reject the operations added to classic VMAL
+  #[structopt(long)]
+  strict: bool,
+
+  /// Number of bits in a word, for programs without a .width directive
+  #[structopt(long, possible_values = &["8", "16", "32", "64"])]
+  width: Option<u32>,
+}
+
+#[derive(Debug, StructOpt)]
+struct RunOpt {
   /// Activate debug mode
   #[structopt(short, long)]
   debug: bool,
 
   /// Input file
   #[structopt(parse(from_os_str))]
-  input: PathBuf,
+  input: Option<PathBuf>,
 
   /// Use unsigned-integers
   #[structopt(short, long)]
@@ -27,10 +210,108 @@ struct Opt {
   /// Show binary representation of numbers
   #[structopt(short, long)]
   binary: bool,
+
+  /// Run the debugger with commands read from a file instead of stdin
+  #[structopt(long, parse(from_os_str))]
+  debug_script: Option<PathBuf>,
+
+  /// Use the plain line-by-line debugger instead of the full-screen one
+  #[structopt(long)]
+  no_ansi: bool,
+
+  /// File the program reads from the console, instead of stdin
+  #[structopt(long = "input", parse(from_os_str), value_name = "file")]
+  console_input: Option<PathBuf>,
+
+  /// Map a console to the last two addresses of memory and a timer below it
+  #[structopt(long, conflicts_with = "machine")]
+  devices: bool,
+
+  /// JSON file describing the devices of the machine and their addresses,
+  /// instead of RAM alone
+  #[structopt(long, parse(from_os_str), value_name = "file")]
+  machine: Option<PathBuf>,
+
+  /// Number of words of memory, in decimal or 0x hexadecimal, instead of
+  /// every address a word can hold
+  #[structopt(
+    long,
+    parse(try_from_str = parse_memory_size),
+    value_name = "words",
+    conflicts_with = "address-bits"
+  )]
+  memory_size: Option<isize>,
+
+  /// Number of bits of an address that reach memory, which then has 2^bits
+  /// words
+  #[structopt(long, value_name = "bits")]
+  address_bits: Option<u32>,
+
+  /// Wrap addresses past the end of memory around to its start, instead of
+  /// faulting
+  #[structopt(long)]
+  wrap_addresses: bool,
+
+  /// How memory is stored: sparse suits any addresses, paged is faster for
+  /// the low addresses programs usually use, and dense is fastest but needs
+  /// memory of a known size
+  #[structopt(
+    long,
+    possible_values = &BACKENDS,
+    default_value = "paged",
+    value_name = "backend"
+  )]
+  memory: Backend,
+
+  /// What reading a word of memory that was never written does
+  #[structopt(
+    long,
+    possible_values = &["allow", "warn", "fault"],
+    default_value = "allow",
+    value_name = "action"
+  )]
+  unwritten: vm::UnwrittenReads,
+
+  /// Wait for a GDB remote protocol connection on this address and let it
+  /// control the program
+  #[structopt(long, value_name = "address")]
+  gdb: Option<String>,
+
+  /// Directory to search for included files, may be given more than once
+  #[structopt(
+    short = "I",
+    long = "include",
+    parse(from_os_str),
+    number_of_values = 1
+  )]
+  include_dirs: Vec<PathBuf>,
+
+  /// Reject the operations added to classic VMAL
+  #[structopt(long)]
+  strict: bool,
+
+  /// Number of bits in a word, for programs without a .width directive
+  #[structopt(long, possible_values = &["8", "16", "32", "64"])]
+  width: Option<u32>,
 }
 
 fn main() {
   let opt = Opt::from_args();
+  match opt.cmd {
+    None => run(opt.run),
+    Some(Command::Run(run_opt)) => run(run_opt),
+    Some(Command::Dap) => dap::serve().unwrap(),
+    Some(Command::Lsp) => lsp::serve().unwrap(),
+    Some(Command::Fmt(fmt_opt)) => fmt(fmt_opt),
+    Some(Command::Check(check_opt)) => check(check_opt),
+    Some(Command::Cfg(cfg_opt)) => cfg(cfg_opt),
+    Some(Command::Asm(asm_opt)) => asm(asm_opt),
+    Some(Command::Link(link_opt)) => link(link_opt),
+    Some(Command::Bench(bench_opt)) => bench(bench_opt),
+  }
+}
+
+fn run(opt: RunOpt) {
   {
     let mut w = util::SHOULD_USE_UNSIGNED_INT.write().unwrap();
     *w = opt.unsigned;
@@ -39,18 +320,397 @@ fn main() {
     let mut w = util::SHOULD_SHOW_BINARY.write().unwrap();
     *w = opt.binary;
   }
-  let file = std::fs::read_to_string(opt.input).unwrap();
-  let assembly = assembler::Assembly::assemble(file);
-  let mut vm = vm::VM::new(assembly.reg_inits, assembly.mem_inits);
-  if !opt.debug {
-    vm.run_code(&assembly.instructions);
+  {
+    let mut w = util::SHOULD_USE_ANSI.write().unwrap();
+    *w = !opt.no_ansi && opt.debug_script.is_none();
+  }
+  let settings = assembler::Settings {
+    strict: opt.strict,
+    width: opt.width,
+  };
+  let input = match opt.input {
+    Some(input) => input,
+    None => Error::with_description(
+      "The following required arguments were not provided:\n    <input>",
+      ErrorKind::MissingRequiredArgument,
+    )
+    .exit(),
+  };
+  let assembly = if input.extension().is_some_and(|e| e == "vmo") {
+    read_object(&input, opt.width)
+  } else {
+    let sources = read_sources(&input, &opt.include_dirs);
+    let assembly = assembler::Assembly::assemble(sources, settings);
+    let unlinked = assembly.unlinked();
+    if !unlinked.is_empty() {
+      for err in unlinked {
+        println!("{}", err);
+      }
+      std::process::exit(1);
+    }
+    assembly
+  };
+  let (reg_inits, mem_inits) = (assembly.reg_inits.clone(), assembly.mem_inits.clone());
+  let width = assembly.width;
+  let size = match opt.address_bits {
+    Some(bits) if bits > width => {
+      println!(
+        "Error: --address-bits {} is more than a {}-bit word holds",
+        bits, width
+      );
+      std::process::exit(1);
+    }
+    Some(bits) if bits < width => Some(1 << bits),
+    _ => opt.memory_size,
+  };
+  // The standard machine is only built here if its memory is limited or it
+  // has devices.
+  let bus = match (&opt.machine, size) {
+    (Some(path), size) => bus::Bus::from_file(path, width).and_then(|mut bus| {
+      if let Some(size) = size {
+        bus.set_size(size, width)?;
+      }
+      Ok(Some(bus))
+    }),
+    (None, Some(size)) => bus::Bus::with_size(width, size).map(Some),
+    (None, None) if opt.devices => Ok(Some(bus::Bus::new())),
+    (None, None) => Ok(None),
+  };
+  let (devices, wrap) = (opt.devices, opt.wrap_addresses);
+  let vm = bus.and_then(|bus| match bus {
+    Some(mut bus) => {
+      // The devices go at the top of memory, so its size has to be known.
+      if devices {
+        bus.map_devices(width);
+      }
+      bus.wrap |= wrap;
+      vm::VM::with_bus(width, bus, reg_inits, mem_inits)
+    }
+    None => Ok(vm::VM::with_width(width, reg_inits, mem_inits)),
+  });
+  let mut vm = match vm {
+    Ok(vm) => vm,
+    Err(err) => {
+      println!("Error: {}", err);
+      std::process::exit(1);
+    }
+  };
+  if let Err(err) = vm.memory.set_backend(opt.memory, width) {
+    println!("Error: {}", err);
+    std::process::exit(1);
+  }
+  vm.unwritten_reads = opt.unwritten;
+  vm.start_stack(&assembly.instructions);
+  let use_tui = opt.debug
+    && opt.debug_script.is_none()
+    && *util::SHOULD_USE_ANSI.read().unwrap()
+    && stdout().is_terminal();
+  if let Some(console) = vm.memory.device_mut::<Console>() {
+    if let Some(path) = &opt.console_input {
+      match File::open(path) {
+        Ok(file) => console.set_input(Box::new(BufReader::new(file))),
+        Err(err) => {
+          println!("Error: Cannot read {}: {}", path.display(), err);
+          std::process::exit(1);
+        }
+      }
+    } else if use_tui {
+      // The full-screen debugger takes the keyboard, so the program gets no
+      // input.
+      console.set_input(Box::new(std::io::empty()));
+    }
+  }
+  if let Some(address) = opt.gdb {
+    let listener = TcpListener::bind(&address).unwrap();
+    println!("Waiting for GDB on {}", listener.local_addr().unwrap());
+    let (stream, peer) = listener.accept().unwrap();
+    println!("GDB connected from {}", peer);
+    gdb::serve(stream, &mut vm, &assembly.instructions).unwrap();
+  } else if let Some(script) = opt.debug_script {
+    let script = std::fs::File::open(script).unwrap();
+    println!("\nAssembled Code:");
+    print_code(&assembly.instructions);
+    vm.run_debug_with(&assembly.instructions, &mut BufReader::new(script), true);
+  } else if !opt.debug {
+    vm.run_fast(&assembly.instructions);
+  } else if use_tui {
+    tui::run_tui(&mut vm, &assembly);
   } else {
     println!("\nAssembled Code:");
     print_code(&assembly.instructions);
     vm.run_debug(&assembly.instructions);
   }
   vm.print_registers();
-  if vm.memory.len() > 0 {
+  if !vm.memory.cells().is_empty() {
     vm.print_memory();
   }
+  for (pc, warning) in &vm.warnings {
+    println!();
+    println!(
+      "Warning: {} at instruction {}: {}",
+      warning,
+      pc,
+      util::op_to_string(&assembly.instructions[*pc as usize])
+    );
+  }
+  if let Some(fault) = vm.fault {
+    let pc = vm.registers[0];
+    println!();
+    println!(
+      "{} at instruction {}: {}",
+      fault,
+      pc,
+      util::op_to_string(&assembly.instructions[pc as usize])
+    );
+  }
+  std::process::exit(vm.exit_status());
+}
+
+fn bench(opt: BenchOpt) {
+  let settings = assembler::Settings {
+    width: opt.width,
+    ..assembler::Settings::default()
+  };
+  let sources = read_sources(&opt.input, &opt.include_dirs);
+  let assembly = match assembler::Assembly::try_assemble_with(sources, settings) {
+    Ok(assembly) => assembly,
+    Err(errors) => {
+      for err in errors {
+        println!("{}", err);
+      }
+      std::process::exit(1);
+    }
+  };
+  let width = assembly.width;
+  // Every backend gets the same memory, so dense memory has to hold it.
+  let size = opt.memory_size.unwrap_or_else(|| {
+    let size = match width {
+      64 => DENSE_LIMIT,
+      _ => DENSE_LIMIT.min(1 << width),
+    };
+    println!("Memory size: {:#X} words", size);
+    size
+  });
+  // Every run has to end with the same registers and memory as the first.
+  let mut expected = None;
+  println!(
+    "{:<8} {:<12} {:>12} {:>12}",
+    "memory", "interpreter", "best", "mean"
+  );
+  let runs = [Backend::Sparse, Backend::Paged, Backend::Dense]
+    .iter()
+    .flat_map(|backend| [(*backend, false), (*backend, true)]);
+  for (backend, fast) in runs {
+    let interpreter = if fast { "decoded" } else { "step" };
+    let mut times = vec![];
+    for _ in 0..opt.runs {
+      let vm = bus::Bus::with_size(width, size).and_then(|mut bus| {
+        if opt.devices {
+          bus.map_devices(width);
+        }
+        let (reg_inits, mem_inits) = (assembly.reg_inits.clone(), assembly.mem_inits.clone());
+        let mut vm = vm::VM::with_bus(width, bus, reg_inits, mem_inits)?;
+        vm.memory.set_backend(backend, width)?;
+        vm.start_stack(&assembly.instructions);
+        Ok(vm)
+      });
+      let mut vm = match vm {
+        Ok(vm) => vm,
+        Err(err) => {
+          println!("{:<8} {:<12} {}", backend, interpreter, err);
+          break;
+        }
+      };
+      if let Some(console) = vm.memory.device_mut::<Console>() {
+        console.set_input(Box::new(std::io::empty()));
+        console.capture();
+      }
+      let start = Instant::now();
+      if fast {
+        vm.run_fast(&assembly.instructions);
+      } else {
+        vm.run_code(&assembly.instructions);
+      }
+      times.push(start.elapsed());
+      let state = (vm.registers, vm.fault, vm.memory.cells());
+      if *expected.get_or_insert_with(|| state.clone()) != state {
+        println!(
+          "Error: the program ends differently with {} memory and the {} interpreter",
+          backend, interpreter
+        );
+        std::process::exit(1);
+      }
+    }
+    if let Some(best) = times.iter().min() {
+      let mean = times.iter().sum::<Duration>() / times.len() as u32;
+      println!(
+        "{:<8} {:<12} {:>12.3?} {:>12.3?}",
+        backend, interpreter, best, mean
+      );
+    }
+  }
+}
+
+fn parse_memory_size(s: &str) -> Result<isize, String> {
+  bus::parse_number(s).ok_or_else(|| format!("Invalid number of words: {}", s))
+}
+
+fn fmt(opt: FmtOpt) {
+  let mut failed = false;
+  for path in opt.files {
+    let sources = read_sources(&path, &opt.include_dirs);
+    let source = sources.main().to_owned();
+    let formatted = match formatter::format(sources) {
+      Ok(formatted) => formatted,
+      Err(errors) => {
+        println!("{}:", path.display());
+        for err in errors {
+          println!("{}", err);
+        }
+        failed = true;
+        continue;
+      }
+    };
+    if formatted == source {
+      continue;
+    }
+    if opt.check {
+      println!("{} is not formatted", path.display());
+      failed = true;
+    } else {
+      std::fs::write(&path, formatted).unwrap();
+    }
+  }
+  if failed {
+    std::process::exit(1);
+  }
+}
+
+fn check(opt: CheckOpt) {
+  let settings = assembler::Settings {
+    strict: opt.strict,
+    width: opt.width,
+  };
+  let mut failed = false;
+  for path in opt.files {
+    let sources = read_sources(&path, &opt.include_dirs);
+    let (assembly, mut diagnostics) = assembler::Assembly::check_with(sources, settings);
+    if diagnostics.is_empty() {
+      diagnostics.extend(lint::lint(&assembly));
+    } else {
+      failed = true;
+    }
+    diagnostics.extend(assembly.warnings);
+    if diagnostics.is_empty() {
+      continue;
+    }
+    diagnostics.sort_by(|a, b| (&a.file, a.line, a.columns).cmp(&(&b.file, b.line, b.columns)));
+    println!("{}:", path.display());
+    for diagnostic in diagnostics {
+      println!("{}", diagnostic);
+    }
+  }
+  if failed {
+    std::process::exit(1);
+  }
+}
+
+fn cfg(opt: CfgOpt) {
+  let sources = read_sources(&opt.input, &opt.include_dirs);
+  // Warnings are left out, since the graph may be written to stdout.
+  let assembly = match assembler::Assembly::try_assemble(sources) {
+    Ok(assembly) => assembly,
+    Err(errors) => {
+      for err in errors {
+        println!("{}", err);
+      }
+      std::process::exit(1);
+    }
+  };
+  let name = opt
+    .input
+    .file_name()
+    .map(|n| n.to_string_lossy())
+    .unwrap_or_default();
+  let dot = cfg::to_dot(&name, &assembly);
+  match opt.output {
+    Some(output) => std::fs::write(output, dot).unwrap(),
+    None => print!("{}", dot),
+  }
+}
+
+/// Reads a program, to be assembled along with the files it includes.
+fn read_sources(path: &Path, include_dirs: &[PathBuf]) -> SourceMap {
+  let text = std::fs::read_to_string(path).unwrap();
+  let mut sources = SourceMap::from_path(path, text);
+  sources.include_dirs = include_dirs.to_vec();
+  sources
+}
+
+/// Reads a linked object to run, which must have words of the given width.
+fn read_object(path: &Path, width: Option<u32>) -> assembler::Assembly {
+  let object = match object::Object::read(path) {
+    Ok(object) => object,
+    Err(err) => {
+      println!("Error: {}", err);
+      std::process::exit(1);
+    }
+  };
+  if let Some(width) = width {
+    if width != object.width {
+      println!(
+        "Error: {} has {}-bit words, not {}",
+        path.display(),
+        object.width,
+        width
+      );
+      std::process::exit(1);
+    }
+  }
+  let externs = object.externs();
+  if !externs.is_empty() {
+    println!(
+      "Error: {} uses labels of other objects, link it with `vmal link` to run it: {}",
+      path.display(),
+      externs.join(", ")
+    );
+    std::process::exit(1);
+  }
+  object.into_assembly()
+}
+
+fn asm(opt: AsmOpt) {
+  let settings = assembler::Settings {
+    strict: opt.strict,
+    width: opt.width,
+  };
+  let sources = read_sources(&opt.input, &opt.include_dirs);
+  let assembly = assembler::Assembly::assemble(sources, settings);
+  let output = match opt.output {
+    Some(output) => output,
+    None => opt.input.with_extension("vmo"),
+  };
+  object::Object::new(&assembly).write(&output).unwrap();
+}
+
+fn link(opt: LinkOpt) {
+  let mut objects = vec![];
+  for path in &opt.inputs {
+    match object::Object::read(path) {
+      Ok(object) => objects.push((path.display().to_string(), object)),
+      Err(err) => {
+        println!("Error: {}", err);
+        std::process::exit(1);
+      }
+    }
+  }
+  match object::link(&objects) {
+    Ok(linked) => linked.write(&opt.output).unwrap(),
+    Err(errors) => {
+      for err in errors {
+        println!("Error: {}", err);
+      }
+      std::process::exit(1);
+    }
+  }
 }
@@ -0,0 +1,277 @@
+//! Ways of storing the words of RAM and ROM.
+//!
+//! Sparse memory keeps the words written in a hash map, which suits any
+//! address space. Paged memory allocates pages of words as they are written,
+//! found through a table covering the low addresses programs use most, and
+//! keeps words above those in a sparse map. Dense memory holds every word of a
+//! memory of known size in one vector.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Storage for words, every one of which is unwritten to begin with.
+pub trait Memory: Send + fmt::Debug {
+  /// The word at an address, or None if it was never written.
+  fn get(&self, addr: isize) -> Option<isize>;
+  /// Writes a word, doing nothing if the address is outside the memory.
+  fn set(&mut self, addr: isize, val: isize);
+  /// Every word written, by address.
+  fn cells(&self) -> Vec<(isize, isize)>;
+}
+
+/// A kind of memory, chosen when the machine is built.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Backend {
+  Sparse,
+  #[default]
+  Paged,
+  Dense,
+}
+
+/// Names of the backends, as they are given on the command line.
+pub const BACKENDS: [&str; 3] = ["sparse", "paged", "dense"];
+
+/// Most words dense memory can hold.
+pub const DENSE_LIMIT: isize = 1 << 24;
+
+impl Backend {
+  /// Memory of this kind for the addresses below `size`, or any address if
+  /// `size` is None.
+  pub fn memory(self, size: Option<isize>) -> Result<Box<dyn Memory>, String> {
+    Ok(match (self, size) {
+      (Backend::Sparse, _) => Box::new(Sparse::default()),
+      (Backend::Paged, _) => Box::new(Paged::default()),
+      (Backend::Dense, Some(size)) if size <= DENSE_LIMIT => Box::new(Dense::new(size)),
+      (Backend::Dense, Some(size)) => {
+        return Err(format!(
+          "Dense memory holds at most {:#X} words, not {:#X}",
+          DENSE_LIMIT, size
+        ))
+      }
+      (Backend::Dense, None) => {
+        return Err("Dense memory needs a memory size for words this wide".to_owned())
+      }
+    })
+  }
+}
+
+impl FromStr for Backend {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "sparse" => Ok(Backend::Sparse),
+      "paged" => Ok(Backend::Paged),
+      "dense" => Ok(Backend::Dense),
+      _ => Err(format!("Expected sparse, paged or dense, not {}", s)),
+    }
+  }
+}
+
+impl fmt::Display for Backend {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.pad(BACKENDS[*self as usize])
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct Sparse {
+  words: HashMap<isize, isize>,
+}
+
+impl Memory for Sparse {
+  fn get(&self, addr: isize) -> Option<isize> {
+    self.words.get(&addr).copied()
+  }
+  fn set(&mut self, addr: isize, val: isize) {
+    self.words.insert(addr, val);
+  }
+  fn cells(&self) -> Vec<(isize, isize)> {
+    let mut cells = self.words.iter().map(|(a, v)| (*a, *v)).collect::<Vec<_>>();
+    cells.sort_unstable();
+    cells
+  }
+}
+
+const PAGE_BITS: u32 = 10;
+const PAGE_SIZE: usize = 1 << PAGE_BITS;
+/// Pages the page table covers, from address 0.
+const TABLE_PAGES: usize = 1 << 16;
+
+/// Words of one page, with a bit for each saying whether it was written.
+struct Page {
+  words: [isize; PAGE_SIZE],
+  written: [u64; PAGE_SIZE / 64],
+}
+
+impl Page {
+  fn get(&self, i: usize) -> Option<isize> {
+    (self.written[i / 64] & 1 << (i % 64) != 0).then_some(self.words[i])
+  }
+}
+
+#[derive(Default)]
+pub struct Paged {
+  /// Pages by number, None until a word of them is written.
+  table: Vec<Option<Box<Page>>>,
+  /// Words outside the pages the table covers.
+  far: Sparse,
+}
+
+impl Paged {
+  /// The page number and index into it of an address the table covers.
+  fn locate(addr: isize) -> Option<(usize, usize)> {
+    let page = addr as usize >> PAGE_BITS;
+    (addr >= 0 && page < TABLE_PAGES).then_some((page, addr as usize % PAGE_SIZE))
+  }
+}
+
+impl Memory for Paged {
+  fn get(&self, addr: isize) -> Option<isize> {
+    match Paged::locate(addr) {
+      Some((page, i)) => self.table.get(page)?.as_ref()?.get(i),
+      None => self.far.get(addr),
+    }
+  }
+  fn set(&mut self, addr: isize, val: isize) {
+    let (page, i) = match Paged::locate(addr) {
+      Some(location) => location,
+      None => return self.far.set(addr, val),
+    };
+    if page >= self.table.len() {
+      self.table.resize_with(page + 1, || None);
+    }
+    let page = self.table[page].get_or_insert_with(|| {
+      Box::new(Page {
+        words: [0; PAGE_SIZE],
+        written: [0; PAGE_SIZE / 64],
+      })
+    });
+    page.words[i] = val;
+    page.written[i / 64] |= 1 << (i % 64);
+  }
+  fn cells(&self) -> Vec<(isize, isize)> {
+    let paged = self.table.iter().enumerate().flat_map(|(n, page)| {
+      page.iter().flat_map(move |page| {
+        (0..PAGE_SIZE)
+          .filter_map(move |i| page.get(i).map(|val| ((n * PAGE_SIZE + i) as isize, val)))
+      })
+    });
+    // Far words are either above every page or negative, below them.
+    let far = self.far.cells();
+    let split = far.partition_point(|(addr, _)| *addr < 0);
+    far[..split]
+      .iter()
+      .copied()
+      .chain(paged)
+      .chain(far[split..].iter().copied())
+      .collect()
+  }
+}
+
+impl fmt::Debug for Paged {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let pages = self.table.iter().filter(|page| page.is_some()).count();
+    f.debug_struct("Paged")
+      .field("pages", &pages)
+      .field("far", &self.far)
+      .finish()
+  }
+}
+
+#[derive(Debug)]
+pub struct Dense {
+  words: Vec<isize>,
+  /// A bit for each word saying whether it was written.
+  written: Vec<u64>,
+}
+
+impl Dense {
+  /// Memory for the addresses below `size`.
+  pub fn new(size: isize) -> Self {
+    let size = size as usize;
+    Dense {
+      words: vec![0; size],
+      written: vec![0; size.div_ceil(64)],
+    }
+  }
+}
+
+impl Memory for Dense {
+  fn get(&self, addr: isize) -> Option<isize> {
+    // Negative addresses become too large to be in memory.
+    let i = addr as usize;
+    (i < self.words.len() && self.written[i / 64] & 1 << (i % 64) != 0).then(|| self.words[i])
+  }
+  fn set(&mut self, addr: isize, val: isize) {
+    let i = addr as usize;
+    if i < self.words.len() {
+      self.words[i] = val;
+      self.written[i / 64] |= 1 << (i % 64);
+    }
+  }
+  fn cells(&self) -> Vec<(isize, isize)> {
+    // Skips 64 words at a time where none were written.
+    let written = self
+      .written
+      .iter()
+      .enumerate()
+      .filter(|(_, bits)| **bits != 0);
+    written
+      .flat_map(|(n, bits)| {
+        (0..64)
+          .filter(move |bit| bits & 1 << bit != 0)
+          .map(move |bit| n * 64 + bit)
+      })
+      .map(|i| (i as isize, self.words[i]))
+      .collect()
+  }
+}
+
+#[test]
+fn test_memory() {
+  for backend in [Backend::Sparse, Backend::Paged, Backend::Dense] {
+    let mut memory = backend.memory(Some(0x10000)).unwrap();
+    memory.set(0xFFFF, 3);
+    memory.set(5, 0);
+    memory.set(1030, -1);
+    memory.set(5, 2);
+    assert_eq!(
+      (memory.get(5), memory.get(6), memory.get(0xFFFF)),
+      (Some(2), None, Some(3)),
+      "{}",
+      backend
+    );
+    assert_eq!(
+      memory.cells(),
+      vec![(5, 2), (1030, -1), (0xFFFF, 3)],
+      "{}",
+      backend
+    );
+  }
+
+  let mut paged = Paged::default();
+  paged.set(isize::MAX, 1);
+  paged.set(-4, 2);
+  paged.set(7, 3);
+  assert_eq!(paged.get(isize::MAX), Some(1));
+  assert_eq!(paged.cells(), vec![(-4, 2), (7, 3), (isize::MAX, 1)]);
+  assert_eq!(paged.table.len(), 1);
+
+  let mut dense = Dense::new(0x100);
+  dense.set(0x200, 1);
+  dense.set(-1, 2);
+  dense.set(0xFF, 3);
+  assert_eq!((dense.get(0x200), dense.get(-1)), (None, None));
+  assert_eq!(dense.cells(), vec![(0xFF, 3)]);
+
+  assert_eq!(
+    Backend::Dense.memory(None).unwrap_err(),
+    "Dense memory needs a memory size for words this wide"
+  );
+  assert_eq!(
+    Backend::Dense.memory(Some(1 << 32)).unwrap_err(),
+    "Dense memory holds at most 0x1000000 words, not 0x100000000"
+  );
+  assert_eq!("paged".parse(), Ok(Backend::Paged));
+}
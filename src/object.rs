@@ -0,0 +1,821 @@
+//! Relocatable objects, which let the files of a program be assembled on their
+//! own and linked together afterwards.
+//!
+//! An object holds the instructions and initializers of one file, the labels
+//! it exports with `.global`, and a relocation for every branch and every use
+//! of the address of a data label. Branches within the object move along with
+//! it when it is placed after other objects, and branches to labels declared
+//! `.extern` are pointed at the object exporting the label. The words laid
+//! out by data directives are likewise placed after the data of the objects
+//! before, and addresses of data labels moved or pointed at the data of
+//! another object. Other memory initializers hold absolute addresses, so they
+//! stay where they are, and linking fails if one lands in the data of another
+//! object or two objects give the same location different values.
+//!
+//! Objects are stored as JSON in `.vmo` files.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+use crate::{
+  assembler::{Assembly, Fixup, Instruction},
+  lexer::Span,
+  source::SourceMap,
+  vm::{DEFAULT_WIDTH, WIDTHS},
+};
+
+/// Version of the `.vmo` format, increased whenever old files can no longer
+/// be read.
+const VERSION: u64 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Relocation {
+  /// The branch at an index jumps to an instruction of the same object.
+  Code(usize),
+  /// The branch at an index jumps to a label of another object.
+  Extern(usize, String),
+  /// The value of a fixup is an address in the data of the same object.
+  Data(Fixup),
+  /// The value of a fixup is the address of a data label of another object.
+  ExternData(Fixup, String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Object {
+  /// Number of bits in a word, which every linked object must agree on.
+  pub width: u32,
+  pub instructions: Vec<Instruction>,
+  pub reg_inits: Vec<(isize, isize)>,
+  pub mem_inits: Vec<(isize, isize)>,
+  /// Index in `mem_inits` of every word laid out by a data directive, whose
+  /// address is relative to where the data of the object is placed.
+  pub data: Vec<usize>,
+  /// Number of words the data of the object takes up.
+  pub data_size: isize,
+  /// Labels exported with `.global`, with the index of the instruction they
+  /// name.
+  pub symbols: Vec<(String, usize)>,
+  /// Data labels exported with `.global`, with their address in the data of
+  /// the object.
+  pub data_symbols: Vec<(String, isize)>,
+  pub relocations: Vec<Relocation>,
+}
+
+/// A label exported by an object once the objects are placed.
+#[derive(Clone, Copy)]
+enum Symbol {
+  Code(usize),
+  Data(isize),
+}
+
+fn is_branch(instruction: Option<&Instruction>) -> bool {
+  matches!(
+    instruction,
+    Some(Instruction::GO(_))
+      | Some(Instruction::BIN(_))
+      | Some(Instruction::BIZ(_))
+      | Some(Instruction::BIC(_))
+      | Some(Instruction::BIV(_))
+      | Some(Instruction::CALL(_))
+      | Some(Instruction::SIV(_))
+  )
+}
+
+/// A fixup as its kind and index.
+fn encode_fixup(fixup: &Fixup) -> (&'static str, usize) {
+  match *fixup {
+    Fixup::Register(i) => ("register", i),
+    Fixup::Memory(i) => ("memory", i),
+    Fixup::Instruction(i) => ("instruction", i),
+  }
+}
+
+/// An instruction as its operation number, from `OP_MAP`, and its operands.
+fn encode(instruction: &Instruction) -> Value {
+  match *instruction {
+    Instruction::SA(a) => json!([0, a]),
+    Instruction::RB(a) => json!([1, a]),
+    Instruction::RD => json!([2]),
+    Instruction::WR => json!([3]),
+    Instruction::SB(a) => json!([4, a]),
+    Instruction::SF(a) => json!([5, a]),
+    Instruction::GO(t) => json!([6, t]),
+    Instruction::BIN(t) => json!([7, t]),
+    Instruction::BIZ(t) => json!([8, t]),
+    Instruction::BIC(t) => json!([28, t]),
+    Instruction::BIV(t) => json!([29, t]),
+    Instruction::ADD(a, b) => json!([9, a, b]),
+    Instruction::AND(a, b) => json!([10, a, b]),
+    Instruction::MV(a, b) => json!([11, a, b]),
+    Instruction::NOT(a, b) => json!([12, a, b]),
+    Instruction::RS(a, b) => json!([13, a, b]),
+    Instruction::LS(a, b) => json!([14, a, b]),
+    Instruction::SW(a, b) => json!([15, a, b]),
+    Instruction::PRINT => json!([16]),
+    Instruction::LI(a, v) => json!([17, a, v]),
+    Instruction::CALL(t) => json!([18, t]),
+    Instruction::RET => json!([19]),
+    Instruction::PUSH(a) => json!([20, a]),
+    Instruction::POP(a) => json!([21, a]),
+    Instruction::SUB(a, b) => json!([22, a, b]),
+    Instruction::OR(a, b) => json!([23, a, b]),
+    Instruction::XOR(a, b) => json!([24, a, b]),
+    Instruction::ASR(a, b) => json!([25, a, b]),
+    Instruction::ROL(a, b) => json!([26, a, b]),
+    Instruction::ROR(a, b) => json!([27, a, b]),
+    Instruction::HALT(a) => json!([30, a]),
+    Instruction::EI => json!([31]),
+    Instruction::DI => json!([32]),
+    Instruction::SIV(t) => json!([33, t]),
+    Instruction::RTI => json!([34]),
+  }
+}
+
+fn decode(value: &Value) -> Option<Instruction> {
+  let fields = value
+    .as_array()?
+    .iter()
+    .map(|f| f.as_i64().map(|f| f as isize))
+    .collect::<Option<Vec<_>>>()?;
+  let instruction = match *fields.as_slice() {
+    [0, a] => Instruction::SA(a),
+    [1, a] => Instruction::RB(a),
+    [2] => Instruction::RD,
+    [3] => Instruction::WR,
+    [4, a] => Instruction::SB(a),
+    [5, a] => Instruction::SF(a),
+    [6, t] => Instruction::GO(t),
+    [7, t] => Instruction::BIN(t),
+    [8, t] => Instruction::BIZ(t),
+    [28, t] => Instruction::BIC(t),
+    [29, t] => Instruction::BIV(t),
+    [9, a, b] => Instruction::ADD(a, b),
+    [10, a, b] => Instruction::AND(a, b),
+    [11, a, b] => Instruction::MV(a, b),
+    [12, a, b] => Instruction::NOT(a, b),
+    [13, a, b] => Instruction::RS(a, b),
+    [14, a, b] => Instruction::LS(a, b),
+    [15, a, b] => Instruction::SW(a, b),
+    [16] => Instruction::PRINT,
+    [17, a, v] => Instruction::LI(a, v),
+    [18, t] => Instruction::CALL(t),
+    [19] => Instruction::RET,
+    [20, a] => Instruction::PUSH(a),
+    [21, a] => Instruction::POP(a),
+    [22, a, b] => Instruction::SUB(a, b),
+    [23, a, b] => Instruction::OR(a, b),
+    [24, a, b] => Instruction::XOR(a, b),
+    [25, a, b] => Instruction::ASR(a, b),
+    [26, a, b] => Instruction::ROL(a, b),
+    [27, a, b] => Instruction::ROR(a, b),
+    [30, a] => Instruction::HALT(a),
+    [31] => Instruction::EI,
+    [32] => Instruction::DI,
+    [33, t] => Instruction::SIV(t),
+    [34] => Instruction::RTI,
+    _ => return None,
+  };
+  Some(instruction)
+}
+
+impl Object {
+  /// The object of an assembled file.
+  pub fn new(assembly: &Assembly) -> Self {
+    let mut relocations = vec![];
+    for (i, instruction) in assembly.instructions.iter().enumerate() {
+      if is_branch(Some(instruction)) {
+        match assembly.imports.iter().find(|(j, _)| *j == i) {
+          Some((_, name)) => relocations.push(Relocation::Extern(i, name.clone())),
+          None => relocations.push(Relocation::Code(i)),
+        }
+      }
+    }
+    for (fixup, name, _) in &assembly.fixups {
+      if assembly.addresses.contains_key(name) {
+        relocations.push(Relocation::Data(*fixup));
+      } else {
+        relocations.push(Relocation::ExternData(*fixup, name.clone()));
+      }
+    }
+    let mut symbols = assembly
+      .globals
+      .keys()
+      .filter_map(|name| Some((name.clone(), *assembly.targets.get(name)?)))
+      .collect::<Vec<_>>();
+    symbols.sort();
+    let mut data_symbols = assembly
+      .globals
+      .keys()
+      .filter_map(|name| Some((name.clone(), *assembly.addresses.get(name)?)))
+      .collect::<Vec<_>>();
+    data_symbols.sort();
+    Object {
+      width: assembly.width,
+      instructions: assembly.instructions.clone(),
+      reg_inits: assembly.reg_inits.clone(),
+      mem_inits: assembly.mem_inits.clone(),
+      data: assembly.data.clone(),
+      data_size: assembly.data_size,
+      symbols,
+      data_symbols,
+      relocations,
+    }
+  }
+
+  /// Labels of other objects the object branches to or uses the address of,
+  /// which must be linked before it can run.
+  pub fn externs(&self) -> Vec<&str> {
+    let mut externs = self
+      .relocations
+      .iter()
+      .filter_map(|r| match r {
+        Relocation::Extern(_, name) | Relocation::ExternData(_, name) => Some(name.as_str()),
+        Relocation::Code(_) | Relocation::Data(_) => None,
+      })
+      .collect::<Vec<_>>();
+    externs.sort_unstable();
+    externs.dedup();
+    externs
+  }
+
+  /// An assembly to run the object with, which has no source to show.
+  pub fn into_assembly(self) -> Assembly {
+    let count = self.instructions.len();
+    Assembly {
+      width: self.width,
+      instructions: self.instructions,
+      reg_inits: self.reg_inits,
+      mem_inits: self.mem_inits,
+      lines: vec![0; count],
+      files: vec![0; count],
+      spans: vec![Span::default(); count],
+      sources: SourceMap::from(""),
+      ..Assembly::default()
+    }
+  }
+
+  pub fn to_json(&self) -> Value {
+    let relocations = self
+      .relocations
+      .iter()
+      .map(|r| match r {
+        Relocation::Code(at) => json!(["code", at]),
+        Relocation::Extern(at, name) => json!(["extern", at, name]),
+        Relocation::Data(fixup) => {
+          let (kind, at) = encode_fixup(fixup);
+          json!(["data", kind, at])
+        }
+        Relocation::ExternData(fixup, name) => {
+          let (kind, at) = encode_fixup(fixup);
+          json!(["extern data", kind, at, name])
+        }
+      })
+      .collect::<Vec<_>>();
+    json!({
+      "format": "vmo",
+      "version": VERSION,
+      "width": self.width,
+      "instructions": self.instructions.iter().map(encode).collect::<Vec<_>>(),
+      "registers": self.reg_inits,
+      "memory": self.mem_inits,
+      "data": self.data,
+      "data_size": self.data_size,
+      "symbols": self.symbols,
+      "data_symbols": self.data_symbols,
+      "relocations": relocations,
+    })
+  }
+
+  pub fn from_json(value: &Value) -> Result<Self, String> {
+    if value["format"] != "vmo" {
+      return Err("Not a VMAL object file".to_owned());
+    }
+    // Objects of version 1 have no data to place, as all of their memory
+    // initializers hold absolute addresses.
+    if !(1..=VERSION).contains(&value["version"].as_u64().unwrap_or(0)) {
+      return Err(format!(
+        "Unsupported object file version {} (expected {})",
+        value["version"], VERSION
+      ));
+    }
+    let field = |name: &str| -> Result<&Vec<Value>, String> {
+      value[name]
+        .as_array()
+        .ok_or_else(|| format!("Missing '{}' in object file", name))
+    };
+    let instructions = field("instructions")?
+      .iter()
+      .map(|i| decode(i).ok_or_else(|| format!("Invalid instruction {}", i)))
+      .collect::<Result<Vec<_>, _>>()?;
+    let pairs = |name: &str| {
+      serde_json::from_value::<Vec<(isize, isize)>>(value[name].clone())
+        .map_err(|e| format!("Invalid '{}' in object file: {}", name, e))
+    };
+    let reg_inits = pairs("registers")?;
+    let mem_inits = pairs("memory")?;
+    let symbols = serde_json::from_value::<Vec<(String, usize)>>(value["symbols"].clone())
+      .map_err(|e| format!("Invalid 'symbols' in object file: {}", e))?;
+    // Fields added in version 2, which older objects go without.
+    let optional = |name: &str| match &value[name] {
+      Value::Null => json!([]),
+      field => field.clone(),
+    };
+    let data_symbols = serde_json::from_value::<Vec<(String, isize)>>(optional("data_symbols"))
+      .map_err(|e| format!("Invalid 'data_symbols' in object file: {}", e))?;
+    let data = serde_json::from_value::<Vec<usize>>(optional("data"))
+      .ok()
+      .filter(|data| data.iter().all(|i| *i < mem_inits.len()))
+      .ok_or_else(|| "Invalid 'data' in object file".to_owned())?;
+    let data_size = match &value["data_size"] {
+      Value::Null => 0,
+      size => match size.as_i64() {
+        Some(size) if size >= 0 => size as isize,
+        _ => return Err(format!("Invalid data size {} in object file", size)),
+      },
+    };
+    let relocations = field("relocations")?
+      .iter()
+      .map(|r| {
+        let at = r[1].as_u64().map(|at| at as usize);
+        let fixup = match (r[1].as_str(), r[2].as_u64().map(|i| i as usize)) {
+          (Some("register"), Some(i)) => Some(Fixup::Register(i)),
+          (Some("memory"), Some(i)) => Some(Fixup::Memory(i)),
+          (Some("instruction"), Some(i)) => Some(Fixup::Instruction(i)),
+          _ => None,
+        };
+        let relocation = match (r[0].as_str(), at, fixup, r[2].as_str(), r[3].as_str()) {
+          (Some("code"), Some(at), _, None, _) => Relocation::Code(at),
+          (Some("extern"), Some(at), _, Some(name), _) => Relocation::Extern(at, name.to_owned()),
+          (Some("data"), _, Some(fixup), _, None) => Relocation::Data(fixup),
+          (Some("extern data"), _, Some(fixup), _, Some(name)) => {
+            Relocation::ExternData(fixup, name.to_owned())
+          }
+          _ => return Err(format!("Invalid relocation {}", r)),
+        };
+        match &relocation {
+          Relocation::Code(at) | Relocation::Extern(at, _) => {
+            if !is_branch(instructions.get(*at)) {
+              return Err(format!("Relocation {} is not at a branch", r));
+            }
+          }
+          Relocation::Data(fixup) | Relocation::ExternData(fixup, _) => {
+            let found = match *fixup {
+              Fixup::Register(i) => i < reg_inits.len(),
+              Fixup::Memory(i) => i < mem_inits.len(),
+              Fixup::Instruction(i) => matches!(instructions.get(i), Some(Instruction::LI(..))),
+            };
+            if !found {
+              return Err(format!("Relocation {} is not at an initializer or LI", r));
+            }
+          }
+        }
+        Ok(relocation)
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+    // Objects written before the width was configurable have 32-bit words.
+    let width = match &value["width"] {
+      Value::Null => DEFAULT_WIDTH,
+      width => match width.as_u64() {
+        Some(width) if WIDTHS.contains(&(width as u32)) => width as u32,
+        _ => return Err(format!("Invalid word width {} in object file", width)),
+      },
+    };
+    Ok(Object {
+      width,
+      instructions,
+      reg_inits,
+      mem_inits,
+      data,
+      data_size,
+      symbols,
+      data_symbols,
+      relocations,
+    })
+  }
+
+  pub fn read(path: &Path) -> Result<Self, String> {
+    let text = std::fs::read_to_string(path)
+      .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    let value = serde_json::from_str(&text)
+      .map_err(|_| format!("{}: Not a VMAL object file", path.display()))?;
+    Object::from_json(&value).map_err(|e| format!("{}: {}", path.display(), e))
+  }
+
+  /// Writes the object with one instruction, initializer, symbol or
+  /// relocation per line.
+  pub fn write(&self, path: &Path) -> std::io::Result<()> {
+    let json = self.to_json();
+    let fields = json
+      .as_object()
+      .unwrap()
+      .iter()
+      .map(|(name, value)| match value.as_array() {
+        Some(items) if !items.is_empty() => {
+          let items = items
+            .iter()
+            .map(|item| format!("    {}", item))
+            .collect::<Vec<_>>();
+          format!("  \"{}\": [\n{}\n  ]", name, items.join(",\n"))
+        }
+        _ => format!("  \"{}\": {}", name, value),
+      })
+      .collect::<Vec<_>>();
+    std::fs::write(path, format!("{{\n{}\n}}\n", fields.join(",\n")))
+  }
+}
+
+/// Links objects into one, placing their instructions one after another in
+/// the order given, so the program starts at the first instruction of the
+/// first object, and their data likewise from address 0. Fails with every
+/// label exported twice or never exported, every register or memory location
+/// given different values, and every memory initializer in the data of
+/// another object.
+pub fn link(objects: &[(String, Object)]) -> Result<Object, Vec<String>> {
+  let mut errors = vec![];
+  let mut symbols = HashMap::new();
+  // Where the instructions and the data of every object are placed.
+  let mut bases = vec![];
+  let (mut base, mut data_base) = (0, 0);
+  for (name, object) in objects {
+    let code = object
+      .symbols
+      .iter()
+      .map(|(symbol, i)| (symbol, Symbol::Code(base + i)));
+    let data = object
+      .data_symbols
+      .iter()
+      .map(|(symbol, address)| (symbol, Symbol::Data(data_base + address)));
+    for (symbol, value) in code.chain(data) {
+      match symbols.get(symbol) {
+        Some((_, other)) => errors.push(format!(
+          "Label '{}' is exported by both {} and {}",
+          symbol, other, name
+        )),
+        None => {
+          symbols.insert(symbol, (value, name));
+        }
+      }
+    }
+    bases.push((base, data_base));
+    base += object.instructions.len();
+    data_base += object.data_size;
+  }
+
+  let mut linked = Object {
+    width: objects.first().map_or(DEFAULT_WIDTH, |(_, o)| o.width),
+    data_size: data_base,
+    ..Object::default()
+  };
+  for (name, object) in objects {
+    if object.width != linked.width {
+      errors.push(format!(
+        "{} has {}-bit words, but {} has {}-bit words",
+        name, object.width, objects[0].0, linked.width
+      ));
+    }
+  }
+  if linked.width < isize::BITS && data_base > 1 << linked.width {
+    errors.push(format!(
+      "The data of the objects takes up {} words, more than {}-bit words can address",
+      data_base, linked.width
+    ));
+  }
+  let mut registers = HashMap::new();
+  let mut memory = HashMap::new();
+  let register: fn(isize) -> String = |r| format!("Register {:X}", r);
+  let location: fn(isize) -> String = |m| format!("Memory location {}", m);
+  let undefined = |errors: &mut Vec<String>, symbol: &str, name: &str| {
+    let error = format!("Undefined label '{}', used by {}", symbol, name);
+    if !errors.contains(&error) {
+      errors.push(error);
+    }
+  };
+  for ((name, object), &(base, data_base)) in objects.iter().zip(&bases) {
+    linked
+      .instructions
+      .extend(object.instructions.iter().cloned());
+    let mut reg_inits = object.reg_inits.clone();
+    let mut mem_inits = object.mem_inits.clone();
+    for i in &object.data {
+      mem_inits[*i].0 += data_base;
+    }
+    let (reg_base, mem_base) = (linked.reg_inits.len(), linked.mem_inits.len());
+    for relocation in &object.relocations {
+      match relocation {
+        Relocation::Code(at) | Relocation::Extern(at, _) => {
+          let target = match relocation {
+            Relocation::Extern(_, symbol) => match symbols.get(symbol) {
+              Some((Symbol::Code(i), _)) => Some(*i),
+              Some((Symbol::Data(_), other)) => {
+                errors.push(format!(
+                  "Label '{}' of {} names data, but {} branches to it",
+                  symbol, other, name
+                ));
+                continue;
+              }
+              None => {
+                undefined(&mut errors, symbol, name);
+                continue;
+              }
+            },
+            _ => None,
+          };
+          if let Instruction::GO(t)
+          | Instruction::BIN(t)
+          | Instruction::BIZ(t)
+          | Instruction::BIC(t)
+          | Instruction::BIV(t)
+          | Instruction::CALL(t)
+          | Instruction::SIV(t) = &mut linked.instructions[base + at]
+          {
+            // Branches hold the index before their target.
+            *t = match target {
+              Some(i) => i as isize - 1,
+              None => *t + base as isize,
+            };
+          }
+          linked.relocations.push(Relocation::Code(base + at));
+        }
+        Relocation::Data(fixup) | Relocation::ExternData(fixup, _) => {
+          // Added to the address, which is 0 for labels of other objects.
+          let offset = match relocation {
+            Relocation::ExternData(_, symbol) => match symbols.get(symbol) {
+              Some((Symbol::Data(address), _)) => *address,
+              Some((Symbol::Code(_), other)) => {
+                errors.push(format!(
+                  "Label '{}' of {} names an instruction, but {} uses it as a data label",
+                  symbol, other, name
+                ));
+                continue;
+              }
+              None => {
+                undefined(&mut errors, symbol, name);
+                continue;
+              }
+            },
+            _ => data_base,
+          };
+          let (value, fixup) = match *fixup {
+            Fixup::Register(i) => (&mut reg_inits[i].1, Fixup::Register(reg_base + i)),
+            Fixup::Memory(i) => (&mut mem_inits[i].1, Fixup::Memory(mem_base + i)),
+            Fixup::Instruction(i) => match &mut linked.instructions[base + i] {
+              Instruction::LI(_, value) => (value, Fixup::Instruction(base + i)),
+              _ => continue,
+            },
+          };
+          *value += offset;
+          linked.relocations.push(Relocation::Data(fixup));
+        }
+      }
+    }
+    for (i, (at, _)) in mem_inits.iter().enumerate() {
+      if object.data.contains(&i) {
+        continue;
+      }
+      let other = objects.iter().zip(&bases).find(|((other, o), (_, start))| {
+        other != name && (*start..start + o.data_size).contains(at)
+      });
+      if let Some(((other, _), _)) = other {
+        errors.push(format!(
+          "Memory location {} is initialized by {}, but holds data of {}",
+          at, name, other
+        ));
+      }
+    }
+    for (describe, inits, values, data) in [
+      (register, &reg_inits, &mut registers, &[][..]),
+      (location, &mem_inits, &mut memory, &object.data[..]),
+    ] {
+      for (i, (at, value)) in inits.iter().enumerate() {
+        // Data cannot overlap other data, only the initializers checked above.
+        if data.contains(&i) {
+          continue;
+        }
+        if let Some((other_value, other)) = values.insert(*at, (*value, name)) {
+          if other != name && other_value != *value {
+            errors.push(format!(
+              "{} is initialized to {} by {} and to {} by {}",
+              describe(*at),
+              other_value,
+              other,
+              value,
+              name
+            ));
+          }
+        }
+      }
+    }
+    linked.reg_inits.extend(reg_inits);
+    linked.mem_inits.extend(mem_inits);
+    linked.data.extend(object.data.iter().map(|i| mem_base + i));
+    linked
+      .symbols
+      .extend(object.symbols.iter().map(|(s, i)| (s.clone(), base + i)));
+    linked.data_symbols.extend(
+      object
+        .data_symbols
+        .iter()
+        .map(|(s, address)| (s.clone(), data_base + address)),
+    );
+  }
+  if errors.is_empty() {
+    Ok(linked)
+  } else {
+    Err(errors)
+  }
+}
+
+#[test]
+fn test_link() {
+  use crate::vm::VM;
+
+  let main = Assembly::try_assemble(
+    "\
+.extern double, exit;
+.global done;
+A: 3;
+  MV E, A;
+  GO double;
+LBL done;
+  ADD E, 6;
+  GO exit;
+",
+  )
+  .unwrap();
+  let double = Assembly::try_assemble(
+    "\
+.global double, exit;
+.extern done;
+LBL double;
+  SF E;
+  BIZ skip;
+  ADD E, E;
+LBL skip;
+  GO done;
+LBL exit;
+",
+  )
+  .unwrap();
+  assert_eq!(
+    main.imports,
+    vec![(1, "double".to_owned()), (3, "exit".to_owned())]
+  );
+  assert_eq!(main.unlinked().len(), 2);
+  let main = Object::new(&main);
+  let double = Object::new(&double);
+  assert_eq!(
+    double.relocations,
+    vec![
+      Relocation::Code(1),
+      Relocation::Extern(3, "done".to_owned())
+    ]
+  );
+  assert_eq!(Object::from_json(&double.to_json()), Ok(double.clone()));
+
+  let objects = vec![
+    ("main.vmo".to_owned(), main),
+    ("double.vmo".to_owned(), double),
+  ];
+  let linked = link(&objects).unwrap();
+  assert_eq!(
+    linked.instructions,
+    vec![
+      Instruction::MV(0xE, 0xA),
+      Instruction::GO(3),
+      Instruction::ADD(0xE, 6),
+      Instruction::GO(7),
+      Instruction::SF(0xE),
+      Instruction::BIZ(6),
+      Instruction::ADD(0xE, 0xE),
+      Instruction::GO(1)
+    ]
+  );
+  assert!(linked.externs().is_empty());
+  let mut vm = VM::with_width(
+    linked.width,
+    linked.reg_inits.clone(),
+    linked.mem_inits.clone(),
+  );
+  let assembly = linked.into_assembly();
+  vm.run_code(&assembly.instructions);
+  assert_eq!(vm.registers[0xE], 7);
+
+  let other = Object::new(
+    &Assembly::try_assemble(
+      ".global done;\n.extern missing;\nA: 4;\nLBL done;\n  GO missing;\n  GO missing;\n",
+    )
+    .unwrap(),
+  );
+  let (_, main) = objects.into_iter().next().unwrap();
+  let errors = link(&[
+    ("main.vmo".to_owned(), main),
+    ("other.vmo".to_owned(), other),
+  ])
+  .unwrap_err();
+  assert_eq!(
+    errors,
+    vec![
+      "Label 'done' is exported by both main.vmo and other.vmo",
+      "Undefined label 'double', used by main.vmo",
+      "Undefined label 'exit', used by main.vmo",
+      "Undefined label 'missing', used by other.vmo",
+      "Register A is initialized to 3 by main.vmo and to 4 by other.vmo",
+    ]
+  );
+
+  let (_, errors) = Assembly::check(".global a, b;\n.extern b;\n.extern c;\nLBL a;\nLBL c;\n");
+  assert_eq!(
+    errors
+      .iter()
+      .map(|e| e.message.as_str())
+      .collect::<Vec<_>>(),
+    vec![
+      "Label 'b' is declared .global but never defined",
+      "Label 'b' is already declared .global",
+      "Label 'c' is declared .extern but defined here"
+    ]
+  );
+}
+
+#[test]
+fn test_link_data() {
+  use crate::vm::VM;
+
+  let main = Assembly::try_assemble(
+    "\
+.extern table;
+.global msg;
+  LI A, msg;
+  SA A;
+  RD;
+  RB B;
+  LI C, table;
+  SA C;
+  RD;
+  RB D;
+  ADD B, D;
+msg: .word 5, 6
+",
+  )
+  .unwrap();
+  assert_eq!(main.unlinked().len(), 1);
+  let table = Assembly::try_assemble(
+    "\
+.global table;
+.extern msg;
+table: .word 7
+ptr: .word msg
+.space 2
+",
+  )
+  .unwrap();
+  let main = Object::new(&main);
+  let table = Object::new(&table);
+  assert_eq!(main.data_symbols, vec![("msg".to_owned(), 0)]);
+  assert_eq!((table.data.clone(), table.data_size), (vec![0, 1], 4));
+  assert_eq!(
+    table.relocations,
+    vec![Relocation::ExternData(Fixup::Memory(1), "msg".to_owned())]
+  );
+  assert_eq!(Object::from_json(&table.to_json()), Ok(table.clone()));
+
+  let objects = vec![
+    ("main.vmo".to_owned(), main.clone()),
+    ("table.vmo".to_owned(), table.clone()),
+  ];
+  let linked = link(&objects).unwrap();
+  assert_eq!(linked.mem_inits, vec![(0, 5), (1, 6), (2, 7), (3, 0)]);
+  assert_eq!(linked.instructions[4], Instruction::LI(0xC, 2));
+  assert_eq!(linked.data_size, 6);
+  assert!(linked.externs().is_empty());
+  let mut vm = VM::with_width(
+    linked.width,
+    linked.reg_inits.clone(),
+    linked.mem_inits.clone(),
+  );
+  vm.run_code(&linked.into_assembly().instructions);
+  assert_eq!(vm.registers[0xB], 12);
+
+  // Placed first, the data of the table moves the data of the program.
+  let linked = link(&[
+    ("table.vmo".to_owned(), table.clone()),
+    ("main.vmo".to_owned(), main.clone()),
+  ])
+  .unwrap();
+  assert_eq!(linked.mem_inits, vec![(0, 7), (1, 4), (4, 5), (5, 6)]);
+
+  let fixed =
+    Object::new(&Assembly::try_assemble(".extern table;\n[3]: 9;\nLI A, table;\n").unwrap());
+  let errors = link(&[
+    ("main.vmo".to_owned(), main),
+    ("table.vmo".to_owned(), table),
+    ("fixed.vmo".to_owned(), fixed),
+  ])
+  .unwrap_err();
+  assert_eq!(
+    errors,
+    vec!["Memory location 3 is initialized by fixed.vmo, but holds data of table.vmo"]
+  );
+}
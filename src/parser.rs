@@ -0,0 +1,495 @@
+//! Parses VMAL source into statements.
+//!
+//! Every statement sits on a line of its own and, apart from directives, ends
+//! with a `;`. A statement is one of:
+//!
+//! ```text
+//! r: value;            register initializer
+//! [address]: value;    memory initializer
+//! OP arg, arg, ...;    instruction
+//! .name arg, ...       directive, with an optional `;`
+//! label: .name arg     directive with a data label
+//! ```
+//!
+//! The exception to commas between operands is `.macro NAME a, b`, whose name
+//! is followed by its parameters.
+
+use crate::{
+  assembler::Diagnostic,
+  lexer::{tokenize, Span, Token, TokenKind},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OperandKind {
+  Name,
+  Number,
+  Str(String),
+}
+
+/// A single operand, or the name of an operation or directive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operand {
+  pub kind: OperandKind,
+  /// The operand as written, including the sign of a negative number.
+  pub text: String,
+  pub span: Span,
+}
+
+impl Operand {
+  /// The value of a number literal, or the kind of literal it failed to parse
+  /// as.
+  pub fn number(&self) -> Result<isize, &'static str> {
+    if self.kind != OperandKind::Number {
+      return Err("character");
+    }
+    let (negative, digits) = match self.text.strip_prefix('-') {
+      Some(digits) => (true, digits),
+      None => (false, self.text.as_str()),
+    };
+    // Hexadecimal and binary literals may set the top bit of a 64-bit word.
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+      u64::from_str_radix(hex, 16).map_err(|_| "hexadecimal")? as isize
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+      u64::from_str_radix(binary, 2).map_err(|_| "binary")? as isize
+    } else {
+      digits.parse::<isize>().map_err(|_| "character")?
+    };
+    Ok(if negative {
+      value.wrapping_neg()
+    } else {
+      value
+    })
+  }
+  /// The register a single hexadecimal digit names.
+  pub fn register(&self) -> Option<isize> {
+    if self.text.len() != 1 || matches!(self.kind, OperandKind::Str(_)) {
+      return None;
+    }
+    isize::from_str_radix(&self.text, 16).ok()
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatementKind {
+  RegisterInit {
+    register: Operand,
+    value: Operand,
+  },
+  MemoryInit {
+    address: Operand,
+    value: Operand,
+  },
+  Instruction {
+    op: Operand,
+    args: Vec<Operand>,
+  },
+  Directive {
+    /// Data label naming the address of the first word the directive lays
+    /// out, as in `table: .word 1, 2`.
+    label: Option<Operand>,
+    name: Operand,
+    args: Vec<Operand>,
+  },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+  pub kind: StatementKind,
+  /// Span of the statement, up to and including its `;`.
+  pub span: Span,
+}
+
+impl Statement {
+  /// Moves every span in the statement by an offset, for statements parsed
+  /// from an included file.
+  pub fn shift(&mut self, offset: usize) {
+    let shift = |span: &mut Span| *span = Span::new(span.start + offset, span.end + offset);
+    shift(&mut self.span);
+    let operands = match &mut self.kind {
+      StatementKind::RegisterInit { register, value } => vec![register, value],
+      StatementKind::MemoryInit { address, value } => vec![address, value],
+      StatementKind::Instruction { op, args } => {
+        let mut operands = vec![op];
+        operands.extend(args.iter_mut());
+        operands
+      }
+      StatementKind::Directive { label, name, args } => {
+        let mut operands = label.iter_mut().collect::<Vec<_>>();
+        operands.push(name);
+        operands.extend(args.iter_mut());
+        operands
+      }
+    };
+    for operand in operands {
+      shift(&mut operand.span);
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct Program {
+  pub statements: Vec<Statement>,
+  pub comments: Vec<Span>,
+  pub errors: Vec<Diagnostic>,
+}
+
+/// Describes a token for error messages.
+fn describe(source: &str, token: &Token) -> String {
+  match token.kind {
+    TokenKind::Newline => "end of line".to_owned(),
+    _ => format!("'{}'", token.text(source)),
+  }
+}
+
+struct Parser<'a> {
+  source: &'a str,
+  tokens: &'a [Token],
+  position: usize,
+}
+
+impl<'a> Parser<'a> {
+  fn peek(&self) -> &'a Token {
+    &self.tokens[self.position.min(self.tokens.len() - 1)]
+  }
+  fn next(&mut self) -> &'a Token {
+    let token = self.peek();
+    self.position += 1;
+    token
+  }
+  fn at_end(&self) -> bool {
+    self.position >= self.tokens.len()
+  }
+  fn error(&self, span: Span, message: String) -> Diagnostic {
+    Diagnostic::error(self.source, span, message)
+  }
+  /// Span just after the last token, where something missing was expected.
+  fn end_span(&self) -> Span {
+    let end = self.tokens.last().map(|t| t.span.end).unwrap_or(0);
+    Span::new(end, end)
+  }
+
+  fn operand(&mut self) -> Result<Operand, Diagnostic> {
+    if self.at_end() {
+      return Err(self.error(self.end_span(), "Expected an operand".to_owned()));
+    }
+    let token = self.next();
+    let kind = match &token.kind {
+      TokenKind::Ident => OperandKind::Name,
+      TokenKind::Number => OperandKind::Number,
+      TokenKind::Str(value) => OperandKind::Str(value.clone()),
+      TokenKind::Minus if !self.at_end() && self.peek().kind == TokenKind::Number => {
+        let number = self.next();
+        return Ok(Operand {
+          kind: OperandKind::Number,
+          text: format!("-{}", number.text(self.source)),
+          span: token.span.to(number.span),
+        });
+      }
+      _ => {
+        return Err(self.error(
+          token.span,
+          format!(
+            "Expected an operand, found {}",
+            describe(self.source, token)
+          ),
+        ))
+      }
+    };
+    Ok(Operand {
+      kind,
+      text: token.text(self.source).to_owned(),
+      span: token.span,
+    })
+  }
+
+  /// Parses a comma separated list of operands up to the end of the tokens.
+  fn operands(&mut self) -> Result<Vec<Operand>, Diagnostic> {
+    let mut operands = vec![];
+    while !self.at_end() {
+      operands.push(self.operand()?);
+      if self.at_end() {
+        break;
+      }
+      let token = self.next();
+      if token.kind != TokenKind::Comma {
+        return Err(self.error(
+          token.span,
+          format!(
+            "Expected ',' or ';' after operand, found {}",
+            describe(self.source, token)
+          ),
+        ));
+      }
+      if self.at_end() {
+        return Err(self.error(token.span, "Expected an operand after ','".to_owned()));
+      }
+    }
+    Ok(operands)
+  }
+
+  fn expect(&mut self, kind: TokenKind, message: &str) -> Result<(), Diagnostic> {
+    if self.at_end() {
+      return Err(self.error(self.end_span(), message.to_owned()));
+    }
+    let token = self.next();
+    if token.kind != kind {
+      return Err(self.error(token.span, message.to_owned()));
+    }
+    Ok(())
+  }
+
+  fn initializer_value(&mut self) -> Result<Operand, Diagnostic> {
+    let value = self.operand()?;
+    if !self.at_end() {
+      let token = self.peek();
+      return Err(self.error(
+        token.span,
+        format!(
+          "Unexpected {} after initializer value",
+          describe(self.source, token)
+        ),
+      ));
+    }
+    Ok(value)
+  }
+
+  /// Parses the tokens of a statement before its `;`.
+  fn statement(&mut self) -> Result<StatementKind, Diagnostic> {
+    let first = self.peek();
+    let syntax = "Invalid syntax for register/memory initializer";
+    if first.kind == TokenKind::LBracket {
+      self.next();
+      let address = self.operand()?;
+      self.expect(TokenKind::RBracket, syntax)?;
+      self.expect(TokenKind::Colon, syntax)?;
+      let value = self.initializer_value()?;
+      return Ok(StatementKind::MemoryInit { address, value });
+    }
+    if self.tokens.get(1).map(|t| &t.kind) == Some(&TokenKind::Colon) {
+      let register = self.operand()?;
+      self.next();
+      let value = self.initializer_value()?;
+      return Ok(StatementKind::RegisterInit { register, value });
+    }
+    if let Some(colon) = self.tokens.iter().find(|t| t.kind == TokenKind::Colon) {
+      return Err(self.error(colon.span, syntax.to_owned()));
+    }
+    if first.kind != TokenKind::Ident {
+      return Err(self.error(
+        first.span,
+        format!(
+          "Expected an operation, found {}",
+          describe(self.source, first)
+        ),
+      ));
+    }
+    let op = self.operand()?;
+    let args = self.operands()?;
+    Ok(StatementKind::Instruction { op, args })
+  }
+}
+
+/// Parses the code of one line, without its comment or newline.
+fn parse_line(source: &str, tokens: &[Token]) -> Result<Option<Statement>, Diagnostic> {
+  if tokens.is_empty() {
+    return Ok(None);
+  }
+  for token in tokens {
+    let message = match token.kind {
+      TokenKind::Unknown => format!("Unexpected character {}", describe(source, token)),
+      TokenKind::UnterminatedStr => "Unterminated string literal".to_owned(),
+      _ => continue,
+    };
+    return Err(Diagnostic::error(source, token.span, message));
+  }
+  let start = tokens[0].span.start;
+  let (label, tokens) = match tokens {
+    [name, colon, directive, ..]
+      if name.kind == TokenKind::Ident
+        && colon.kind == TokenKind::Colon
+        && directive.kind == TokenKind::Directive =>
+    {
+      let label = Operand {
+        kind: OperandKind::Name,
+        text: name.text(source).to_owned(),
+        span: name.span,
+      };
+      (Some(label), &tokens[2..])
+    }
+    _ => (None, tokens),
+  };
+  if tokens[0].kind == TokenKind::Directive {
+    let mut end = tokens.len();
+    if tokens[end - 1].kind == TokenKind::Semicolon {
+      end -= 1;
+    }
+    let mut parser = Parser {
+      source,
+      tokens: &tokens[1..end],
+      position: 0,
+    };
+    let mut args = vec![];
+    // `.macro` separates its name from its parameters with a space.
+    if tokens[0].text(source).eq_ignore_ascii_case(".macro")
+      && !parser.at_end()
+      && parser.peek().kind == TokenKind::Ident
+    {
+      args.push(parser.operand()?);
+    }
+    args.extend(parser.operands()?);
+    let name = Operand {
+      kind: OperandKind::Name,
+      text: tokens[0].text(source).to_owned(),
+      span: tokens[0].span,
+    };
+    return Ok(Some(Statement {
+      kind: StatementKind::Directive { label, name, args },
+      span: Span::new(start, tokens[tokens.len() - 1].span.end),
+    }));
+  }
+  let semicolon = match tokens.iter().position(|t| t.kind == TokenKind::Semicolon) {
+    Some(semicolon) => semicolon,
+    None => {
+      let end = tokens[tokens.len() - 1].span.end;
+      return Err(Diagnostic::error(
+        source,
+        Span::new(end, end),
+        "Missing semicolon".to_owned(),
+      ));
+    }
+  };
+  if semicolon + 1 < tokens.len() {
+    let extra = tokens[semicolon + 1].span.to(tokens[tokens.len() - 1].span);
+    return Err(Diagnostic::error(
+      source,
+      extra,
+      format!(
+        "Extra non-comment character sequence after semicolon - '{}'",
+        &source[extra.start..extra.end]
+      ),
+    ));
+  }
+  if semicolon == 0 {
+    return Err(Diagnostic::error(
+      source,
+      tokens[0].span,
+      "Expected an operation, found ';'".to_owned(),
+    ));
+  }
+  let mut parser = Parser {
+    source,
+    tokens: &tokens[..semicolon],
+    position: 0,
+  };
+  let kind = parser.statement()?;
+  Ok(Some(Statement {
+    kind,
+    span: Span::new(start, tokens[semicolon].span.end),
+  }))
+}
+
+/// Parses a program, collecting an error for every line that fails to parse.
+pub fn parse(source: &str) -> Program {
+  let tokens = tokenize(source);
+  let mut program = Program::default();
+  let mut line_start = 0;
+  for (i, token) in tokens.iter().enumerate() {
+    match token.kind {
+      TokenKind::Comment => program.comments.push(token.span),
+      TokenKind::Newline => {
+        let code = tokens[line_start..i]
+          .iter()
+          .filter(|t| t.kind != TokenKind::Comment)
+          .cloned()
+          .collect::<Vec<_>>();
+        match parse_line(source, &code) {
+          Ok(Some(statement)) => program.statements.push(statement),
+          Ok(None) => {}
+          Err(err) => program.errors.push(err),
+        }
+        line_start = i + 1;
+      }
+      _ => {}
+    }
+  }
+  program
+}
+
+#[test]
+fn test_parse() {
+  let program = parse("A: -0x10;\n[4]: 2; # four\nadd\tE, a;\n.ascii \"x#y\"\nLBL 1abc;");
+  assert!(program.errors.is_empty());
+  assert_eq!(program.comments, vec![Span::new(18, 24)]);
+  assert_eq!(program.statements.len(), 5);
+  match &program.statements[0].kind {
+    StatementKind::RegisterInit { register, value } => {
+      assert_eq!(register.register(), Some(0xA));
+      assert_eq!(value.number(), Ok(-0x10));
+    }
+    kind => panic!("{:?}", kind),
+  }
+  match &program.statements[2].kind {
+    StatementKind::Instruction { op, args } => {
+      assert_eq!(op.text, "add");
+      assert_eq!(op.span, Span::new(25, 28));
+      assert_eq!(
+        args.iter().map(|a| a.register()).collect::<Vec<_>>(),
+        vec![Some(0xE), Some(0xA)]
+      );
+    }
+    kind => panic!("{:?}", kind),
+  }
+  match &program.statements[3].kind {
+    StatementKind::Directive { label, name, args } => {
+      assert_eq!(label, &None);
+      assert_eq!(name.text, ".ascii");
+      assert_eq!(args[0].kind, OperandKind::Str("x#y".to_owned()));
+    }
+    kind => panic!("{:?}", kind),
+  }
+
+  match &parse("msg: .asciz \"hi\";").statements[0].kind {
+    StatementKind::Directive { label, name, args } => {
+      assert_eq!(
+        label.as_ref().map(|l| (l.text.as_str(), l.span)),
+        Some(("msg", Span::new(0, 3)))
+      );
+      assert_eq!(name.text, ".asciz");
+      assert_eq!(args.len(), 1);
+    }
+    kind => panic!("{:?}", kind),
+  }
+
+  let errors = parse("ADD A B;\nLBL a-b;\nRD\n\"open;\nRD; RD;\nA: 1: 2;")
+    .errors
+    .into_iter()
+    .map(|e| (e.line, e.columns, e.message))
+    .collect::<Vec<_>>();
+  assert_eq!(
+    errors,
+    vec![
+      (
+        0,
+        (6, 7),
+        "Expected ',' or ';' after operand, found 'B'".to_owned()
+      ),
+      (
+        1,
+        (5, 6),
+        "Expected ',' or ';' after operand, found '-'".to_owned()
+      ),
+      (2, (2, 2), "Missing semicolon".to_owned()),
+      (3, (0, 6), "Unterminated string literal".to_owned()),
+      (
+        4,
+        (4, 7),
+        "Extra non-comment character sequence after semicolon - 'RD;'".to_owned()
+      ),
+      (
+        5,
+        (4, 5),
+        "Unexpected ':' after initializer value".to_owned()
+      ),
+    ]
+  );
+}
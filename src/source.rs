@@ -0,0 +1,111 @@
+//! Source text of a program and the files it includes.
+//!
+//! Every file gets its own range of offsets, so a span points into exactly one
+//! file. The main file comes first, at offset 0.
+
+use std::path::{Path, PathBuf};
+
+use crate::lexer::Span;
+
+/// Text that spans point into.
+pub trait Source {
+  /// Finds the line holding an offset, returning the name of its file if it
+  /// should be shown, the zero-based line number, the text of the line and the
+  /// offset the line starts at.
+  fn line_at(&self, offset: usize) -> (Option<&str>, usize, &str, usize);
+}
+
+impl Source for str {
+  fn line_at(&self, offset: usize) -> (Option<&str>, usize, &str, usize) {
+    let start = self[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let end = self[start..].find('\n').map_or(self.len(), |i| start + i);
+    let line = self[..start].matches('\n').count();
+    (None, line, &self[start..end], start)
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct SourceFile {
+  /// Name of the file in messages.
+  pub name: String,
+  pub path: Option<PathBuf>,
+  pub text: String,
+  /// Offset of the start of the file.
+  pub start: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct SourceMap {
+  pub files: Vec<SourceFile>,
+  /// Directories searched for included files that are not found next to the
+  /// file including them.
+  pub include_dirs: Vec<PathBuf>,
+}
+
+impl SourceMap {
+  /// A program read from a file.
+  pub fn from_path<P: AsRef<Path>>(path: P, text: String) -> Self {
+    let path = path.as_ref();
+    let mut sources = SourceMap::default();
+    sources.add(path.display().to_string(), Some(path.to_owned()), text);
+    sources
+  }
+  /// Adds a file after the others, returning its index.
+  pub fn add(&mut self, name: String, path: Option<PathBuf>, text: String) -> usize {
+    let start = self.files.last().map_or(0, |f| f.start + f.text.len() + 1);
+    self.files.push(SourceFile {
+      name,
+      path,
+      text,
+      start,
+    });
+    self.files.len() - 1
+  }
+  /// Text of the main file.
+  pub fn main(&self) -> &str {
+    &self.files[0].text
+  }
+  /// Index of the file holding an offset.
+  pub fn file_at(&self, offset: usize) -> usize {
+    self.files.partition_point(|f| f.start <= offset) - 1
+  }
+  pub fn text(&self, span: Span) -> &str {
+    let file = &self.files[self.file_at(span.start)];
+    &file.text[span.start - file.start..span.end - file.start]
+  }
+  /// File, zero-based line and byte column of an offset.
+  pub fn position(&self, offset: usize) -> (usize, usize, usize) {
+    let index = self.file_at(offset);
+    let (_, line, _, start) = self.line_at(offset);
+    (index, line, offset - start)
+  }
+}
+
+impl Source for SourceMap {
+  fn line_at(&self, offset: usize) -> (Option<&str>, usize, &str, usize) {
+    let index = self.file_at(offset);
+    let file = &self.files[index];
+    let (_, line, text, start) = file.text.line_at(offset - file.start);
+    // Only included files are named, as the main file is the one being run.
+    let name = if index == 0 {
+      None
+    } else {
+      Some(file.name.as_str())
+    };
+    (name, line, text, file.start + start)
+  }
+}
+
+impl From<String> for SourceMap {
+  fn from(text: String) -> Self {
+    let mut sources = SourceMap::default();
+    sources.add(String::new(), None, text);
+    sources
+  }
+}
+
+impl From<&str> for SourceMap {
+  fn from(text: &str) -> Self {
+    SourceMap::from(text.to_owned())
+  }
+}
@@ -0,0 +1,515 @@
+use std::collections::HashSet;
+use std::io::{stdout, Stdout, Write};
+use std::time::Duration;
+
+use crossterm::{
+  cursor,
+  event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+  execute, queue,
+  style::{Attribute, Color, Print, ResetColor, SetAttribute, SetForegroundColor},
+  terminal::{self, ClearType},
+};
+
+use crate::{
+  assembler::{Assembly, Instruction},
+  console::Console,
+  source::SourceMap,
+  util::{op_to_string, SHOULD_SHOW_BINARY},
+  vm::{format_binary, get_int, DebugCommand, VM},
+};
+
+/// Number of instructions run between checks for an interrupting key press
+/// while continuing or running.
+const POLL_INTERVAL: usize = 10000;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Style {
+  Plain,
+  Title,
+  Current,
+  Changed,
+  Breakpoint,
+  Error,
+}
+
+struct Tui<'a> {
+  out: Stdout,
+  code: &'a [Instruction],
+  sources: &'a SourceMap,
+  files: &'a [usize],
+  lines: &'a [usize],
+  breakpoints: HashSet<isize>,
+  previous: [isize; 16],
+  command: String,
+  message: String,
+  is_error: bool,
+  /// Everything the program has written to the console.
+  output: String,
+}
+
+/// Runs the full-screen debugger until the program ends or the user quits.
+/// Returns false if the user quit.
+pub fn run_tui(vm: &mut VM, assembly: &Assembly) -> bool {
+  let mut tui = Tui {
+    out: stdout(),
+    code: &assembly.instructions,
+    sources: &assembly.sources,
+    files: &assembly.files,
+    lines: &assembly.lines,
+    breakpoints: HashSet::new(),
+    previous: vm.registers,
+    command: String::new(),
+    message:
+      "Commands: n (next), b [instruction] (breakpoint), c (continue), r (run), w (where), q (quit)"
+        .to_owned(),
+    is_error: false,
+    output: String::new(),
+  };
+  if let Some(console) = vm.memory.device_mut::<Console>() {
+    console.capture();
+  }
+  let screen = Screen::enter();
+  let finished = tui.run(vm);
+  drop(screen);
+  // The alternate screen is gone, so the output is shown again after it.
+  print!("{}", tui.output);
+  finished
+}
+
+impl<'a> Tui<'a> {
+  fn run(&mut self, vm: &mut VM) -> bool {
+    while vm.registers[0] < self.code.len() as isize && vm.halted.is_none() {
+      self.draw(vm);
+      let key = match event::read().unwrap() {
+        Event::Key(key) if key.kind != KeyEventKind::Release => key,
+        _ => continue,
+      };
+      if is_interrupt(&key) {
+        return false;
+      }
+      match key.code {
+        KeyCode::Char(c) => self.command.push(c),
+        KeyCode::Backspace => {
+          self.command.pop();
+        }
+        KeyCode::Esc => self.command.clear(),
+        KeyCode::Enter => {
+          let command = std::mem::take(&mut self.command);
+          let running = self.execute(vm, &command);
+          if let Some(console) = vm.memory.device_mut::<Console>() {
+            self.output.push_str(&console.take_output());
+          }
+          if !running {
+            return false;
+          }
+        }
+        _ => {}
+      }
+    }
+    true
+  }
+
+  /// Runs a command line, returning false if the user asked to quit.
+  fn execute(&mut self, vm: &mut VM, command: &str) -> bool {
+    self.message.clear();
+    self.is_error = false;
+    let warned = vm.warnings.len();
+    match DebugCommand::parse(command) {
+      Ok(DebugCommand::Next) => {
+        self.previous = vm.registers;
+        vm.step(self.code);
+      }
+      Ok(DebugCommand::Breakpoint(loc)) => {
+        let loc = loc.unwrap_or(vm.registers[0]);
+        if self.breakpoints.remove(&loc) {
+          self.message = format!("Breakpoint at {} OFF", loc);
+        } else {
+          self.breakpoints.insert(loc);
+          self.message = format!("Breakpoint at {} ON", loc);
+        }
+      }
+      Ok(DebugCommand::Continue) => {
+        self.previous = vm.registers;
+        vm.step(self.code);
+        if !self.run_until(vm, true) {
+          return true;
+        }
+        if vm.fault.is_none() && self.breakpoints.contains(&vm.registers[0]) {
+          self.message = format!("BREAKPOINT at {}", vm.registers[0]);
+        }
+      }
+      Ok(DebugCommand::Run) => {
+        self.previous = vm.registers;
+        if !self.run_until(vm, false) {
+          return true;
+        }
+      }
+      Ok(DebugCommand::Where) => {
+        let frames = vm
+          .backtrace()
+          .into_iter()
+          .map(|loc| format!("{} ({})", loc, op_to_string(&self.code[loc as usize])))
+          .collect::<Vec<_>>();
+        self.message = format!("Call stack: {}", frames.join(" < "));
+      }
+      Ok(DebugCommand::Quit) => return false,
+      Err(err) => {
+        self.message = err;
+        self.is_error = true;
+      }
+    }
+    if let Some((pc, warning)) = vm.warnings.get(warned..).and_then(|new| new.last()) {
+      self.message = format!("Warning: {} at {}", warning, pc);
+    }
+    // A faulted program stays on the instruction that faulted until the user
+    // quits.
+    if let Some(fault) = vm.fault {
+      self.message = format!("{} at {}", fault, vm.registers[0]);
+      self.is_error = true;
+    }
+    true
+  }
+
+  /// Runs the program until it stops, or reaches a breakpoint if `breakpoints`
+  /// is true, checking for key presses that interrupt it every
+  /// `POLL_INTERVAL` instructions. Returns false if it was interrupted.
+  fn run_until(&mut self, vm: &mut VM, breakpoints: bool) -> bool {
+    let mut count = 0;
+    loop {
+      let at_breakpoint = breakpoints && self.breakpoints.contains(&vm.registers[0]);
+      if vm.stopped(self.code) || at_breakpoint {
+        return true;
+      }
+      vm.step(self.code);
+      count += 1;
+      if count % POLL_INTERVAL == 0 && interrupted() {
+        self.message = "Interrupted".to_owned();
+        return false;
+      }
+    }
+  }
+
+  fn draw(&mut self, vm: &VM) {
+    let (width, height) = terminal::size().unwrap();
+    let (width, height) = (width as usize, height as usize);
+    queue!(self.out, terminal::Clear(ClearType::All)).unwrap();
+    if height < 4 || width < 20 {
+      self.put(0, 0, width, "Terminal too small", Style::Error);
+      self.out.flush().unwrap();
+      return;
+    }
+    let body = height - 2;
+    let left = width / 2;
+    let right = left + 2;
+    let right_width = width - right;
+    for y in 0..body {
+      self.put(left, y, 1, "│", Style::Plain);
+    }
+    let output_rows = if self.output.is_empty() || body < 6 {
+      0
+    } else {
+      (body / 3).max(2)
+    };
+    self.draw_source(vm, 0, left, body - output_rows);
+    self.draw_output(0, body - output_rows, left, output_rows);
+
+    let mut y = 0;
+    y = self.draw_registers(vm, right, y, right_width);
+    y = self.draw_flags(vm, right, y + 1, right_width);
+    let call_rows = vm.backtrace().len().min(body.saturating_sub(y) / 4).max(1);
+    y = self.draw_calls(vm, right, y + 1, right_width, call_rows);
+    let breakpoint_rows = (self.breakpoints.len() + 1)
+      .min(body.saturating_sub(y) / 3)
+      .max(1);
+    let memory_rows = body.saturating_sub(y + 3 + breakpoint_rows);
+    y = self.draw_memory(vm, right, y + 1, right_width, memory_rows);
+    self.draw_breakpoints(right, y + 1, right_width, body.saturating_sub(y + 2));
+
+    let style = if self.is_error {
+      Style::Error
+    } else {
+      Style::Plain
+    };
+    let message = self.message.clone();
+    self.put(0, height - 2, width, &message, style);
+    let prompt = format!("Debug (n,b,c,r,w,q): {}", self.command);
+    self.put(0, height - 1, width, &prompt, Style::Plain);
+    queue!(
+      self.out,
+      cursor::MoveTo(
+        prompt.chars().count().min(width - 1) as u16,
+        height as u16 - 1
+      )
+    )
+    .unwrap();
+    self.out.flush().unwrap();
+  }
+
+  /// Draws the file holding the current instruction, with its line
+  /// highlighted and kept in the middle of the pane.
+  fn draw_source(&mut self, vm: &VM, x: usize, width: usize, height: usize) {
+    let pc = vm.registers[0] as usize;
+    let file = self.files.get(pc).copied().unwrap_or(0);
+    let sources = self.sources;
+    let source = sources.files[file].text.lines().collect::<Vec<_>>();
+    if file == 0 {
+      self.put(x, 0, width, " Source", Style::Title);
+    } else {
+      let title = format!(" Source - {}", sources.files[file].name);
+      self.put(x, 0, width, &title, Style::Title);
+    }
+    let height = height - 1;
+    let current = self.lines.get(pc).copied();
+    let breakpoint_lines = self
+      .breakpoints
+      .iter()
+      .filter(|b| self.files.get(**b as usize) == Some(&file))
+      .filter_map(|b| self.lines.get(*b as usize).copied())
+      .collect::<HashSet<_>>();
+    let first = current
+      .unwrap_or(0)
+      .saturating_sub(height / 2)
+      .min(source.len().saturating_sub(height));
+    let number_width = format!("{}", source.len()).len();
+    for row in 0..height {
+      let i = first + row;
+      if i >= source.len() {
+        break;
+      }
+      let text = format!(
+        "{}{:>w$} {}",
+        if breakpoint_lines.contains(&i) {
+          '●'
+        } else {
+          ' '
+        },
+        i + 1,
+        source[i].replace('\t', "  "),
+        w = number_width
+      );
+      let style = if Some(i) == current {
+        Style::Current
+      } else if breakpoint_lines.contains(&i) {
+        Style::Breakpoint
+      } else {
+        Style::Plain
+      };
+      self.put(x, row + 1, width, &text, style);
+    }
+  }
+
+  /// Draws the last lines the program wrote to the console.
+  fn draw_output(&mut self, x: usize, y: usize, width: usize, height: usize) {
+    if height == 0 {
+      return;
+    }
+    self.put(x, y, width, " Output", Style::Title);
+    let output = self.output.clone();
+    let lines = output.lines().collect::<Vec<_>>();
+    let first = lines.len().saturating_sub(height - 1);
+    for (row, line) in lines[first..].iter().enumerate() {
+      self.put(
+        x,
+        y + 1 + row,
+        width,
+        &line.replace('\t', "  "),
+        Style::Plain,
+      );
+    }
+  }
+
+  fn draw_registers(&mut self, vm: &VM, x: usize, y: usize, width: usize) -> usize {
+    self.put(x, y, width, " Registers", Style::Title);
+    let binary = *SHOULD_SHOW_BINARY.read().unwrap();
+    let cells = vm
+      .registers
+      .iter()
+      .map(|v| {
+        if binary {
+          format_binary(*v, vm.width)
+        } else {
+          format!("{}", get_int(*v, vm.width))
+        }
+      })
+      .collect::<Vec<_>>();
+    let cell_width = cells.iter().map(|c| c.len()).max().unwrap() + 4;
+    let columns = (width / cell_width).clamp(1, 4);
+    let rows = 16_usize.div_ceil(columns);
+    for row in 0..rows {
+      for column in 0..columns {
+        let reg = column * rows + row;
+        if reg >= 16 {
+          continue;
+        }
+        let text = format!("{:X}: {:>w$}", reg, cells[reg], w = cell_width - 4);
+        let style = if vm.registers[reg] != self.previous[reg] {
+          Style::Changed
+        } else {
+          Style::Plain
+        };
+        self.put(
+          x + column * cell_width,
+          y + 1 + row,
+          cell_width,
+          &text,
+          style,
+        );
+      }
+    }
+    y + 1 + rows
+  }
+
+  fn draw_flags(&mut self, vm: &VM, x: usize, y: usize, width: usize) -> usize {
+    self.put(x, y, width, " Flags", Style::Title);
+    let text = format!(
+      "N: {:<5}  Z: {:<5}  C: {:<5}  V: {:<5}  I: {:<5}  MAR: {}  MBR: {}",
+      vm.N,
+      vm.Z,
+      vm.C,
+      vm.V,
+      vm.I,
+      vm.MAR,
+      get_int(vm.MBR, vm.width)
+    );
+    self.put(x, y + 1, width, &text, Style::Plain);
+    y + 2
+  }
+
+  /// Draws the memory cells around MAR.
+  fn draw_memory(&mut self, vm: &VM, x: usize, y: usize, width: usize, rows: usize) -> usize {
+    self.put(x, y, width, " Memory", Style::Title);
+    let first = vm.MAR - (rows / 2) as isize;
+    for row in 0..rows {
+      let loc = first + row as isize;
+      let text = match vm.memory.peek(loc) {
+        Some(v) => format!(
+          "{}[{}]: {}",
+          if loc == vm.MAR { '>' } else { ' ' },
+          loc,
+          get_int(v, vm.width)
+        ),
+        None => format!("{}[{}]: -", if loc == vm.MAR { '>' } else { ' ' }, loc),
+      };
+      let style = if loc == vm.MAR {
+        Style::Current
+      } else {
+        Style::Plain
+      };
+      self.put(x, y + 1 + row, width, &text, style);
+    }
+    y + 1 + rows
+  }
+
+  /// Draws the instruction of every frame of the call stack, innermost
+  /// first.
+  fn draw_calls(&mut self, vm: &VM, x: usize, y: usize, width: usize, rows: usize) -> usize {
+    self.put(x, y, width, " Call stack", Style::Title);
+    for (row, loc) in vm.backtrace().into_iter().take(rows).enumerate() {
+      let text = self.describe(loc);
+      self.put(x, y + 1 + row, width, &text, Style::Plain);
+    }
+    y + 1 + rows
+  }
+
+  fn draw_breakpoints(&mut self, x: usize, y: usize, width: usize, rows: usize) {
+    self.put(x, y, width, " Breakpoints", Style::Title);
+    let mut breakpoints = self.breakpoints.iter().copied().collect::<Vec<_>>();
+    breakpoints.sort_unstable();
+    for (row, loc) in breakpoints.into_iter().take(rows).enumerate() {
+      let text = self.describe(loc);
+      self.put(x, y + 1 + row, width, &text, Style::Breakpoint);
+    }
+  }
+
+  /// Describes an instruction and where it is in the source.
+  fn describe(&self, loc: isize) -> String {
+    match self.code.get(loc as usize) {
+      Some(op) => {
+        let line = self.lines[loc as usize] + 1;
+        match self.files[loc as usize] {
+          0 => format!("{:>4}: {} (line {})", loc, op_to_string(op), line),
+          file => format!(
+            "{:>4}: {} (line {} of {})",
+            loc,
+            op_to_string(op),
+            line,
+            self.sources.files[file].name
+          ),
+        }
+      }
+      None => format!("{:>4}: <no instruction>", loc),
+    }
+  }
+
+  /// Prints `text` at a position, cut off at `width` characters.
+  fn put(&mut self, x: usize, y: usize, width: usize, text: &str, style: Style) {
+    let text = text.chars().take(width).collect::<String>();
+    queue!(self.out, cursor::MoveTo(x as u16, y as u16)).unwrap();
+    match style {
+      Style::Plain => {}
+      Style::Title => queue!(self.out, SetAttribute(Attribute::Bold)).unwrap(),
+      Style::Current => queue!(self.out, SetAttribute(Attribute::Reverse)).unwrap(),
+      Style::Changed => queue!(
+        self.out,
+        SetForegroundColor(Color::Yellow),
+        SetAttribute(Attribute::Bold)
+      )
+      .unwrap(),
+      Style::Breakpoint | Style::Error => queue!(self.out, SetForegroundColor(Color::Red)).unwrap(),
+    }
+    queue!(self.out, Print(text)).unwrap();
+    if style != Style::Plain {
+      queue!(self.out, SetAttribute(Attribute::Reset), ResetColor).unwrap();
+    }
+  }
+}
+
+/// The terminal switched to the alternate screen in raw mode, switched back
+/// when dropped or when the debugger panics, so the shell stays usable and
+/// the panic message is left on the screen.
+struct Screen;
+
+impl Screen {
+  fn enter() -> Self {
+    terminal::enable_raw_mode().unwrap();
+    execute!(stdout(), terminal::EnterAlternateScreen).unwrap();
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+      leave_screen();
+      hook(info);
+    }));
+    Screen
+  }
+}
+
+impl Drop for Screen {
+  fn drop(&mut self) {
+    if !std::thread::panicking() {
+      // Puts back the default hook.
+      drop(std::panic::take_hook());
+    }
+    leave_screen();
+  }
+}
+
+fn leave_screen() {
+  // The terminal may already be restored, or gone.
+  let _ = execute!(stdout(), terminal::LeaveAlternateScreen);
+  let _ = terminal::disable_raw_mode();
+}
+
+fn is_interrupt(key: &KeyEvent) -> bool {
+  key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL)
+}
+
+/// Checks, without blocking, whether Escape or Ctrl-C was pressed.
+fn interrupted() -> bool {
+  while event::poll(Duration::from_secs(0)).unwrap() {
+    if let Event::Key(key) = event::read().unwrap() {
+      if key.code == KeyCode::Esc || is_interrupt(&key) {
+        return true;
+      }
+    }
+  }
+  false
+}
@@ -1,4 +1,6 @@
 use lazy_static::lazy_static;
+use serde_json::Value;
+use std::io::{self, BufRead, Write};
 use std::sync::RwLock;
 
 use crate::assembler::Instruction;
@@ -6,6 +8,7 @@ use crate::assembler::Instruction;
 lazy_static! {
   pub static ref SHOULD_USE_UNSIGNED_INT: RwLock<bool> = RwLock::new(false);
   pub static ref SHOULD_SHOW_BINARY: RwLock<bool> = RwLock::new(false);
+  pub static ref SHOULD_USE_ANSI: RwLock<bool> = RwLock::new(true);
 }
 
 pub fn op_to_string(op: &Instruction) -> String {
@@ -13,25 +16,98 @@ pub fn op_to_string(op: &Instruction) -> String {
     Instruction::GO(a) => format!("GO {:X}", a + 1),
     Instruction::BIN(a) => format!("BIN {:X}", a + 1),
     Instruction::BIZ(a) => format!("BIZ {:X}", a + 1),
+    Instruction::BIC(a) => format!("BIC {:X}", a + 1),
+    Instruction::BIV(a) => format!("BIV {:X}", a + 1),
     Instruction::ADD(a, b) => format!("ADD {:X}, {:X}", a, b),
     Instruction::AND(a, b) => format!("AND {:X}, {:X}", a, b),
     Instruction::LS(a, b) => format!("LS {:X}, {:X}", a, b),
     Instruction::MV(a, b) => format!("MV {:X}, {:X}", a, b),
     Instruction::NOT(a, b) => format!("NOT {:X}, {:X}", a, b),
     Instruction::RB(a) => format!("RB {:X}", a),
-    Instruction::RD => format!("RD"),
+    Instruction::RD => "RD".to_owned(),
     Instruction::RS(a, b) => format!("RS {:X}, {:X}", a, b),
     Instruction::SA(a) => format!("SA {:X}", a),
     Instruction::SB(a) => format!("SB {:X}", a),
     Instruction::SF(a) => format!("SF {:X}", a),
     Instruction::SW(a, b) => format!("SW {:X}, {:X}", a, b),
-    Instruction::WR => format!("WR"),
-    Instruction::PRINT => format!("PRINT"),
+    Instruction::WR => "WR".to_owned(),
+    Instruction::PRINT => "PRINT".to_owned(),
+    Instruction::LI(a, v) => format!("LI {:X}, {}", a, v),
+    Instruction::CALL(a) => format!("CALL {:X}", a + 1),
+    Instruction::RET => "RET".to_owned(),
+    Instruction::PUSH(a) => format!("PUSH {:X}", a),
+    Instruction::POP(a) => format!("POP {:X}", a),
+    Instruction::SUB(a, b) => format!("SUB {:X}, {:X}", a, b),
+    Instruction::OR(a, b) => format!("OR {:X}, {:X}", a, b),
+    Instruction::XOR(a, b) => format!("XOR {:X}, {:X}", a, b),
+    Instruction::ASR(a, b) => format!("ASR {:X}, {:X}", a, b),
+    Instruction::ROL(a, b) => format!("ROL {:X}, {:X}", a, b),
+    Instruction::ROR(a, b) => format!("ROR {:X}, {:X}", a, b),
+    Instruction::HALT(5) => "HALT".to_owned(),
+    Instruction::HALT(a) => format!("HALT {:X}", a),
+    Instruction::EI => "EI".to_owned(),
+    Instruction::DI => "DI".to_owned(),
+    Instruction::SIV(a) => format!("SIV {:X}", a + 1),
+    Instruction::RTI => "RTI".to_owned(),
   }
 }
 
-pub fn print_code(code: &Vec<Instruction>) {
+/// Describes what a register is used for, following the Mic-1 conventions
+/// VMAL is modelled on.
+pub fn register_role(reg: isize) -> &'static str {
+  match reg {
+    0 => "PC, the program counter",
+    1 => "AC, the accumulator",
+    2 => "SP, the stack pointer",
+    3 => "IR, the instruction register",
+    4 => "TIR, the temporary instruction register",
+    5 => "the constant 0",
+    6 => "the constant 1",
+    7 => "the constant -1",
+    8 => "AMASK, the address mask",
+    9 => "SMASK, the stack mask",
+    _ => "general purpose register",
+  }
+}
+
+pub fn print_code(code: &[Instruction]) {
   for (i, op) in code.iter().enumerate() {
     println!("{:>4}: {}", i, op_to_string(op));
   }
 }
+
+/// Reads a `Content-Length` framed JSON message, as used by the debug adapter
+/// and language server protocols, returning `None` at the end of the input.
+pub fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Value>> {
+  let mut length = None;
+  loop {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+      return Ok(None);
+    }
+    let line = line.trim();
+    if line.is_empty() {
+      if length.is_some() {
+        break;
+      }
+      continue;
+    }
+    if let Some((name, value)) = line.split_once(':') {
+      if name.eq_ignore_ascii_case("Content-Length") {
+        length = value.trim().parse::<usize>().ok();
+      }
+    }
+  }
+  let mut body = vec![0; length.unwrap()];
+  input.read_exact(&mut body)?;
+  serde_json::from_slice(&body)
+    .map(Some)
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes a `Content-Length` framed JSON message.
+pub fn write_message<W: Write>(output: &mut W, message: &Value) -> io::Result<()> {
+  let body = message.to_string();
+  write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+  output.flush()
+}
@@ -1,108 +1,355 @@
-use std::collections::{HashMap, HashSet};
-use std::io::{stdin, stdout, Write};
+use std::collections::HashSet;
+use std::fmt::{self, Write as _};
+use std::io::{stdin, stdout, BufRead, Write};
+use std::str::FromStr;
 
 use crate::{
   assembler::Instruction,
+  bus::{timer_port, Bus, Device},
+  decode::{decode, Op},
   util::{op_to_string, SHOULD_SHOW_BINARY, SHOULD_USE_UNSIGNED_INT},
 };
 
-const INT_MAX: isize = 0xffffffff;
+/// Width of a word on the classic machine, in bits.
+pub const DEFAULT_WIDTH: u32 = 32;
+/// Widths a word can have, in bits.
+pub const WIDTHS: [u32; 4] = [8, 16, 32, 64];
+/// Register holding the address of the top of the stack.
+pub const SP: isize = 2;
+/// Where SP starts, just past the stack, unless the program initializes it.
+/// Machines with too little memory to hold it start SP at the standard timer
+/// instead, so the stack sits just below the devices at the top of memory.
+pub const STACK_TOP: isize = 0x10000;
+/// Number of words the stack holds, or a quarter of the memory below its top
+/// if that is smaller. It grows down from where SP starts.
+pub const STACK_SIZE: isize = 0x1000;
 
-fn count_space(num: isize) -> usize {
-  return format!("{}", num).len();
+/// A word with every bit set.
+pub fn word_mask(width: u32) -> isize {
+  if width >= isize::BITS {
+    -1
+  } else {
+    (1 << width) - 1
+  }
+}
+
+/// Index of a register an operation uses, which is always below 16.
+fn reg(r: u8) -> usize {
+  usize::from(r & 0xF)
+}
+
+/// What C and V are left as by the operations `run_decoded` has run since it
+/// last set them.
+#[derive(Debug, Clone, Copy)]
+enum Carry {
+  /// They are already set.
+  Set,
+  /// An ADD of the two words set them.
+  Add(u64, u64),
+  /// A SUB of the second word from the first set them.
+  Sub(u64, u64),
+}
+
+/// An error that stops the program at the instruction that caused it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+  /// `CALL` or `PUSH` with the stack full.
+  StackOverflow,
+  /// `RET` or `POP` with the stack empty.
+  StackUnderflow,
+  /// An access to an address outside memory, which does not wrap around.
+  OutOfRange(isize),
+  /// A read of a word that was never written, when those are faults.
+  UnwrittenRead(isize),
+  /// A jump to a negative instruction index, which only wide words can hold.
+  JumpBeforeStart(isize),
+}
+
+impl fmt::Display for Fault {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Fault::StackOverflow => write!(f, "Stack overflow"),
+      Fault::StackUnderflow => write!(f, "Stack underflow"),
+      Fault::OutOfRange(addr) => write!(f, "Address {:#X} is outside memory", addr),
+      Fault::UnwrittenRead(addr) => {
+        write!(f, "Read of address {:#X}, which was never written", addr)
+      }
+      Fault::JumpBeforeStart(pc) => {
+        write!(f, "Jump to instruction {}, before the program", pc)
+      }
+    }
+  }
+}
+
+/// What reading a word of memory that was never written does.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum UnwrittenReads {
+  /// Reads 0, as on the classic machine.
+  #[default]
+  Allow,
+  /// Reads 0, but warns about it the first time for each address.
+  Warn,
+  Fault,
+}
+
+impl FromStr for UnwrittenReads {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "allow" => Ok(UnwrittenReads::Allow),
+      "warn" => Ok(UnwrittenReads::Warn),
+      "fault" => Ok(UnwrittenReads::Fault),
+      _ => Err(format!("Expected allow, warn or fault, not {}", s)),
+    }
+  }
+}
+
+fn count_space(num: i128) -> usize {
+  format!("{}", num).len()
 }
 #[derive(Debug)]
 pub struct VM {
   pub registers: [isize; 16],
-  pub memory: HashMap<isize, isize>,
+  /// Routes memory accesses to RAM and the other devices of the machine.
+  pub memory: Bus,
   pub MAR: isize,
   pub MBR: isize,
   pub N: bool,
   pub Z: bool,
-  linecount: usize,
+  /// Carry, set by `ADD`, `SUB` and the shifts.
+  pub C: bool,
+  /// Signed overflow, set by `ADD` and `SUB`.
+  pub V: bool,
+  /// Interrupt enable, set by `EI` and cleared by `DI` and while an interrupt
+  /// is handled.
+  pub I: bool,
+  /// Index of the instruction interrupts jump to, set by `SIV`.
+  pub vector: Option<isize>,
+  /// Whether a device has interrupted the program, which is handled once
+  /// interrupts are enabled and a vector is set.
+  pub interrupt_pending: bool,
+  /// Number of bits in a word.
+  pub width: u32,
+  /// Where SP starts in programs that use the stack, just past its bottom.
+  pub stack_top: isize,
+  /// Number of words the stack holds.
+  pub stack_size: isize,
+  /// Index of every `CALL` or interrupted instruction that has not returned
+  /// yet, with the address its return address was pushed to, innermost last.
+  pub calls: Vec<(isize, isize)>,
+  /// The fault that stopped the program, if any.
+  pub fault: Option<Fault>,
+  /// The exit status given to the `HALT` that stopped the program, if any.
+  pub halted: Option<isize>,
+  pub unwritten_reads: UnwrittenReads,
+  /// Warnings about the program so far, as the instruction each is about and
+  /// a message.
+  pub warnings: Vec<(isize, String)>,
+  /// Output of `PRINT` kept for a debugger to take, instead of being written
+  /// to stdout, if it is Some.
+  pub printed: Option<String>,
 }
 
-fn get_int(reg_val: isize) -> isize {
-  if reg_val & (!(INT_MAX >> 1) & INT_MAX) == 0 || *SHOULD_USE_UNSIGNED_INT.read().unwrap() {
-    reg_val
+/// The number a word of `width` bits holds, which is negative if its top bit
+/// is set unless unsigned integers are used.
+pub fn get_int(reg_val: isize, width: u32) -> i128 {
+  let val = (reg_val & word_mask(width)) as u64 as i128;
+  if val >> (width - 1) == 0 || *SHOULD_USE_UNSIGNED_INT.read().unwrap() {
+    val
   } else {
-    -((-reg_val) & INT_MAX)
+    val - (1 << width)
   }
 }
 
-fn clear_lines(count: usize) {
-  let mut stdout = stdout();
-  for i in 0..count {
-    write!(
-      stdout,
-      "\u{001B}[2K{}",
-      if i < count - 1 { "\u{001B}[1A" } else { "" }
-    )
-    .unwrap();
-  }
-  if count > 0 {
-    write!(stdout, "\u{001B}[G").unwrap();
+/// Formats a word as `width` binary digits in groups of four.
+pub fn format_binary(val: isize, width: u32) -> String {
+  let val = (val & word_mask(width)) as u64;
+  format!("{:0>w$b}", val, w = width as usize)
+    .chars()
+    .enumerate()
+    .flat_map(|(i, c)| {
+      if i != 0 && i % 4 == 0 {
+        Some(' ')
+      } else {
+        None
+      }
+      .into_iter()
+      .chain(std::iter::once(c))
+    })
+    .collect::<String>()
+}
+
+/// A command understood by the debugger prompt.
+#[derive(Debug, PartialEq)]
+pub enum DebugCommand {
+  /// Run the current instruction.
+  Next,
+  /// Toggle a breakpoint at an instruction, or at PC if none is given.
+  Breakpoint(Option<isize>),
+  /// Toggle running until the next breakpoint.
+  Continue,
+  /// Run the rest of the program without stopping.
+  Run,
+  /// Show the call stack.
+  Where,
+  /// Stop the program.
+  Quit,
+}
+
+impl DebugCommand {
+  pub fn parse(s: &str) -> Result<Self, String> {
+    let s = s.trim();
+    let (cmd, arg) = match s.split_once(char::is_whitespace) {
+      Some((cmd, arg)) => (cmd, arg.trim()),
+      None => (s, ""),
+    };
+    let cmd = match cmd.chars().next() {
+      Some(c) => c.to_lowercase().collect::<String>(),
+      None => "n".to_owned(),
+    };
+    match cmd.as_str() {
+      "n" => Ok(DebugCommand::Next),
+      "b" if arg.is_empty() => Ok(DebugCommand::Breakpoint(None)),
+      "b" => match arg.parse::<isize>() {
+        Ok(loc) => Ok(DebugCommand::Breakpoint(Some(loc))),
+        Err(_) => Err(format!("Invalid breakpoint location '{}'", arg)),
+      },
+      "c" => Ok(DebugCommand::Continue),
+      "r" => Ok(DebugCommand::Run),
+      "w" => Ok(DebugCommand::Where),
+      "q" => Ok(DebugCommand::Quit),
+      _ => Err(format!("Unknown debug command '{}'", s)),
+    }
   }
-  stdout.flush().unwrap();
 }
 
 impl VM {
-  pub fn new(reg_inits: Vec<(isize, isize)>, mem_inits: Vec<(isize, isize)>) -> Self {
+  /// The standard machine with words of `width` bits, one of `WIDTHS`.
+  pub fn with_width(
+    width: u32,
+    reg_inits: Vec<(isize, isize)>,
+    mem_inits: Vec<(isize, isize)>,
+  ) -> Self {
+    // Memory spans every address, so every initializer is in it.
+    VM::with_bus(width, Bus::new(), reg_inits, mem_inits).unwrap()
+  }
+  /// A machine with words of `width` bits and the devices on `bus`, failing if
+  /// memory is initialized outside the bus without wrapping addresses.
+  pub fn with_bus(
+    width: u32,
+    bus: Bus,
+    reg_inits: Vec<(isize, isize)>,
+    mem_inits: Vec<(isize, isize)>,
+  ) -> Result<Self, String> {
+    let mask = word_mask(width);
+    let top = bus.last_address(width);
+    let stack_top = if (top as u64) < STACK_TOP as u64 {
+      timer_port(top)
+    } else {
+      STACK_TOP
+    };
     let mut vm = VM {
       registers: [0; 16],
-      memory: HashMap::new(),
+      memory: bus,
       MAR: 0,
       MBR: 0,
       N: false,
       Z: false,
-      linecount: 0,
+      C: false,
+      V: false,
+      I: false,
+      vector: None,
+      interrupt_pending: false,
+      width,
+      stack_top,
+      stack_size: STACK_SIZE.min(stack_top / 4),
+      calls: vec![],
+      fault: None,
+      halted: None,
+      unwritten_reads: UnwrittenReads::Allow,
+      warnings: vec![],
+      printed: None,
     };
 
     for (reg, val) in reg_inits {
-      vm.registers[reg as usize] = val & INT_MAX;
+      // A program that sets up SP itself has the stack end there.
+      if reg == SP {
+        vm.stack_top = val & mask;
+      }
+      vm.registers[reg as usize] = val & mask;
     }
 
     vm.registers[0] = 0;
     vm.registers[5] = 0;
     vm.registers[6] = 1;
-    vm.registers[7] = INT_MAX;
+    vm.registers[7] = mask;
 
     for (mem, val) in mem_inits {
-      vm.memory.insert(mem, val & INT_MAX);
+      let addr = vm.address(mem).map_err(|_| {
+        format!(
+          "Cannot initialize address {:#X}, which is outside memory",
+          mem
+        )
+      })?;
+      vm.memory.load(addr, val & mask);
     }
 
-    vm
+    Ok(vm)
   }
   fn set_reg(&mut self, reg: isize, val: isize) {
-    self.registers[reg as usize] = val & INT_MAX;
+    self.registers[reg as usize] = val & word_mask(self.width);
   }
-  fn set_mem(&mut self, mem: isize, val: isize) {
-    self.memory.insert(mem, val & INT_MAX);
+  /// The address in memory `mem` refers to.
+  pub fn address(&self, mem: isize) -> Result<isize, Fault> {
+    self.memory.resolve(mem).ok_or(Fault::OutOfRange(mem))
+  }
+  fn set_mem(&mut self, mem: isize, val: isize) -> Result<(), Fault> {
+    let addr = self.address(mem)?;
+    self.memory.write(addr, val & word_mask(self.width));
+    Ok(())
+  }
+  fn get_mem(&mut self, mem: isize) -> Result<isize, Fault> {
+    let addr = self.address(mem)?;
+    if self.unwritten_reads != UnwrittenReads::Allow && !self.memory.written(addr) {
+      match self.unwritten_reads {
+        UnwrittenReads::Allow => {}
+        UnwrittenReads::Warn => {
+          let message = format!("Read of address {:#X}, which was never written", addr);
+          if !self.warnings.iter().any(|(_, m)| *m == message) {
+            self.warnings.push((self.registers[0], message));
+          }
+        }
+        UnwrittenReads::Fault => return Err(Fault::UnwrittenRead(addr)),
+      }
+    }
+    Ok(self.memory.read(addr) & word_mask(self.width))
+  }
+  /// A register as an unsigned word.
+  fn word(&self, reg: isize) -> u64 {
+    self.registers[reg as usize] as u64
+  }
+  fn sign_bit(&self) -> u64 {
+    1 << (self.width - 1)
   }
   fn SA(&mut self, x: isize) {
     self.MAR = self.registers[x as usize];
   }
   fn RB(&mut self, x: isize) {
-    self.registers[x as usize] = self.MBR;
+    self.set_reg(x, self.MBR);
   }
-  fn RD(&mut self) {
-    self.MBR = 0;
-    match self.memory.get(&self.MAR) {
-      Some(a) => {
-        self.MBR = *a;
-      }
-      None => {}
-    }
+  fn RD(&mut self) -> Result<(), Fault> {
+    self.MBR = self.get_mem(self.MAR)?;
+    Ok(())
   }
-  fn WR(&mut self) {
-    self.memory.insert(self.MAR, self.MBR);
+  fn WR(&mut self) -> Result<(), Fault> {
+    self.set_mem(self.MAR, self.MBR)
   }
   fn SB(&mut self, x: isize) {
     self.MBR = self.registers[x as usize];
   }
   fn SF(&mut self, x: isize) {
     self.Z = self.registers[x as usize] == 0;
-    self.N = (self.registers[x as usize] & 0x80000000) > 0;
+    self.N = self.word(x) & self.sign_bit() != 0;
   }
   fn GO(&mut self, i: isize) {
     self.registers[0] = i;
@@ -117,66 +364,658 @@ impl VM {
       self.registers[0] = i;
     }
   }
+  fn BIC(&mut self, i: isize) {
+    if self.C {
+      self.registers[0] = i;
+    }
+  }
+  fn BIV(&mut self, i: isize) {
+    if self.V {
+      self.registers[0] = i;
+    }
+  }
   fn ADD(&mut self, a: isize, b: isize) {
-    self.registers[a as usize] =
-      (self.registers[a as usize] + self.registers[b as usize]) & INT_MAX;
+    let (x, y) = (self.word(a), self.word(b));
+    let sum = x as u128 + y as u128;
+    self.C = sum >> self.width != 0;
+    let sum = sum as u64;
+    self.V = !(x ^ y) & (x ^ sum) & self.sign_bit() != 0;
+    self.set_reg(a, sum as isize);
   }
   fn AND(&mut self, a: isize, b: isize) {
-    self.registers[a as usize] =
-      (self.registers[a as usize] & self.registers[b as usize]) & INT_MAX;
+    self.set_reg(a, self.registers[a as usize] & self.registers[b as usize]);
   }
   fn MV(&mut self, a: isize, b: isize) {
     self.registers[a as usize] = self.registers[b as usize];
   }
   fn NOT(&mut self, a: isize, b: isize) {
-    self.registers[a as usize] = (!self.registers[b as usize]) & INT_MAX;
+    self.set_reg(a, !self.registers[b as usize]);
   }
   fn LS(&mut self, a: isize, b: isize) {
-    self.registers[a as usize] = (self.registers[b as usize] << 1) & INT_MAX;
+    let b = self.word(b);
+    self.C = b & self.sign_bit() != 0;
+    self.set_reg(a, (b << 1) as isize);
   }
   fn RS(&mut self, a: isize, b: isize) {
-    self.registers[a as usize] = (self.registers[b as usize] >> 1) & INT_MAX;
+    let b = self.word(b);
+    self.C = b & 1 != 0;
+    self.set_reg(a, (b >> 1) as isize);
+  }
+  fn SUB(&mut self, a: isize, b: isize) {
+    let (x, y) = (self.word(a), self.word(b));
+    let difference = x.wrapping_sub(y);
+    self.C = y > x;
+    self.V = (x ^ y) & (x ^ difference) & self.sign_bit() != 0;
+    self.set_reg(a, difference as isize);
+  }
+  fn OR(&mut self, a: isize, b: isize) {
+    self.set_reg(a, self.registers[a as usize] | self.registers[b as usize]);
+  }
+  fn XOR(&mut self, a: isize, b: isize) {
+    self.set_reg(a, self.registers[a as usize] ^ self.registers[b as usize]);
+  }
+  fn ASR(&mut self, a: isize, b: isize) {
+    let b = self.word(b);
+    self.C = b & 1 != 0;
+    self.set_reg(a, ((b >> 1) | (b & self.sign_bit())) as isize);
+  }
+  fn ROL(&mut self, a: isize, b: isize) {
+    let b = self.word(b);
+    self.C = b & self.sign_bit() != 0;
+    self.set_reg(a, ((b << 1) | (b >> (self.width - 1))) as isize);
+  }
+  fn ROR(&mut self, a: isize, b: isize) {
+    let b = self.word(b);
+    self.C = b & 1 != 0;
+    self.set_reg(a, ((b >> 1) | ((b & 1) << (self.width - 1))) as isize);
+  }
+  fn SW(&mut self, a: isize, b: isize) -> Result<(), Fault> {
+    let (mar, mbr) = (self.registers[a as usize], self.registers[b as usize]);
+    self.set_mem(mar, mbr)?;
+    self.MAR = mar;
+    self.MBR = mbr;
+    Ok(())
+  }
+  fn LI(&mut self, a: isize, v: isize) {
+    self.set_reg(a, v);
   }
-  fn SW(&mut self, a: isize, b: isize) {
-    self.MAR = self.registers[a as usize];
-    self.MBR = self.registers[b as usize];
-    self.memory.insert(self.MAR, self.MBR);
+  /// Pushes a word onto the stack.
+  fn push(&mut self, val: isize) -> Result<(), Fault> {
+    let sp = self.registers[SP as usize];
+    if sp <= self.stack_top - self.stack_size || sp > self.stack_top {
+      return Err(Fault::StackOverflow);
+    }
+    self.set_mem(sp - 1, val)?;
+    self.set_reg(SP, sp - 1);
+    Ok(())
+  }
+  /// Pops a word off the stack.
+  fn pop(&mut self) -> Result<isize, Fault> {
+    let sp = self.registers[SP as usize];
+    if sp >= self.stack_top || sp < self.stack_top - self.stack_size {
+      return Err(Fault::StackUnderflow);
+    }
+    let val = self.get_mem(sp)?;
+    self.set_reg(SP, sp + 1);
+    Ok(val)
+  }
+  fn CALL(&mut self, i: isize) -> Result<(), Fault> {
+    self.unwind();
+    let pc = self.registers[0];
+    let sp = self.registers[SP as usize];
+    self.push(pc + 1)?;
+    self.calls.push((pc, sp - 1));
+    self.registers[0] = i;
+    Ok(())
+  }
+  fn RET(&mut self) -> Result<(), Fault> {
+    let sp = self.registers[SP as usize];
+    let next = self.pop()?;
+    if next < 0 {
+      self.registers[SP as usize] = sp;
+      return Err(Fault::JumpBeforeStart(next));
+    }
+    self.unwind();
+    self.registers[0] = next - 1;
+    Ok(())
+  }
+  fn EI(&mut self) {
+    self.I = true;
+  }
+  fn DI(&mut self) {
+    self.I = false;
+  }
+  fn SIV(&mut self, i: isize) {
+    self.vector = Some(i + 1);
+  }
+  fn RTI(&mut self) -> Result<(), Fault> {
+    let sp = self.registers[SP as usize];
+    let flags = self.pop()?;
+    let next = match self.pop() {
+      Ok(next) if next < 0 => Err(Fault::JumpBeforeStart(next)),
+      result => result,
+    };
+    let next = match next {
+      Ok(next) => next,
+      Err(fault) => {
+        self.registers[SP as usize] = sp;
+        return Err(fault);
+      }
+    };
+    self.set_flags(flags);
+    self.unwind();
+    self.registers[0] = next - 1;
+    Ok(())
+  }
+  /// Saves PC and the flags on the stack and jumps to the interrupt vector,
+  /// with interrupts disabled until `RTI` restores the flags.
+  fn interrupt(&mut self, vector: isize) -> Result<(), Fault> {
+    self.unwind();
+    let sp = self.registers[SP as usize];
+    let pc = self.registers[0];
+    if let Err(fault) = self.push(pc).and_then(|_| self.push(self.flags())) {
+      self.registers[SP as usize] = sp;
+      return Err(fault);
+    }
+    self.calls.push((pc, sp - 1));
+    self.I = false;
+    self.interrupt_pending = false;
+    self.registers[0] = vector;
+    Ok(())
+  }
+  /// The flags as a word: Z in bit 0, N in bit 1, C in bit 2, V in bit 3 and
+  /// I in bit 4.
+  pub fn flags(&self) -> isize {
+    (self.Z as isize)
+      | (self.N as isize) << 1
+      | (self.C as isize) << 2
+      | (self.V as isize) << 3
+      | (self.I as isize) << 4
+  }
+  pub fn set_flags(&mut self, flags: isize) {
+    self.Z = flags & 1 != 0;
+    self.N = flags & 2 != 0;
+    self.C = flags & 4 != 0;
+    self.V = flags & 8 != 0;
+    self.I = flags & 16 != 0;
+  }
+  fn PUSH(&mut self, x: isize) -> Result<(), Fault> {
+    self.push(self.registers[x as usize])
   }
-  fn run_op(&mut self, op: &Instruction) {
+  fn POP(&mut self, x: isize) -> Result<(), Fault> {
+    let val = self.pop()?;
+    self.set_reg(x, val);
+    self.unwind();
+    Ok(())
+  }
+  /// Forgets the calls whose return address is no longer on the stack, as
+  /// SP has moved past it.
+  fn unwind(&mut self) {
+    let sp = self.registers[SP as usize];
+    while self.calls.last().is_some_and(|(_, at)| *at < sp) {
+      self.calls.pop();
+    }
+  }
+  /// Index of every call whose return address is still on the stack,
+  /// outermost first.
+  fn frames(&self) -> impl DoubleEndedIterator<Item = isize> + '_ {
+    let sp = self.registers[SP as usize];
+    self
+      .calls
+      .iter()
+      .filter(move |(_, at)| *at >= sp)
+      .map(|(pc, _)| *pc)
+  }
+  /// Number of calls that have not returned.
+  pub fn depth(&self) -> usize {
+    self.frames().count()
+  }
+  /// Points SP at the top of the stack if `code` calls, pushes, pops or takes
+  /// interrupts. Other programs, like classic VMAL ones, start with SP as
+  /// initialized, like any other register.
+  pub fn start_stack(&mut self, code: &[Instruction]) {
+    let uses_stack = code.iter().any(|op| {
+      matches!(
+        op,
+        Instruction::CALL(_)
+          | Instruction::RET
+          | Instruction::PUSH(_)
+          | Instruction::POP(_)
+          | Instruction::EI
+          | Instruction::SIV(_)
+          | Instruction::RTI
+      )
+    });
+    if uses_stack {
+      self.registers[SP as usize] = self.stack_top;
+    }
+  }
+  fn HALT(&mut self, x: isize) {
+    self.halted = Some(self.registers[x as usize]);
+  }
+  fn run_op(&mut self, op: &Instruction) -> Result<(), Fault> {
     match op {
       Instruction::ADD(a, b) => self.ADD(*a, *b),
       Instruction::AND(a, b) => self.AND(*a, *b),
       Instruction::BIN(a) => self.BIN(*a),
       Instruction::BIZ(a) => self.BIZ(*a),
+      Instruction::BIC(a) => self.BIC(*a),
+      Instruction::BIV(a) => self.BIV(*a),
       Instruction::GO(a) => self.GO(*a),
       Instruction::LS(a, b) => self.LS(*a, *b),
       Instruction::MV(a, b) => self.MV(*a, *b),
       Instruction::NOT(a, b) => self.NOT(*a, *b),
       Instruction::RB(a) => self.RB(*a),
-      Instruction::RD => self.RD(),
+      Instruction::RD => self.RD()?,
       Instruction::RS(a, b) => self.RS(*a, *b),
-      Instruction::SW(a, b) => self.SW(*a, *b),
+      Instruction::SW(a, b) => self.SW(*a, *b)?,
       Instruction::SA(a) => self.SA(*a),
       Instruction::SB(a) => self.SB(*a),
       Instruction::SF(a) => self.SF(*a),
-      Instruction::WR => self.WR(),
-      Instruction::PRINT => self.print_registers(),
+      Instruction::WR => self.WR()?,
+      Instruction::LI(a, v) => self.LI(*a, *v),
+      Instruction::PRINT => match self.printed.take() {
+        Some(printed) => self.printed = Some(printed + &self.registers_to_string()),
+        None => self.print_registers(),
+      },
+      Instruction::CALL(a) => self.CALL(*a)?,
+      Instruction::RET => self.RET()?,
+      Instruction::PUSH(a) => self.PUSH(*a)?,
+      Instruction::POP(a) => self.POP(*a)?,
+      Instruction::SUB(a, b) => self.SUB(*a, *b),
+      Instruction::OR(a, b) => self.OR(*a, *b),
+      Instruction::XOR(a, b) => self.XOR(*a, *b),
+      Instruction::ASR(a, b) => self.ASR(*a, *b),
+      Instruction::ROL(a, b) => self.ROL(*a, *b),
+      Instruction::ROR(a, b) => self.ROR(*a, *b),
+      Instruction::HALT(a) => self.HALT(*a),
+      Instruction::EI => self.EI(),
+      Instruction::DI => self.DI(),
+      Instruction::SIV(a) => self.SIV(*a),
+      Instruction::RTI => self.RTI()?,
     }
+    Ok(())
   }
-  pub fn run_code(&mut self, code: &Vec<Instruction>) {
-    while self.registers[0] < code.len() as isize {
-      let op = &code[self.registers[0] as usize];
-      self.run_op(op);
-      self.registers[0] += 1;
+  /// Whether the program has ended, by running past the end of `code`, by
+  /// `HALT` or by a fault. PC is only ever before the program if a debugger
+  /// put it there.
+  pub fn stopped(&self, code: &[Instruction]) -> bool {
+    self.fault.is_some()
+      || self.halted.is_some()
+      || !(0..code.len() as isize).contains(&self.registers[0])
+  }
+  /// Exit status of a stopped program: 1 after a fault, the value given to
+  /// `HALT`, or 0 after running past the end.
+  pub fn exit_status(&self) -> i32 {
+    match (self.fault, self.halted) {
+      (Some(_), _) => 1,
+      (None, Some(status)) => get_int(status, self.width) as i32,
+      (None, None) => 0,
     }
   }
-  pub fn run_debug(&mut self, code: &Vec<Instruction>) -> bool {
+  /// Runs the instruction at PC and moves PC to the next one, or to the
+  /// interrupt vector if a device interrupts. Returns false without doing
+  /// anything once the program has stopped. An instruction that faults changes
+  /// nothing and leaves PC on it, as does `HALT`.
+  pub fn step(&mut self, code: &[Instruction]) -> bool {
+    if self.stopped(code) {
+      return false;
+    }
+    let op = &code[self.registers[0] as usize];
+    // Kept to undo an instruction that writes PC with a negative index.
+    let registers = self.registers;
+    match self.run_op(op) {
+      Ok(()) if self.halted.is_some() => {}
+      Ok(()) if self.registers[0] < -1 => {
+        let next = self.registers[0] + 1;
+        self.registers = registers;
+        self.fault = Some(Fault::JumpBeforeStart(next));
+      }
+      Ok(()) => self.registers[0] += 1,
+      Err(fault) => self.fault = Some(fault),
+    }
+    self.interrupt_pending |= self.memory.tick();
+    if let (true, true, Some(vector)) = (self.interrupt_pending, self.I, self.vector) {
+      if !self.stopped(code) {
+        if let Err(fault) = self.interrupt(vector) {
+          self.fault = Some(fault);
+        }
+      }
+    }
+    true
+  }
+  /// Runs the program like `run_code`, but faster, by decoding it first.
+  pub fn run_fast(&mut self, code: &[Instruction]) {
+    self.run_decoded(code, &decode(code));
+  }
+  /// Runs the operations decoded from `code` until the program stops, ending
+  /// in the same state as running its instructions would.
+  ///
+  /// Devices are only told how many instructions have run before the program
+  /// accesses one of them, before instructions the interpreter runs and once
+  /// the program stops, since nothing else could notice the difference. While
+  /// an interrupt could be taken, which has to happen right after the
+  /// instruction that raised it, the interpreter runs every instruction.
+  pub fn run_decoded(&mut self, code: &[Instruction], ops: &[Op]) {
+    let mask = word_mask(self.width) as u64;
+    let sign = 1 << (self.width - 1);
+    // PC is kept here, and only stored in its register when something could
+    // use it.
+    let mut pc = self.registers[0] as usize;
+    // Instructions run since the devices were last told.
+    let mut ticks = 0;
+    // C and V are only worked out from the last ADD or SUB when something
+    // could read them.
+    let mut carry = Carry::Set;
+    // Memory can be accessed here when nothing but RAM could notice.
+    let plain = self.unwritten_reads == UnwrittenReads::Allow && self.memory.only_ram().is_some();
+    while pc < ops.len() {
+      if self.I && self.vector.is_some() {
+        self.registers[0] = pc as isize;
+        self.tick_many(ticks);
+        ticks = 0;
+        self.step(code);
+        if self.fault.is_some() || self.halted.is_some() {
+          return;
+        }
+        pc = self.registers[0] as usize;
+        continue;
+      }
+      while let Some(&op) = ops.get(pc) {
+        ticks += 1;
+        let r = &mut self.registers;
+        match op {
+          Op::ADD(a, b) => {
+            let (x, y) = (r[reg(a)] as u64, r[reg(b)] as u64);
+            carry = Carry::Add(x, y);
+            r[reg(a)] = (x.wrapping_add(y) & mask) as isize;
+            pc += 1;
+          }
+          Op::SUB(a, b) => {
+            let (x, y) = (r[reg(a)] as u64, r[reg(b)] as u64);
+            carry = Carry::Sub(x, y);
+            r[reg(a)] = (x.wrapping_sub(y) & mask) as isize;
+            pc += 1;
+          }
+          Op::MV(a, b) => {
+            r[reg(a)] = r[reg(b)];
+            pc += 1;
+          }
+          Op::LI(a, v) => {
+            r[reg(a)] = (v as u64 & mask) as isize;
+            pc += 1;
+          }
+          Op::SA(a) => {
+            self.MAR = r[reg(a)];
+            pc += 1;
+          }
+          Op::SB(a) => {
+            self.MBR = r[reg(a)];
+            pc += 1;
+          }
+          Op::RB(a) => {
+            r[reg(a)] = (self.MBR as u64 & mask) as isize;
+            pc += 1;
+          }
+          Op::SF(a) => {
+            self.Z = r[reg(a)] == 0;
+            self.N = r[reg(a)] as u64 & sign != 0;
+            pc += 1;
+          }
+          Op::Test {
+            reg: a,
+            target,
+            zero,
+            next,
+          } => {
+            self.Z = r[reg(a)] == 0;
+            self.N = r[reg(a)] as u64 & sign != 0;
+            ticks += 1;
+            let flag = if zero { self.Z } else { self.N };
+            pc = if flag {
+              target
+            } else {
+              // A GO after the branch runs too.
+              ticks += (next != pc + 2) as isize;
+              next
+            };
+          }
+          Op::ArithTest {
+            a,
+            b,
+            from,
+            sub,
+            target,
+            zero,
+            next,
+          } => {
+            let mut len = 3;
+            if let Some(from) = from {
+              r[reg(a)] = r[reg(from)];
+              len += 1;
+            }
+            let (x, y) = (r[reg(a)] as u64, r[reg(b)] as u64);
+            let result = if sub {
+              carry = Carry::Sub(x, y);
+              x.wrapping_sub(y) & mask
+            } else {
+              carry = Carry::Add(x, y);
+              x.wrapping_add(y) & mask
+            };
+            r[reg(a)] = result as isize;
+            self.Z = result == 0;
+            self.N = result & sign != 0;
+            ticks += len - 1;
+            let flag = if zero { self.Z } else { self.N };
+            pc = if flag {
+              target
+            } else {
+              ticks += (next != pc + len as usize) as isize;
+              next
+            };
+          }
+          Op::Load(a, b) | Op::Store(a, b) | Op::SW(a, b)
+            if plain && self.memory.resolve(r[reg(a)]).is_some() =>
+          {
+            let addr = self.memory.resolve(r[reg(a)]).unwrap();
+            let ram = self.memory.only_ram().unwrap();
+            self.MAR = r[reg(a)];
+            if let Op::Load(..) = op {
+              self.MBR = ram.read(addr) & mask as isize;
+              r[reg(b)] = self.MBR;
+            } else {
+              self.MBR = r[reg(b)];
+              ram.write(addr, self.MBR);
+            }
+            if let Op::SW(..) = op {
+              pc += 1;
+            } else {
+              ticks += 2;
+              pc += 3;
+            }
+          }
+          Op::GO(t) => pc = t,
+          Op::BIN(t) => pc = if self.N { t } else { pc + 1 },
+          Op::BIZ(t) => pc = if self.Z { t } else { pc + 1 },
+          Op::BIC(t) | Op::BIV(t) => {
+            self.set_carry(std::mem::replace(&mut carry, Carry::Set));
+            let flag = if let Op::BIC(_) = op { self.C } else { self.V };
+            pc = if flag { t } else { pc + 1 };
+          }
+          _ => {
+            self.set_carry(std::mem::replace(&mut carry, Carry::Set));
+            match self.run_slow(code, op, pc, ticks) {
+              Some((next, left)) => {
+                pc = next;
+                ticks = left;
+                if self.I && self.vector.is_some() {
+                  break;
+                }
+              }
+              None => return,
+            }
+          }
+        }
+      }
+    }
+    self.set_carry(carry);
+    self.registers[0] = pc as isize;
+    self.tick_many(ticks);
+  }
+  /// Sets C and V the way the ADD or SUB `carry` was left by would have.
+  fn set_carry(&mut self, carry: Carry) {
+    let mask = word_mask(self.width) as u64;
+    let sign = self.sign_bit();
+    match carry {
+      Carry::Set => {}
+      Carry::Add(x, y) => {
+        let (sum, carry) = x.overflowing_add(y);
+        self.C = carry || sum & !mask != 0;
+        self.V = !(x ^ y) & (x ^ sum) & sign != 0;
+      }
+      Carry::Sub(x, y) => {
+        let difference = x.wrapping_sub(y) & mask;
+        self.C = y > x;
+        self.V = (x ^ y) & (x ^ difference) & sign != 0;
+      }
+    }
+  }
+  /// Runs an operation `run_decoded` does not run itself, the `ticks`th
+  /// instruction since the devices were last told, returning where to go next
+  /// and how many instructions they are still to be told about, or `None` if
+  /// the program stopped.
+  #[inline(never)]
+  fn run_slow(
+    &mut self,
+    code: &[Instruction],
+    op: Op,
+    pc: usize,
+    ticks: isize,
+  ) -> Option<(usize, isize)> {
+    let r = &mut self.registers;
+    match op {
+      Op::Run => {
+        self.run_op(&code[pc]).unwrap();
+        Some((pc + 1, ticks))
+      }
+      Op::Load(a, b) => {
+        // SA has run by the time RD reads, which may fault.
+        self.MAR = r[reg(a)];
+        self.registers[0] = pc as isize + 1;
+        let ticks = self.tick_before(self.MAR, ticks + 1);
+        match self.get_mem(self.MAR) {
+          Ok(val) => {
+            self.MBR = val;
+            self.registers[reg(b)] = val;
+            Some((pc + 3, ticks + 1))
+          }
+          Err(fault) => self.stop_decoded(fault, ticks),
+        }
+      }
+      Op::Store(a, b) => {
+        // SA and SB have run by the time WR writes, which may fault.
+        self.MAR = r[reg(a)];
+        self.MBR = r[reg(b)];
+        self.registers[0] = pc as isize + 2;
+        let ticks = self.tick_before(self.MAR, ticks + 2);
+        match self.WR() {
+          Ok(()) => Some((pc + 3, ticks)),
+          Err(fault) => self.stop_decoded(fault, ticks),
+        }
+      }
+      Op::RD | Op::WR | Op::SW(..) | Op::CALL(_) | Op::RET | Op::PUSH(_) | Op::POP(_) => {
+        let sp = r[SP as usize];
+        let addr = match op {
+          Op::SW(a, _) => r[reg(a)],
+          Op::CALL(_) | Op::PUSH(_) => sp - 1,
+          Op::RET | Op::POP(_) => sp,
+          _ => self.MAR,
+        };
+        self.registers[0] = pc as isize;
+        let ticks = self.tick_before(addr, ticks);
+        let result = match op {
+          Op::RD => self.RD(),
+          Op::WR => self.WR(),
+          Op::SW(a, b) => self.SW(a as isize, b as isize),
+          Op::CALL(t) => self.CALL(t as isize - 1),
+          Op::RET => self.RET(),
+          Op::PUSH(a) => self.PUSH(a as isize),
+          Op::POP(a) => self.POP(a as isize),
+          _ => unreachable!(),
+        };
+        match result {
+          Ok(()) => Some(((self.registers[0] + 1) as usize, ticks)),
+          Err(fault) => self.stop_decoded(fault, ticks),
+        }
+      }
+      _ => {
+        self.registers[0] = pc as isize;
+        self.tick_many(ticks - 1);
+        self.step(code);
+        if self.fault.is_some() || self.halted.is_some() {
+          return None;
+        }
+        Some((self.registers[0] as usize, 0))
+      }
+    }
+  }
+  /// Tells the devices about every instruction before the one being run if it
+  /// accesses one of them at `mem`, since only they could notice, returning
+  /// how many instructions are still to be told about.
+  fn tick_before(&mut self, mem: isize, ticks: isize) -> isize {
+    if self
+      .memory
+      .resolve(mem)
+      .is_some_and(|addr| self.memory.mapped(addr))
+    {
+      self.tick_many(ticks - 1);
+      1
+    } else {
+      ticks
+    }
+  }
+  /// Stops a program run by `run_decoded` on a fault.
+  fn stop_decoded(&mut self, fault: Fault, ticks: isize) -> Option<(usize, isize)> {
+    self.fault = Some(fault);
+    self.tick_many(ticks);
+    None
+  }
+  /// Lets the devices know `ticks` instructions have run.
+  fn tick_many(&mut self, ticks: isize) {
+    if ticks > 0 {
+      self.interrupt_pending |= self.memory.tick_many(ticks);
+    }
+  }
+  /// Index of the instruction every frame of the call stack is at, innermost
+  /// first: PC, then every `CALL` that has not returned.
+  pub fn backtrace(&self) -> Vec<isize> {
+    std::iter::once(self.registers[0])
+      .chain(self.frames().rev())
+      .collect()
+  }
+  pub fn run_code(&mut self, code: &[Instruction]) {
+    while self.step(code) {}
+  }
+  pub fn run_debug(&mut self, code: &[Instruction]) -> bool {
     let stdin = stdin();
+    let mut input = stdin.lock();
+    self.run_debug_with(code, &mut input, false)
+  }
+  /// Runs the debugger, reading commands line by line from `input`.
+  ///
+  /// When `echo` is set every command is printed after the prompt, so that a
+  /// scripted session reads the same as one typed at the terminal. Reaching
+  /// the end of `input` runs the rest of the program without stopping.
+  pub fn run_debug_with<R: BufRead>(
+    &mut self,
+    code: &[Instruction],
+    input: &mut R,
+    echo: bool,
+  ) -> bool {
     let mut breakpoints: HashSet<isize> = HashSet::new();
     let mut cont = false;
     let mut debug = true;
-    while self.registers[0] < code.len() as isize {
-      self.linecount = 0;
+    while !self.stopped(code) {
       let op = &code[self.registers[0] as usize];
       let on_bp = breakpoints.contains(&self.registers[0]);
       if debug && (!cont || on_bp) {
@@ -185,74 +1024,98 @@ impl VM {
         println!("Flags:");
         println!("  N: {}", self.N);
         println!("  Z: {}", self.Z);
+        println!("  C: {}", self.C);
+        println!("  V: {}", self.V);
+        println!("  I: {}", self.I);
         println!();
-        self.linecount += 4;
 
         if cont {
           println!("Continue till Breakpoint");
-          self.linecount += 1;
         }
         if on_bp {
           println!("BREAKPOINT");
-          self.linecount += 1;
         }
         println!("Operation: {}", op_to_string(op));
-        self.linecount += 1;
 
         loop {
           let mut s = String::new();
-          print!("Debug (n,b,c,r,q): ");
+          print!("Debug (n,b,c,r,w,q): ");
           stdout().flush().unwrap();
-          stdin
+          let read = input
             .read_line(&mut s)
             .expect("Did not enter a correct string");
-          self.linecount += 1;
-          let s = s.trim();
-          let s = if s.len() == 0 {
-            "n".to_owned()
-          } else {
-            s[0..1].to_lowercase()
-          };
-          if s == "n" {
-          } else if s == "b" {
-            print!("Turning Breakpoint ");
-            self.linecount += 1;
-            if breakpoints.contains(&self.registers[0]) {
-              breakpoints.remove(&self.registers[0]);
-              println!("OFF");
+          if read == 0 {
+            if echo {
+              println!("<end of script>");
             } else {
-              breakpoints.insert(self.registers[0]);
-              println!("ON");
+              println!();
             }
-            continue;
-          } else if s == "c" {
-            cont = !cont;
-          } else if s == "r" {
             debug = false;
-          } else if s == "q" {
-            return false;
-          } else {
-            panic!();
+            break;
+          }
+          if echo {
+            println!("{}", s.trim_end());
+          }
+          if echo && s.trim().starts_with('#') {
+            continue;
+          }
+          match DebugCommand::parse(&s) {
+            Ok(DebugCommand::Next) => {}
+            Ok(DebugCommand::Breakpoint(loc)) => {
+              let loc = match loc {
+                Some(loc) => {
+                  print!("Turning Breakpoint at {} ", loc);
+                  loc
+                }
+                None => {
+                  print!("Turning Breakpoint ");
+                  self.registers[0]
+                }
+              };
+              if breakpoints.contains(&loc) {
+                breakpoints.remove(&loc);
+                println!("OFF");
+              } else {
+                breakpoints.insert(loc);
+                println!("ON");
+              }
+              continue;
+            }
+            Ok(DebugCommand::Continue) => cont = !cont,
+            Ok(DebugCommand::Run) => debug = false,
+            Ok(DebugCommand::Where) => {
+              println!("Call stack:");
+              for (i, loc) in self.backtrace().into_iter().enumerate() {
+                println!("  #{} {:>4}: {}", i, loc, op_to_string(&code[loc as usize]));
+              }
+              continue;
+            }
+            Ok(DebugCommand::Quit) => return false,
+            Err(err) => {
+              println!("{}", err);
+              continue;
+            }
           }
           break;
         }
-        clear_lines(self.linecount + 1);
       }
-      self.run_op(&op);
-      self.registers[0] += 1;
+      self.step(code);
     }
-    return true;
+    true
   }
-  pub fn print_registers(&mut self) {
-    println!();
-    self.linecount += 1;
-    println!("Registers: ");
-    self.linecount += 1;
+  pub fn print_registers(&self) {
+    print!("{}", self.registers_to_string());
+  }
+  /// Formats the registers the way `PRINT` shows them.
+  pub fn registers_to_string(&self) -> String {
+    let mut s = String::new();
+    writeln!(s).unwrap();
+    writeln!(s, "Registers: ").unwrap();
     let binary = *SHOULD_SHOW_BINARY.read().unwrap();
     let padding = self
       .registers
       .iter()
-      .map(|x| count_space(get_int(*x)))
+      .map(|x| count_space(get_int(*x, self.width)))
       .max()
       .unwrap();
     for i in 0..self.registers.len() {
@@ -270,46 +1133,602 @@ impl VM {
       };
       let v = self.registers[loc];
       if binary {
-        print!(
-          "{:X}: {}  ",
-          loc,
-          format!("{:0>32b}", v)
-            .chars()
-            .enumerate()
-            .flat_map(|(i, c)| {
-              if i != 0 && i % 4 == 0 {
-                Some(' ')
-              } else {
-                None
-              }
-              .into_iter()
-              .chain(std::iter::once(c))
-            })
-            .collect::<String>()
-        );
+        write!(s, "{:X}: {}  ", loc, format_binary(v, self.width)).unwrap();
       } else {
-        print!("{:X}: {:width$}\t", loc, get_int(v), width = padding);
+        write!(
+          s,
+          "{:X}: {:width$}\t",
+          loc,
+          get_int(v, self.width),
+          width = padding
+        )
+        .unwrap();
       }
       if (i + 1) % x == 0 {
-        println!();
-        self.linecount += 1;
+        writeln!(s).unwrap();
       }
     }
+    s
   }
   pub fn print_memory(&mut self) {
     println!();
     println!("Memory:");
     let mut last: Option<isize> = None;
-    let mut items = self.memory.iter().collect::<Vec<_>>();
-    items.sort_by_key(|x| x.0);
-    for (loc, val) in items {
+    for (loc, val) in self.memory.cells() {
       if let Some(i) = last {
         if loc - i > 1 {
           println!("  ... {} empty locations ...", loc - i - 1);
         }
       }
-      println!("  [{}]: {}", loc, get_int(*val));
-      last = Some(*loc);
+      println!("  [{}]: {}", loc, get_int(val, self.width));
+      last = Some(loc);
+    }
+  }
+}
+
+#[test]
+fn test_debug_script() {
+  use crate::assembler::Assembly;
+  let a = Assembly::try_assemble("ADD E, 6;\nADD E, 6;\nADD E, 6;\nADD E, 6;").unwrap();
+  let mut vm = VM::with_width(a.width, a.reg_inits, a.mem_inits);
+  let mut script = std::io::Cursor::new("n\n# comment\nb 3\nc\nq\n");
+  assert!(!vm.run_debug_with(&a.instructions, &mut script, true));
+  assert_eq!(vm.registers[0], 3);
+  assert_eq!(vm.registers[0xE], 3);
+
+  let mut vm = VM::with_width(DEFAULT_WIDTH, vec![], vec![]);
+  let mut script = std::io::Cursor::new("n\n");
+  assert!(vm.run_debug_with(&a.instructions, &mut script, true));
+  assert_eq!(vm.registers[0xE], 4);
+}
+
+#[test]
+fn test_debug_command_parse() {
+  assert_eq!(DebugCommand::parse(""), Ok(DebugCommand::Next));
+  assert_eq!(
+    DebugCommand::parse("Break 4"),
+    Ok(DebugCommand::Breakpoint(Some(4)))
+  );
+  assert_eq!(DebugCommand::parse("continue"), Ok(DebugCommand::Continue));
+  assert!(DebugCommand::parse("é").is_err());
+  assert!(DebugCommand::parse("日本 3").is_err());
+}
+
+#[test]
+fn test_read_write_mask() {
+  let code = vec![
+    Instruction::SA(6),
+    Instruction::RD,
+    Instruction::RB(0xA),
+    Instruction::SA(5),
+    Instruction::WR,
+  ];
+  let mut vm = VM::with_width(32, vec![], vec![(1, 0x1FFFFFFFF)]);
+  vm.run_code(&code);
+  assert_eq!(vm.registers[0xA], 0xFFFFFFFF);
+  assert_eq!(vm.memory.peek(0), Some(0xFFFFFFFF));
+
+  // MBR is public, so it may hold more than a word.
+  let mut vm = VM::with_width(32, vec![], vec![]);
+  vm.MBR = -1;
+  vm.run_code(&code[2..]);
+  assert_eq!(vm.registers[0xA], 0xFFFFFFFF);
+  assert_eq!(vm.memory.peek(0), Some(0xFFFFFFFF));
+}
+
+#[test]
+fn test_stack() {
+  use crate::assembler::Assembly;
+  let a = Assembly::try_assemble(
+    "A: 5;\nCALL double;\nCALL double;\nGO end;\nLBL double;\n  PUSH A;\n  POP B;\n  ADD A, B;\n  RET;\nLBL end;",
+  ).unwrap();
+  let mut vm = VM::with_width(a.width, a.reg_inits, a.mem_inits);
+  assert_eq!(vm.registers[SP as usize], 0);
+  vm.start_stack(&a.instructions);
+  assert_eq!(vm.registers[SP as usize], STACK_TOP);
+  for _ in 0..3 {
+    vm.step(&a.instructions);
+  }
+  assert_eq!(vm.backtrace(), vec![5, 0]);
+  assert_eq!(vm.memory.peek(STACK_TOP - 1), Some(1));
+  vm.run_code(&a.instructions);
+  assert_eq!(vm.fault, None);
+  assert_eq!(vm.registers[0xA], 20);
+  assert_eq!(vm.registers[SP as usize], STACK_TOP);
+  assert!(vm.calls.is_empty());
+
+  let a = Assembly::try_assemble("POP A;").unwrap();
+  let mut vm = VM::with_width(a.width, a.reg_inits, a.mem_inits);
+  vm.start_stack(&a.instructions);
+  vm.run_code(&a.instructions);
+  assert_eq!(vm.fault, Some(Fault::StackUnderflow));
+  assert_eq!(vm.registers[0], 0);
+
+  let a = Assembly::try_assemble("LBL loop;\nCALL loop;").unwrap();
+  let mut vm = VM::with_width(a.width, a.reg_inits, a.mem_inits);
+  vm.start_stack(&a.instructions);
+  vm.run_code(&a.instructions);
+  assert_eq!(vm.fault, Some(Fault::StackOverflow));
+  assert_eq!(vm.calls.len(), STACK_SIZE as usize);
+  assert_eq!(vm.registers[SP as usize], STACK_TOP - STACK_SIZE);
+  assert!(!vm.step(&a.instructions));
+
+  // Calls whose return address is popped, or dropped by moving SP, are
+  // forgotten.
+  let a = Assembly::try_assemble(
+    "LI A, 20000;\nLBL loop;\n  CALL f;\nLBL f;\n  POP B;\n  SUB A, 6;\n  SF A;\n  BIZ done;\n  GO loop;\nLBL done;\nCALL g;\nLBL g;\nADD 2, 6;",
+  ).unwrap();
+  let mut vm = VM::with_width(a.width, a.reg_inits, a.mem_inits);
+  vm.start_stack(&a.instructions);
+  for _ in 0..2 {
+    vm.step(&a.instructions);
+  }
+  assert_eq!((vm.backtrace(), vm.calls.len()), (vec![2, 1], 1));
+  vm.step(&a.instructions);
+  assert_eq!((vm.backtrace(), vm.calls.len()), (vec![3], 0));
+  vm.run_code(&a.instructions);
+  assert_eq!(vm.fault, None);
+  assert_eq!(vm.registers[SP as usize], STACK_TOP);
+  assert_eq!((vm.backtrace(), vm.depth()), (vec![9], 0));
+  assert_eq!(vm.calls.len(), 1);
+
+  // Programs that do not use the stack start with SP like any register.
+  let a = Assembly::try_assemble("ADD 2, 6;\n").unwrap();
+  let mut vm = VM::with_width(a.width, a.reg_inits, a.mem_inits);
+  vm.start_stack(&a.instructions);
+  vm.run_code(&a.instructions);
+  assert_eq!(vm.registers[SP as usize], 1);
+}
+
+#[test]
+fn test_alu() {
+  use crate::assembler::Assembly;
+  let a = Assembly::try_assemble(
+    "A: 5;\nB: 0x80000003;\nC: 6;\nMV D, A;\nSUB D, C;\nMV E, A;\nOR E, C;\nXOR C, A;\nASR F, B;\nROL 8, B;\nROR 9, B;\nRS 1, B;",
+  ).unwrap();
+  let mut vm = VM::with_width(a.width, a.reg_inits, a.mem_inits);
+  vm.run_code(&a.instructions);
+  assert_eq!(vm.registers[0xD], word_mask(32));
+  assert_eq!(vm.registers[0xE], 7);
+  assert_eq!(vm.registers[0xC], 3);
+  assert_eq!(vm.registers[0xF], 0xc0000001);
+  assert_eq!(vm.registers[8], 7);
+  assert_eq!(vm.registers[9], 0xc0000001);
+  assert_eq!(vm.registers[1], 0x40000001);
+}
+
+#[test]
+fn test_carry_and_overflow() {
+  use crate::assembler::Assembly;
+  // Adds the 64-bit numbers in A:B and C:D, carrying from the low words.
+  let a = Assembly::try_assemble(
+    "A: 1;\nB: 0xFFFFFFFF;\nC: 2;\nD: 1;\nADD B, D;\nBIC carry;\nGO high;\nLBL carry;\n  ADD A, 6;\nLBL high;\n  ADD A, C;",
+  ).unwrap();
+  let mut vm = VM::with_width(a.width, a.reg_inits, a.mem_inits);
+  vm.run_code(&a.instructions);
+  assert_eq!((vm.registers[0xA], vm.registers[0xB]), (4, 0));
+  assert!(!vm.C && !vm.V);
+
+  let mut vm = VM::with_width(
+    DEFAULT_WIDTH,
+    vec![(0xA, 0x7fffffff), (0xB, 0x80000000)],
+    vec![],
+  );
+  vm.run_code(&[Instruction::ADD(0xA, 6)]);
+  assert!(vm.V && !vm.C);
+  vm.run_code(&[Instruction::SUB(0xB, 6)]);
+  assert!(vm.V && !vm.C);
+  vm.registers[0] = 0;
+  vm.run_code(&[Instruction::SUB(0xD, 6)]);
+  assert!(vm.C && !vm.V);
+  vm.registers[0] = 0;
+  vm.run_code(&[Instruction::LS(0xC, 0xA), Instruction::BIV(-1)]);
+  assert!(vm.C);
+}
+
+#[test]
+fn test_width() {
+  let mut vm = VM::with_width(8, vec![(0xA, 0x7F), (0xB, 0x1FF)], vec![]);
+  assert_eq!((vm.registers[7], vm.registers[0xB]), (0xFF, 0xFF));
+  assert_eq!(get_int(vm.registers[0xB], 8), -1);
+  assert_eq!(format_binary(vm.registers[0xA], 8), "0111 1111");
+  vm.run_code(&[Instruction::ADD(0xA, 6), Instruction::SF(0xA)]);
+  assert_eq!(get_int(vm.registers[0xA], 8), -128);
+  assert!(vm.V && vm.N && !vm.C);
+  vm.registers[0] = 0;
+  vm.run_code(&[Instruction::ADD(0xB, 6), Instruction::SF(0xB)]);
+  assert_eq!(vm.registers[0xB], 0);
+  assert!(vm.C && vm.Z && !vm.V);
+
+  let mut vm = VM::with_width(16, vec![(0xA, 0x8000)], vec![]);
+  assert_eq!(vm.stack_top, 0xFFFC);
+  vm.run_code(&[Instruction::LS(0xB, 0xA), Instruction::ASR(0xC, 0xA)]);
+  assert_eq!((vm.registers[0xB], vm.registers[0xC]), (0, 0xC000));
+  assert_eq!(get_int(vm.registers[0xC], 16), -0x4000);
+
+  let mut vm = VM::with_width(64, vec![(0xA, isize::MAX)], vec![]);
+  assert_eq!(vm.registers[7], -1);
+  vm.run_code(&[Instruction::ADD(0xA, 6), Instruction::NOT(0xB, 0xA)]);
+  assert_eq!(get_int(vm.registers[0xA], 64), isize::MIN as i128);
+  assert!(vm.V && !vm.C);
+  assert_eq!(vm.registers[0xB], isize::MAX);
+  assert_eq!(format_binary(vm.registers[0xA], 64).len(), 64 + 15);
+}
+
+#[test]
+fn test_halt() {
+  use crate::assembler::Assembly;
+  let a =
+    Assembly::try_assemble("A: 1;\nSF A;\nBIZ done;\nLI B, -3;\nHALT B;\nLBL done;\nADD A, A;")
+      .unwrap();
+  let mut vm = VM::with_width(a.width, a.reg_inits, a.mem_inits);
+  vm.run_code(&a.instructions);
+  assert_eq!(vm.halted, Some(-3 & word_mask(32)));
+  assert_eq!(vm.exit_status(), -3);
+  assert_eq!(vm.registers[0], 3);
+  assert_eq!(vm.registers[0xA], 1);
+  assert!(!vm.step(&a.instructions));
+
+  let mut vm = VM::with_width(DEFAULT_WIDTH, vec![], vec![]);
+  vm.run_code(&[Instruction::HALT(5), Instruction::ADD(0xA, 6)]);
+  assert_eq!((vm.halted, vm.exit_status()), (Some(0), 0));
+  assert_eq!(vm.registers[0xA], 0);
+  let mut vm = VM::with_width(DEFAULT_WIDTH, vec![], vec![]);
+  vm.run_code(&[Instruction::ADD(0xA, 6)]);
+  assert_eq!((vm.halted, vm.exit_status()), (None, 0));
+}
+
+#[test]
+fn test_console() {
+  use crate::assembler::Assembly;
+  use crate::console::Console;
+  // Echoes the input until it runs out, then prints the number of characters.
+  let a = Assembly::try_assemble(
+    "LI A, -2;\nLBL loop;\n  SA A;\n  RD;\n  RB B;\n  MV C, B;\n  ADD C, 6;\n  SF C;\n  BIZ done;\n  SW A, B;\n  ADD D, 6;\n  GO loop;\nLBL done;\nSW 7, D;",
+  ).unwrap();
+  // Without the devices, the console's ports are memory.
+  let mut vm = VM::with_width(a.width, vec![], vec![]);
+  assert!(vm.memory.device_mut::<Console>().is_none());
+  vm.run_code(&[Instruction::SW(7, 6)]);
+  assert_eq!(vm.memory.read(word_mask(32)), 1);
+  let mut bus = Bus::new();
+  bus.map_devices(a.width);
+  let mut vm = VM::with_bus(a.width, bus, a.reg_inits, a.mem_inits).unwrap();
+  let console = vm.memory.device_mut::<Console>().unwrap();
+  console.set_input(Box::new(std::io::Cursor::new("hi\n")));
+  console.capture();
+  vm.run_code(&a.instructions);
+  let console = vm.memory.device_mut::<Console>().unwrap();
+  assert_eq!(console.take_output(), "hi\n3");
+  assert_eq!(vm.registers[0xB], word_mask(32));
+  assert!(vm.memory.cells().is_empty());
+
+  // A console below the stack, a timer and ROM, with nothing else.
+  let machine = r#"{"devices": [
+    {"type": "console", "start": "0x10"},
+    {"type": "timer", "start": "0x12"},
+    {"type": "rom", "start": "0x20", "size": 2},
+    {"type": "ram", "start": "0xC0", "size": "0x3F"}
+  ]}"#;
+  let bus = Bus::from_description(machine, 8).unwrap();
+  let mut vm = VM::with_bus(8, bus, vec![(0xA, 'x' as isize)], vec![(0x20, 9)]).unwrap();
+  vm.memory.device_mut::<Console>().unwrap().capture();
+  let code = [
+    Instruction::LI(0xB, 0x10),
+    Instruction::SW(0xB, 0xA),
+    Instruction::PUSH(0xA),
+    Instruction::LI(0xB, 0x20),
+    Instruction::SW(0xB, 0xB),
+    Instruction::LI(0xB, 0x12),
+    Instruction::SA(0xB),
+    Instruction::RD,
+  ];
+  vm.start_stack(&code);
+  vm.run_code(&code);
+  assert_eq!(vm.fault, None);
+  assert_eq!(vm.MBR, 7);
+  let console = vm.memory.device_mut::<Console>().unwrap();
+  assert_eq!(console.take_output(), "x");
+  assert_eq!(vm.memory.cells(), vec![(0x20, 9), (0xFB, 'x' as isize)]);
+}
+
+#[test]
+fn test_interrupts() {
+  use crate::assembler::Assembly;
+  // Counts timer interrupts in E until there have been three. The handler
+  // changes the flags, which RTI restores before BIZ tests them.
+  let program = "SIV handler;\nLI A, -3;\nLI B, 10;\nSW A, B;\nLI F, 3;\nEI;\nLBL loop;\n  MV D, E;\n  SUB D, F;\n  SF D;\n  BIZ done;\n  GO loop;\nLBL done;\nHALT;\nLBL handler;\n  ADD E, 6;\n  SF 6;\n  RTI;";
+  let machine = |a: &Assembly| {
+    let mut bus = Bus::new();
+    bus.map_devices(a.width);
+    VM::with_bus(a.width, bus, a.reg_inits.clone(), a.mem_inits.clone()).unwrap()
+  };
+  let a = Assembly::try_assemble(program).unwrap();
+  let mut vm = machine(&a);
+  vm.start_stack(&a.instructions);
+  vm.run_code(&a.instructions);
+  assert_eq!(vm.fault, None);
+  assert_eq!(vm.halted, Some(0));
+  assert_eq!(vm.registers[0xE], 3);
+  assert!(vm.I);
+  assert_eq!(vm.registers[SP as usize], vm.stack_top);
+  assert!(vm.calls.is_empty());
+
+  // Without EI the timer is ignored.
+  let a = Assembly::try_assemble(program.replace("EI;", "DI;").as_str()).unwrap();
+  let mut vm = machine(&a);
+  for _ in 0..100 {
+    assert!(vm.step(&a.instructions));
+  }
+  assert_eq!(vm.registers[0xE], 0);
+
+  // Entering a handler saves PC and the flags and turns interrupts off.
+  let code = [
+    Instruction::RET,
+    Instruction::HALT(5),
+    Instruction::RET,
+    Instruction::RTI,
+  ];
+  let mut vm = VM::with_width(8, vec![], vec![]);
+  vm.start_stack(&code);
+  vm.registers[0] = 1;
+  vm.N = true;
+  vm.I = true;
+  vm.interrupt(3).unwrap();
+  assert_eq!(vm.registers[0], 3);
+  assert!(!vm.I);
+  vm.N = false;
+  vm.run_code(&code);
+  assert_eq!(vm.halted, Some(0));
+  assert_eq!(vm.registers[0], 1);
+  assert!(vm.I && vm.N);
+}
+
+#[test]
+fn test_memory_size() {
+  let code = [
+    Instruction::LI(0xA, 0x102),
+    Instruction::LI(0xB, 9),
+    Instruction::SW(0xA, 0xB),
+    Instruction::RD,
+  ];
+  let mut vm = VM::with_bus(16, Bus::with_size(16, 0x100).unwrap(), vec![], vec![]).unwrap();
+  assert_eq!(vm.stack_top, 0xFC);
+  vm.run_code(&code);
+  assert_eq!(vm.fault, Some(Fault::OutOfRange(0x102)));
+  assert_eq!((vm.registers[0], vm.MAR, vm.MBR), (2, 0, 0));
+  assert!(vm.memory.cells().is_empty());
+
+  let mut bus = Bus::with_size(16, 0x100).unwrap();
+  bus.wrap = true;
+  let mut vm = VM::with_bus(16, bus, vec![], vec![(0x101, 4)]).unwrap();
+  vm.run_code(&code);
+  assert_eq!(vm.fault, None);
+  assert_eq!(vm.MBR, 9);
+  assert_eq!(vm.memory.cells(), vec![(1, 4), (2, 9)]);
+
+  let bus = Bus::with_size(16, 0x100).unwrap();
+  assert_eq!(
+    VM::with_bus(16, bus, vec![], vec![(3, 1), (0x200, 1)]).unwrap_err(),
+    "Cannot initialize address 0x200, which is outside memory"
+  );
+  let mut bus = Bus::with_size(16, 0x100).unwrap();
+  bus.wrap = true;
+  let vm = VM::with_bus(16, bus, vec![], vec![(0x203, 1)]).unwrap();
+  assert_eq!(vm.memory.cells(), vec![(3, 1)]);
+
+  let code = [
+    Instruction::LI(0xA, 0x10),
+    Instruction::SA(0xA),
+    Instruction::RD,
+    Instruction::RD,
+    Instruction::POP(0xB),
+  ];
+  let mut vm = VM::with_width(16, vec![], vec![]);
+  vm.unwritten_reads = UnwrittenReads::Warn;
+  vm.run_code(&code);
+  assert_eq!(vm.fault, Some(Fault::StackUnderflow));
+  assert_eq!(
+    vm.warnings,
+    vec![(
+      2,
+      "Read of address 0x10, which was never written".to_owned()
+    )]
+  );
+  let mut vm = VM::with_width(16, vec![], vec![]);
+  vm.unwritten_reads = UnwrittenReads::Fault;
+  vm.run_code(&code);
+  assert_eq!(vm.fault, Some(Fault::UnwrittenRead(0x10)));
+  assert_eq!(vm.registers[0], 2);
+  let mut vm = VM::with_width(16, vec![], vec![(0x10, 3)]);
+  vm.unwritten_reads = UnwrittenReads::Fault;
+  vm.run_code(&code[..4]);
+  assert_eq!((vm.fault, vm.MBR), (None, 3));
+}
+
+#[test]
+fn test_jump_before_start() {
+  let programs = [
+    vec![Instruction::LI(0xA, 1), Instruction::LI(0, -3)],
+    vec![
+      Instruction::LI(0xA, -5),
+      Instruction::PUSH(0xA),
+      Instruction::RET,
+    ],
+  ];
+  // The second program pushes a word.
+  for (code, pc, next, pushed) in [(&programs[0], 1, -2, None), (&programs[1], 2, -5, Some(1))] {
+    for fast in [false, true] {
+      let mut vm = VM::with_width(64, vec![], vec![]);
+      vm.start_stack(code);
+      if fast {
+        vm.run_fast(code);
+      } else {
+        vm.run_code(code);
+      }
+      assert_eq!(vm.fault, Some(Fault::JumpBeforeStart(next)));
+      assert_eq!(vm.registers[0], pc);
+      let sp = pushed.map_or(0, |pushed| vm.stack_top - pushed);
+      assert_eq!(vm.registers[SP as usize], sp);
+      assert!(vm.stopped(code));
+    }
+  }
+  let mut vm = VM::with_width(64, vec![], vec![]);
+  vm.registers[0] = -1;
+  assert!(!vm.step(&programs[0]));
+}
+
+#[test]
+fn test_run_fast() {
+  use crate::assembler::Assembly;
+  use crate::bus::{Device, Random, Timer};
+  use crate::console::Console;
+  use crate::decode::decode;
+
+  fn state(vm: &mut VM) -> impl PartialEq + fmt::Debug {
+    let timer = vm.memory.device_mut::<Timer>().map(|t| (t.count, t.period));
+    let output = vm.memory.device_mut::<Console>().map(|c| c.take_output());
+    (
+      (
+        vm.registers,
+        vm.MAR,
+        vm.MBR,
+        vm.flags(),
+        vm.interrupt_pending,
+      ),
+      (vm.fault, vm.halted, vm.calls.clone(), vm.warnings.clone()),
+      (vm.memory.cells(), timer, output),
+    )
+  }
+  // Runs code both ways from the same machine, unless the interpreter takes
+  // too long.
+  fn compare(code: &[Instruction], machine: impl Fn() -> VM) -> bool {
+    let (mut slow, mut fast) = (machine(), machine());
+    for vm in [&mut slow, &mut fast] {
+      vm.start_stack(code);
+      if let Some(console) = vm.memory.device_mut::<Console>() {
+        console.set_input(Box::new(std::io::Cursor::new("ab")));
+        console.capture();
+      }
+    }
+    for _ in 0..10_000 {
+      if !slow.step(code) {
+        fast.run_decoded(code, &decode(code));
+        assert_eq!(state(&mut slow), state(&mut fast), "{:?}", code);
+        return true;
+      }
+    }
+    false
+  }
+
+  for program in [
+    include_str!("../memory.vmal").replace("0x4000", "0x40"),
+    "SIV handler;\nLI A, -3;\nLI B, 10;\nSW A, B;\nLI F, 3;\nEI;\nLBL loop;\n  MV D, E;\n  SUB D, F;\n  SF D;\n  BIZ done;\n  GO loop;\nLBL done;\nHALT;\nLBL handler;\n  ADD E, 6;\n  SF 6;\n  RTI;".to_owned(),
+    "LI A, 5;\nLBL loop;\n  CALL f;\n  ADD A, 7;\n  SF A;\n  BIN done;\n  GO loop;\nLBL f;\n  PUSH A;\n  POP B;\n  ADD C, B;\n  RET;\nLBL done;\nLI D, -2;\nSW D, C;\nPOP E;".to_owned(),
+  ] {
+    let a = Assembly::try_assemble(program.as_str()).unwrap();
+    let machine = || {
+      let mut bus = Bus::new();
+      bus.map_devices(a.width);
+      VM::with_bus(a.width, bus, a.reg_inits.clone(), a.mem_inits.clone()).unwrap()
+    };
+    assert!(compare(&a.instructions, machine));
+  }
+
+  // Random programs, which mostly branch forwards, but may fault, interrupt,
+  // and go back by returning. Register A starts at the timer, on the machines
+  // that have one.
+  let mut random = Random::new(1);
+  let mut next = |n: isize| (random.read(0) as u64 % n as u64) as isize;
+  let mut compared = 0;
+  for _ in 0..2000 {
+    let len = 4 + next(28);
+    let code = (0..len)
+      .flat_map(|i| {
+        let (a, b, t) = (1 + next(15), 1 + next(15), i + next(len - i));
+        let op = match next(37) {
+          0 => Instruction::SA(a),
+          1 => Instruction::RB(a),
+          2 => Instruction::RD,
+          3 => Instruction::WR,
+          4 => Instruction::SB(a),
+          5 | 6 => Instruction::SF(a),
+          7 => Instruction::GO(t),
+          8 => Instruction::BIN(t),
+          9 => Instruction::BIZ(t),
+          10 => Instruction::BIC(t),
+          11 => Instruction::BIV(t),
+          12 => Instruction::ADD(a, b),
+          13 => Instruction::AND(a, b),
+          14 => Instruction::MV(a, b),
+          15 => Instruction::NOT(a, b),
+          16 => Instruction::RS(a, b),
+          17 => Instruction::LS(a, b),
+          18 => Instruction::SW(a, b),
+          19 => Instruction::LI(a, next(512) - 256),
+          20 => Instruction::CALL(t),
+          21 => Instruction::RET,
+          22 => Instruction::PUSH(a),
+          23 => Instruction::POP(a),
+          24 => Instruction::SUB(a, b),
+          25 => Instruction::XOR(a, b),
+          26 => Instruction::ROL(a, b),
+          27 => Instruction::ASR(a, b),
+          28 => Instruction::HALT(a),
+          29 => Instruction::EI,
+          30 => Instruction::SIV(t),
+          31 => Instruction::RTI,
+          32 => Instruction::MV(a, 0),
+          33 => return vec![Instruction::SA(a), Instruction::RD, Instruction::RB(b)],
+          34 => return vec![Instruction::SA(a), Instruction::SB(b), Instruction::WR],
+          35 => {
+            let arith = match next(2) {
+              0 => Instruction::ADD(a, b),
+              _ => Instruction::SUB(a, b),
+            };
+            let branch = match next(2) {
+              0 => Instruction::BIZ(t),
+              _ => Instruction::BIN(t),
+            };
+            return vec![
+              Instruction::MV(a, 1 + next(15)),
+              arith,
+              Instruction::SF(a),
+              branch,
+            ];
+          }
+          _ => return vec![Instruction::SF(a), Instruction::BIZ(t), Instruction::GO(t)],
+        };
+        vec![op]
+      })
+      .collect::<Vec<_>>();
+    let (width, size, wrap, period) = ([8, 16][next(2) as usize], next(3), next(2), next(6));
+    let devices = next(4) != 0;
+    let unwritten_reads = [
+      UnwrittenReads::Allow,
+      UnwrittenReads::Warn,
+      UnwrittenReads::Fault,
+    ][next(3) as usize];
+    let machine = || {
+      let mut bus = match size {
+        0 => Bus::new(),
+        _ => Bus::with_size(width, 16 << size).unwrap(),
+      };
+      if devices {
+        bus.map_devices(width);
+      }
+      bus.wrap = wrap == 1;
+      let timer = timer_port(bus.last_address(width));
+      let mut vm = VM::with_bus(width, bus, vec![(0xA, timer)], vec![(3, 1)]).unwrap();
+      if let Some(timer) = vm.memory.device_mut::<Timer>() {
+        timer.period = period;
+      }
+      vm.unwritten_reads = unwritten_reads;
+      vm
+    };
+    if compare(&code, machine) {
+      compared += 1;
     }
   }
+  assert!(compared > 1000, "{}", compared);
 }